@@ -0,0 +1,356 @@
+#![allow(clippy::integer_arithmetic)]
+//! Burn-in test for index drives.
+//! Worker threads run the randomized insert/update/delete/addref/unref workload of the
+//! BucketMap unit tests against a shared map, each keeping a HashMap of what its own keys
+//! should hold and checking the map against it as they go.
+#[macro_use]
+extern crate log;
+use clap::{crate_description, crate_name, value_t, value_t_or_exit, values_t, App, Arg};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use solana_bucket_map::bucket_map::{BucketMap, BucketMapConfig};
+use solana_bucket_map::RefCount;
+use solana_measure::measure::Measure;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, Builder};
+use std::time::{Duration, Instant};
+use std::{panic, process};
+
+type Model = HashMap<Pubkey, (Vec<u64>, RefCount)>;
+
+struct Worker {
+    map: Arc<BucketMap<u64>>,
+    rng: StdRng,
+    // what every key this worker has written should hold
+    model: Model,
+    keys: Vec<Pubkey>,
+    // deleted since the last verify, these have to be gone from the map
+    deleted: Vec<Pubkey>,
+    max_keys: usize,
+    max_slot_list_len: usize,
+    ops: Arc<AtomicU64>,
+}
+
+impl Worker {
+    fn gen_value(&mut self) -> (Vec<u64>, RefCount) {
+        let len = self.rng.gen_range(0, self.max_slot_list_len + 1);
+        let slot_list = (0..len).map(|_| self.rng.gen()).collect();
+        (slot_list, self.rng.gen_range(0, 1 << 16))
+    }
+
+    fn random_key(&mut self) -> Option<Pubkey> {
+        self.random_key_ix().map(|ix| self.keys[ix])
+    }
+
+    fn random_key_ix(&mut self) -> Option<usize> {
+        if self.keys.is_empty() {
+            None
+        } else {
+            Some(self.rng.gen_range(0, self.keys.len()))
+        }
+    }
+
+    fn write(&mut self, key: &Pubkey, value: (Vec<u64>, RefCount)) {
+        if self.rng.gen_range(0, 2) == 0 {
+            self.map
+                .insert(self.map.bucket_ix(key), key, (&value.0, value.1));
+        } else {
+            let expected = self.model.get(key).cloned();
+            self.map.update(key, |current| {
+                assert_eq!(
+                    current,
+                    expected
+                        .as_ref()
+                        .map(|(slot_list, ref_count)| (&slot_list[..], *ref_count)),
+                    "{}",
+                    key
+                );
+                Some(value.clone())
+            });
+        }
+        self.model.insert(*key, value);
+    }
+
+    /// Apply one random operation to the map and the model
+    fn step(&mut self) {
+        if self.keys.len() < self.max_keys && self.rng.gen_range(0, 5) == 0 {
+            let key = Pubkey::new_from_array(self.rng.gen());
+            let value = self.gen_value();
+            self.write(&key, value);
+            self.keys.push(key);
+        }
+        if self.rng.gen_range(0, 10) == 0 {
+            if let Some(key) = self.random_key() {
+                let value = self.gen_value();
+                self.write(&key, value);
+            }
+        }
+        if self.rng.gen_range(0, 20) == 0 {
+            if let Some(ix) = self.random_key_ix() {
+                let key = self.keys.swap_remove(ix);
+                self.model.remove(&key);
+                self.map.delete_key(&key);
+                self.deleted.push(key);
+            }
+        }
+        if self.rng.gen_range(0, 10) == 0 {
+            if let Some(key) = self.random_key() {
+                let (slot_list, ref_count) = self.model[&key].clone();
+                // can't decrement a ref count of 0
+                let inc = ref_count == 0 || self.rng.gen_range(0, 2) == 0;
+                let new_ref_count = if inc { ref_count + 1 } else { ref_count - 1 };
+                let result = if self.rng.gen_range(0, 2) == 0 {
+                    self.map.update(&key, |current| {
+                        Some((current.unwrap().0.to_vec(), new_ref_count))
+                    });
+                    Some(new_ref_count)
+                } else if inc {
+                    self.map.addref(&key)
+                } else {
+                    self.map.unref(&key)
+                };
+                assert_eq!(result, Some(new_ref_count), "{}", key);
+                self.model.insert(key, (slot_list, new_ref_count));
+            }
+        }
+        self.ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn verify(&mut self) {
+        for (key, value) in self.model.iter() {
+            assert_eq!(self.map.read_value(key).as_ref(), Some(value), "{}", key);
+        }
+        for key in self.deleted.drain(..) {
+            assert_eq!(self.map.read_value(&key), None, "{}", key);
+        }
+    }
+}
+
+/// Check that the map holds exactly what the workers' models say, nothing more
+fn verify_all(map: &BucketMap<u64>, models: &[Model]) {
+    let mut num_items = 0;
+    for ix in 0..map.num_buckets() {
+        for item in map.items_in_range(ix, &None::<&RangeInclusive<Pubkey>>) {
+            let expected = models
+                .iter()
+                .find_map(|model| model.get(&item.pubkey))
+                .unwrap_or_else(|| panic!("unexpected key {}", item.pubkey));
+            assert_eq!(
+                (&item.slot_list, item.ref_count),
+                (&expected.0, expected.1),
+                "{}",
+                item.pubkey
+            );
+            num_items += 1;
+        }
+    }
+    assert_eq!(
+        num_items,
+        models.iter().map(|model| model.len()).sum::<usize>()
+    );
+}
+
+fn main() {
+    solana_logger::setup();
+
+    let matches = App::new(crate_name!())
+        .about(crate_description!())
+        .version(solana_version::version!())
+        .arg(
+            Arg::with_name("drive")
+                .long("drive")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("DIR")
+                .help(
+                    "Directory to put the map's files in, may be given more than once. \
+                     Anything already in the directory is deleted. \
+                     Defaults to a temporary directory.",
+                ),
+        )
+        .arg(
+            Arg::with_name("bins")
+                .long("bins")
+                .takes_value(true)
+                .value_name("BINS")
+                .default_value("8192")
+                .help("Number of buckets, must be a power of two"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .value_name("THREADS")
+                .default_value("8")
+                .help("Number of worker threads"),
+        )
+        .arg(
+            Arg::with_name("keys_per_thread")
+                .long("keys-per-thread")
+                .takes_value(true)
+                .value_name("KEYS")
+                .default_value("1000000")
+                .help("Number of live keys each worker grows to"),
+        )
+        .arg(
+            Arg::with_name("max_slot_list_len")
+                .long("max-slot-list-len")
+                .takes_value(true)
+                .value_name("LEN")
+                .default_value("3")
+                .help("Longest value written"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("3600")
+                .help("How long to run for"),
+        )
+        .arg(
+            Arg::with_name("verify_interval")
+                .long("verify-interval")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("60")
+                .help("How often each worker checks all of its keys"),
+        )
+        .arg(
+            Arg::with_name("stats_interval")
+                .long("stats-interval")
+                .takes_value(true)
+                .value_name("SECS")
+                .default_value("10")
+                .help("How often to print stats"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .value_name("SEED")
+                .help("Seed for the map and the workers' operation mix"),
+        )
+        .get_matches();
+
+    let drives = values_t!(matches, "drive", PathBuf).ok();
+    let bins = value_t_or_exit!(matches, "bins", usize);
+    let threads = value_t_or_exit!(matches, "threads", usize);
+    let keys_per_thread = value_t_or_exit!(matches, "keys_per_thread", usize);
+    let max_slot_list_len = value_t_or_exit!(matches, "max_slot_list_len", usize);
+    let duration = Duration::from_secs(value_t_or_exit!(matches, "duration", u64));
+    let verify_interval = Duration::from_secs(value_t_or_exit!(matches, "verify_interval", u64));
+    let stats_interval = Duration::from_secs(value_t_or_exit!(matches, "stats_interval", u64));
+    let seed = value_t!(matches, "seed", u64).ok();
+
+    // a failed check in any worker fails the whole run right away
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        process::exit(1);
+    }));
+
+    let map = Arc::new(BucketMap::<u64>::new(BucketMapConfig {
+        drives: drives.clone(),
+        seed,
+        ..BucketMapConfig::new(bins)
+    }));
+    info!(
+        "soaking {} buckets on {:?} with {} threads, {} keys each, for {:?}",
+        bins, drives, threads, keys_per_thread, duration
+    );
+
+    let exit = Arc::new(AtomicBool::default());
+    let ops = Arc::new(AtomicU64::default());
+    let workers = (0..threads)
+        .map(|i| {
+            let mut worker = Worker {
+                map: Arc::clone(&map),
+                rng: match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+                    None => StdRng::from_entropy(),
+                },
+                model: HashMap::new(),
+                keys: vec![],
+                deleted: vec![],
+                max_keys: keys_per_thread,
+                max_slot_list_len,
+                ops: Arc::clone(&ops),
+            };
+            let exit = Arc::clone(&exit);
+            Builder::new()
+                .name(format!("solana-bucket-map-soak-{}", i))
+                .spawn(move || {
+                    let mut last_verify = Instant::now();
+                    while !exit.load(Ordering::Relaxed) {
+                        worker.step();
+                        if last_verify.elapsed() >= verify_interval {
+                            worker.verify();
+                            last_verify = Instant::now();
+                        }
+                    }
+                    worker.verify();
+                    worker.model
+                })
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut last_ops = 0;
+    let mut last_print = start;
+    while start.elapsed() < duration {
+        sleep(std::cmp::min(
+            stats_interval,
+            duration.saturating_sub(start.elapsed()),
+        ));
+        // push the dirty pages out, so the drives see writeback and not just page cache
+        map.flush().expect("flush failed");
+        let total_ops = ops.load(Ordering::Relaxed);
+        let memory = map.memory_usage();
+        println!(
+            "{:?} elapsed, {} ops, {:.0} ops/s, mapped: {} resident: {} heap: {}",
+            start.elapsed(),
+            total_ops,
+            (total_ops - last_ops) as f64 / last_print.elapsed().as_secs_f64(),
+            memory.mapped_bytes(),
+            memory.resident_bytes(),
+            memory.heap_bytes(),
+        );
+        let mut stats = map.stats_snapshot();
+        // one count per bucket is too much to print every interval
+        let largest_bucket = stats.bucket_entries.drain(..).max().unwrap_or_default();
+        let busiest_bucket = stats
+            .per_bucket
+            .drain(..)
+            .map(|bucket| bucket.insert.count + bucket.update.count + bucket.delete.count)
+            .max()
+            .unwrap_or_default();
+        println!(
+            "largest bucket: {} busiest bucket: {} writes {:?}",
+            largest_bucket, busiest_bucket, stats
+        );
+        last_ops = total_ops;
+        last_print = Instant::now();
+    }
+
+    exit.store(true, Ordering::Relaxed);
+    let models = workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap())
+        .collect::<Vec<_>>();
+    let mut verify_time = Measure::start("verify");
+    verify_all(&map, &models);
+    verify_time.stop();
+    println!(
+        "passed: {} ops, {} live keys, {}",
+        ops.load(Ordering::Relaxed),
+        models.iter().map(|model| model.len()).sum::<usize>(),
+        verify_time
+    );
+}
@@ -0,0 +1,92 @@
+//! How a BucketMap reacts when one of its internal invariants doesn't hold, e.g. because a file
+//! was corrupted under it. Strict mode panics, so tests catch every violation. Resilient mode logs
+//! the violation and carries on without the broken piece: an entry whose data cell belongs to
+//! another key reads as missing and its next write gets a new cell. The try_ reads, such as
+//! BucketMap::try_read_value, return the violation as BucketMapError::InvariantViolation
+//! instead.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssertMode {
+    /// Panic on any violation. The default of debug builds.
+    Strict,
+    /// Log the violation and fall back to a safe behavior. The default of release builds.
+    Resilient,
+}
+
+impl Default for AssertMode {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            AssertMode::Strict
+        } else {
+            AssertMode::Resilient
+        }
+    }
+}
+
+/// An internal invariant that didn't hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// the data cell of an index entry is locked by another uid
+    DataCellUid { expected: u64, found: u64 },
+    /// an encrypted value is too short to be sealed or doesn't authenticate, because its cell
+    /// is corrupt or the map was reopened with another key
+    UndecryptableValue,
+}
+
+impl AssertMode {
+    /// Ok if `holds`. Otherwise Strict mode panics and Resilient mode logs the violation and
+    /// returns it, leaving the fallback to the caller.
+    pub fn check<F>(self, holds: bool, violation: F) -> Result<(), InvariantViolation>
+    where
+        F: FnOnce() -> InvariantViolation,
+    {
+        if holds {
+            return Ok(());
+        }
+        Err(self.violated(violation()))
+    }
+
+    /// Report `violation`, which was found. Strict mode panics and Resilient mode logs and
+    /// returns it.
+    pub fn violated(self, violation: InvariantViolation) -> InvariantViolation {
+        match self {
+            AssertMode::Strict => panic!("bucket map invariant violated: {:?}", violation),
+            AssertMode::Resilient => {
+                log::error!("bucket map invariant violated: {:?}", violation);
+                violation
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assert_mode_resilient() {
+        let violation = InvariantViolation::DataCellUid {
+            expected: 1,
+            found: 2,
+        };
+        assert_eq!(AssertMode::Resilient.check(true, || violation), Ok(()));
+        assert_eq!(
+            AssertMode::Resilient.check(false, || violation),
+            Err(violation)
+        );
+        assert_eq!(AssertMode::Strict.check(true, || violation), Ok(()));
+        assert_eq!(
+            AssertMode::default() == AssertMode::Strict,
+            cfg!(debug_assertions)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant violated: DataCellUid { expected: 1, found: 2 }")]
+    fn test_assert_mode_strict_panics() {
+        let _ = AssertMode::Strict.check(false, || InvariantViolation::DataCellUid {
+            expected: 1,
+            found: 2,
+        });
+    }
+}
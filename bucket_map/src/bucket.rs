@@ -1,21 +1,62 @@
-use crate::bucket_item::BucketItem;
-use crate::bucket_map::BucketMapError;
-use crate::bucket_stats::BucketMapStats;
-use crate::bucket_storage::{BucketStorage, Uid, UID_UNLOCKED};
-use crate::index_entry::IndexEntry;
+use crate::assert_mode::{AssertMode, InvariantViolation};
+use crate::bucket_item::{BucketItem, BucketItemRef};
+use crate::bucket_map::{BucketMapError, RefCountMode};
+use crate::bucket_stats::{BucketMapStats, BucketOpStats, BucketStats};
+use crate::bucket_storage::{BucketStorage, Uid, DEFAULT_CAPACITY_POW2, UID_UNLOCKED};
+use crate::cancel::{CancelToken, Cancelled, CANCEL_CHECK_CELLS};
+use crate::change_feed::ChangeKind;
+use crate::check::{BucketCheck, CheckProblem};
+#[cfg(feature = "encryption")]
+use crate::encryption::Encryption;
+use crate::fail_points::{FailPointOp, FailPoints};
+use crate::index_entry::{DataCellPrefix, IndexEntry};
+use crate::layout::CellLayout;
+use crate::memory_usage::BucketMemoryUsage;
+use crate::prefetch_iter::PREFETCH_MERGE_BYTES;
+use crate::progress::{ProgressCallback, ProgressOperation, PROGRESS_INTERVAL_CELLS};
+use crate::scratch_pool::ScratchPool;
+use crate::throttle::WriteThrottle;
+use crate::value_codec::ValueCodec;
 use crate::{MaxSearch, RefCount};
-use rand::thread_rng;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use solana_measure::measure::Measure;
 use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::marker::PhantomData;
-use std::ops::RangeBounds;
+use std::ops::{Range, RangeBounds};
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+/// A value and its ref count, borrowed from the mmap unless the codec had to decode it
+pub type ValueRef<'a, T> = (Cow<'a, [T]>, RefCount);
+
+/// Settings shared by every bucket of a map
+#[derive(Debug, Clone)]
+pub struct BucketConfig {
+    pub drives: Arc<Vec<PathBuf>>,
+    pub max_search: MaxSearch,
+    pub stats: Arc<BucketMapStats>,
+    pub index_capacity_pow2: u8,
+    //initial size in bytes of newly created data storages. None means DEFAULT_CAPACITY_POW2 cells.
+    pub data_capacity_bytes: Option<u64>,
+    //number of powers of two a storage grows by at a time
+    pub grow_pow2: u8,
+    pub progress: Option<ProgressCallback>,
+    pub fail_points: Option<Arc<FailPoints>>,
+    //layout of the cells of the data storages
+    pub data_layout: CellLayout,
+    pub scratch: Option<Arc<ScratchPool>>,
+    pub ref_count_mode: RefCountMode,
+    pub assert_mode: AssertMode,
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Arc<Encryption>>,
+}
+
 // >= 2 instances of BucketStorage per 'bucket' in the bucket map. 1 for index, >= 1 for data
 pub struct Bucket<T> {
     drives: Arc<Vec<PathBuf>>,
@@ -27,77 +68,450 @@ pub struct Bucket<T> {
     pub data: Vec<BucketStorage>,
     _phantom: PhantomData<T>,
     stats: Arc<BucketMapStats>,
+    //this bucket's shard of the map's operation counters
+    op_stats: Arc<BucketOpStats>,
+    //initial size in bytes of newly created data storages. None means DEFAULT_CAPACITY_POW2 cells.
+    data_capacity_bytes: Option<u64>,
+    throttle: Option<Arc<WriteThrottle>>,
+    progress: Option<ProgressCallback>,
+    grow_pow2: u8,
+    //every random choice the bucket makes comes from here, so a seeded map is reproducible
+    rng: StdRng,
+    fail_points: Option<Arc<FailPoints>>,
+    data_layout: CellLayout,
+    //files of grown storages are kept here for the next storage of the same size
+    scratch: Option<Arc<ScratchPool>>,
+    ref_count_mode: RefCountMode,
+    assert_mode: AssertMode,
+    //applied to every value written to and read from the data storages
+    codec: Arc<dyn ValueCodec<T>>,
+    //applied to every key and, after the codec, every value
+    #[cfg(feature = "encryption")]
+    encryption: Option<Arc<Encryption>>,
 }
 
 impl<T: Clone + Copy> Bucket<T> {
     pub fn new(
-        drives: Arc<Vec<PathBuf>>,
-        max_search: MaxSearch,
-        stats: Arc<BucketMapStats>,
-    ) -> Self {
-        let index = BucketStorage::new(
-            Arc::clone(&drives),
+        config: &BucketConfig,
+        index_capacity_pow2: u8,
+        throttle: Option<Arc<WriteThrottle>>,
+        op_stats: Arc<BucketOpStats>,
+        mut rng: StdRng,
+        codec: Arc<dyn ValueCodec<T>>,
+    ) -> io::Result<Self> {
+        let index = BucketStorage::new_with_capacity(
+            Arc::clone(&config.drives),
+            CellLayout::new::<IndexEntry>(None),
             1,
-            std::mem::size_of::<IndexEntry>() as u64,
-            max_search,
-            Arc::clone(&stats.index),
-        );
-        Self {
-            random: thread_rng().gen(),
-            drives,
+            index_capacity_pow2,
+            config.max_search,
+            Arc::clone(&config.stats.index),
+            rng.gen(),
+            config.fail_points.clone(),
+            config.scratch.clone(),
+        )?;
+        Ok(Self {
+            random: rng.gen(),
+            drives: Arc::clone(&config.drives),
             index,
             data: vec![],
             _phantom: PhantomData::default(),
-            stats,
-        }
+            stats: Arc::clone(&config.stats),
+            op_stats,
+            data_capacity_bytes: config.data_capacity_bytes,
+            throttle,
+            progress: config.progress.clone(),
+            grow_pow2: config.grow_pow2,
+            rng,
+            fail_points: config.fail_points.clone(),
+            data_layout: config.data_layout,
+            scratch: config.scratch.clone(),
+            ref_count_mode: config.ref_count_mode,
+            assert_mode: config.assert_mode,
+            codec,
+            #[cfg(feature = "encryption")]
+            encryption: config.encryption.clone(),
+        })
     }
 
     pub fn bucket_len(&self) -> u64 {
         self.index.used.load(Ordering::Relaxed)
     }
 
-    pub fn keys(&self) -> Vec<Pubkey> {
+    /// Number of cells searched for a key, or for a free cell of the index or a data storage
+    pub fn max_search(&self) -> MaxSearch {
+        self.index.max_search
+    }
+
+    /// Search up to `max_search` cells from now on, including in storages grown later.
+    /// Entries can sit anywhere within the current max_search of where their key hashes to, so
+    /// max_search only goes up. Returns the max_search of the bucket afterwards.
+    pub fn set_max_search(&mut self, max_search: MaxSearch) -> MaxSearch {
+        let max_search = max_search.max(self.index.max_search);
+        self.index.max_search = max_search;
+        self.data
+            .iter_mut()
+            .for_each(|data_bucket| data_bucket.max_search = max_search);
+        max_search
+    }
+
+    pub fn keys(&self, cancel: Option<&CancelToken>) -> Result<Vec<Pubkey>, Cancelled> {
         let mut rv = vec![];
         for i in 0..self.index.capacity() {
+            if i % CANCEL_CHECK_CELLS == 0 {
+                CancelToken::check(cancel)?;
+            }
             if self.index.uid(i) == UID_UNLOCKED {
                 continue;
             }
             let ix: &IndexEntry = self.index.get(i);
-            rv.push(ix.key);
+            if !ix.is_reserved() {
+                rv.push(self.entry_key(ix));
+            }
         }
-        rv
+        Ok(rv)
     }
 
-    pub fn items_in_range<R>(&self, range: &Option<&R>) -> Vec<BucketItem<T>>
+    /// Get the items in `range` whose value and ref count match `pred`.
+    /// `pred` sees values where they are stored, only matching values are copied.
+    pub fn filter_items_in_range<R, F>(
+        &self,
+        range: &Option<&R>,
+        cancel: Option<&CancelToken>,
+        pred: F,
+    ) -> Result<Vec<BucketItem<T>>, Cancelled>
     where
         R: RangeBounds<Pubkey>,
+        F: Fn(&[T], RefCount) -> bool,
     {
         let mut result = Vec::with_capacity(self.index.used.load(Ordering::Relaxed) as usize);
         for i in 0..self.index.capacity() {
+            if i % CANCEL_CHECK_CELLS == 0 {
+                CancelToken::check(cancel)?;
+            }
             let ii = i % self.index.capacity();
             if self.index.uid(ii) == UID_UNLOCKED {
                 continue;
             }
             let ix: &IndexEntry = self.index.get(ii);
-            let key = ix.key;
+            if ix.is_reserved() {
+                continue;
+            }
+            let key = self.entry_key(ix);
             if range.map(|r| r.contains(&key)).unwrap_or(true) {
-                let val = ix.read_value(self);
-                result.push(BucketItem {
-                    pubkey: key,
-                    ref_count: ix.ref_count(),
-                    slot_list: val.map(|(v, _ref_count)| v.to_vec()).unwrap_or_default(),
-                });
+                if let Some((value, ref_count)) = self.decode_value(ix, &key) {
+                    if pred(&value, ref_count) {
+                        result.push(BucketItem {
+                            pubkey: key,
+                            ref_count,
+                            slot_list: value.into_owned(),
+                        });
+                    }
+                }
             }
         }
-        result
+        Ok(result)
+    }
+
+    /// Call `f` with every entry in the bucket. Values are borrowed straight from the mmap
+    /// unless the codec has to decode them.
+    pub fn scan<'a, F>(&'a self, mut f: F)
+    where
+        F: FnMut(Pubkey, Cow<'a, [T]>, RefCount),
+    {
+        self.iter()
+            .for_each(|item| f(item.pubkey, item.slot_list, item.ref_count));
+    }
+
+    /// Call `f` with every entry in the bucket and its value as stored in the data cell, after the
+    /// codec and encryption. Values are always borrowed from the mmap.
+    pub fn scan_stored<'a, F>(&'a self, mut f: F)
+    where
+        F: FnMut(Pubkey, &'a [T], RefCount),
+    {
+        for i in 0..self.index.capacity() {
+            if self.index.uid(i) == UID_UNLOCKED {
+                continue;
+            }
+            let ix: &IndexEntry = self.index.get(i);
+            if ix.is_reserved() {
+                continue;
+            }
+            if let Some((value, ref_count)) = ix.read_value(self) {
+                f(self.entry_key(ix), value, ref_count);
+            }
+        }
+    }
+
+    /// Every entry in the bucket, in index order. Values are borrowed as by scan.
+    pub fn iter(&self) -> impl Iterator<Item = BucketItemRef<'_, T>> {
+        (0..self.index.capacity())
+            .filter(move |i| self.index.uid(*i) != UID_UNLOCKED)
+            .filter_map(move |i| {
+                let ix: &IndexEntry = self.index.get(i);
+                if ix.is_reserved() {
+                    return None;
+                }
+                let pubkey = self.entry_key(ix);
+                let (slot_list, ref_count) = self.decode_value(ix, &pubkey)?;
+                Some(BucketItemRef {
+                    pubkey,
+                    ref_count,
+                    slot_list,
+                })
+            })
+    }
+
+    /// Replace the contents of `items` with every entry in the bucket, reusing the items already
+    /// in it and their values' allocations
+    pub fn copy_items(&self, items: &mut Vec<BucketItem<T>>) {
+        let mut len = 0;
+        self.scan(|pubkey, value, ref_count| {
+            match items.get_mut(len) {
+                Some(item) => {
+                    item.pubkey = pubkey;
+                    item.ref_count = ref_count;
+                    item.slot_list.clear();
+                    item.slot_list.extend_from_slice(&value);
+                }
+                None => items.push(BucketItem {
+                    pubkey,
+                    ref_count,
+                    slot_list: value.into_owned(),
+                }),
+            }
+            len += 1;
+        });
+        items.truncate(len);
+    }
+
+    /// msync the index and every data storage to disk
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.index.flush()?;
+        self.data.iter().try_for_each(|data| data.flush())
+    }
+
+    /// The random offset keys are hashed with to find their index entry
+    pub fn random(&self) -> u64 {
+        self.random
+    }
+
+    /// Leave the files of the index and every data storage on disk when this bucket is dropped.
+    /// Returns their paths, index first.
+    pub fn keep_files(&mut self) -> Vec<PathBuf> {
+        std::iter::once(&mut self.index)
+            .chain(self.data.iter_mut())
+            .map(|storage| storage.keep_file().to_path_buf())
+            .collect()
+    }
+
+    /// Get the mapped, resident and heap bytes of this bucket
+    pub fn memory_usage(&self) -> BucketMemoryUsage {
+        let storages = std::iter::once(&self.index).chain(self.data.iter());
+        let mut usage = BucketMemoryUsage {
+            heap_bytes: (std::mem::size_of::<Self>()
+                + self.data.capacity() * std::mem::size_of::<BucketStorage>())
+                as u64,
+            ..BucketMemoryUsage::default()
+        };
+        for storage in storages {
+            usage.mapped_bytes += storage.mapped_bytes();
+            usage.resident_bytes += storage.resident_bytes();
+            usage.heap_bytes += storage.heap_bytes();
+        }
+        usage
+    }
+
+    /// Return the size of the index file in bytes
+    pub fn index_bytes(&self) -> u64 {
+        self.index.capacity() * self.index.cell_size
+    }
+
+    /// Return the number of cells in the index
+    pub fn index_capacity(&self) -> u64 {
+        self.index.capacity()
+    }
+
+    /// Copy every cell of this bucket into `previous`, an earlier copy, replacing those of its
+    /// storages that no longer have the right shape. The copy is only meant to be read: it
+    /// doesn't count towards the map's stats and isn't throttled.
+    pub fn copy_to(&self, previous: Option<Self>, rng: &mut StdRng) -> io::Result<Self> {
+        let stats = Arc::new(BucketMapStats::default());
+        let (previous_index, previous_data) = match previous {
+            Some(previous) => (Some(previous.index), previous.data),
+            None => (None, vec![]),
+        };
+        let mut copy_storage = |previous: Option<BucketStorage>,
+                                storage: &BucketStorage,
+                                stats: &Arc<BucketStats>|
+         -> io::Result<BucketStorage> {
+            let mut copy = match previous {
+                Some(previous) if previous.same_shape(storage) => previous,
+                _ => storage.new_like(Arc::clone(stats), rng.gen())?,
+            };
+            copy.copy_from(storage);
+            Ok(copy)
+        };
+        let index = copy_storage(previous_index, &self.index, &stats.index)?;
+        let mut previous_data = previous_data.into_iter();
+        let data = self
+            .data
+            .iter()
+            .map(|storage| copy_storage(previous_data.next(), storage, &stats.data))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            drives: Arc::clone(&self.drives),
+            index,
+            random: self.random,
+            data,
+            _phantom: PhantomData::default(),
+            stats,
+            op_stats: Arc::default(),
+            data_capacity_bytes: self.data_capacity_bytes,
+            throttle: None,
+            progress: None,
+            grow_pow2: self.grow_pow2,
+            rng: StdRng::seed_from_u64(rng.gen()),
+            fail_points: self.fail_points.clone(),
+            data_layout: self.data_layout,
+            scratch: self.scratch.clone(),
+            ref_count_mode: self.ref_count_mode,
+            assert_mode: self.assert_mode,
+            codec: Arc::clone(&self.codec),
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption.clone(),
+        })
+    }
+
+    /// Hint that the index cells in `range` will be read soon
+    pub fn prefetch_index(&self, range: Range<u64>) {
+        self.index.prefetch(range);
+    }
+
+    /// Hint that the data cells of the entries in index cells `range` will be read soon.
+    /// Reads those index cells. Data cells less than PREFETCH_MERGE_BYTES apart are advised as
+    /// one range, so a chunk takes a few madvise calls rather than one per entry.
+    pub fn prefetch_data(&self, range: Range<u64>) {
+        let end = std::cmp::min(range.end, self.index.capacity());
+        let mut locs = vec![vec![]; self.data.len()];
+        for ix in range.start..end {
+            if self.index.uid(ix) == UID_UNLOCKED {
+                continue;
+            }
+            let entry = self.index.get::<IndexEntry>(ix);
+            if entry.is_reserved() || entry.num_slots == 0 {
+                continue;
+            }
+            let data_ix = entry.data_bucket_ix() as usize;
+            if let (Some(locs), Some(data_bucket)) = (locs.get_mut(data_ix), self.data.get(data_ix))
+            {
+                locs.push(entry.data_loc(data_bucket));
+            }
+        }
+        for (data_bucket, mut locs) in self.data.iter().zip(locs) {
+            locs.sort_unstable();
+            let gap = std::cmp::max(1, PREFETCH_MERGE_BYTES / data_bucket.cell_size);
+            let mut run: Option<Range<u64>> = None;
+            for loc in locs {
+                run = match run {
+                    Some(run) if loc <= run.end + gap => Some(run.start..loc + 1),
+                    Some(run) => {
+                        data_bucket.prefetch(run);
+                        Some(loc..loc + 1)
+                    }
+                    None => Some(loc..loc + 1),
+                };
+            }
+            if let Some(run) = run {
+                data_bucket.prefetch(run);
+            }
+        }
+    }
+
+    /// Number of entries and bytes of stored values, counted like items_in_cells returns them.
+    /// Only the index is read.
+    pub fn entry_stats(&self) -> (u64, u64) {
+        let (mut entries, mut bytes) = (0, 0);
+        self.scan_lens(|_, len, _| {
+            entries += 1;
+            bytes += len * std::mem::size_of::<T>() as u64;
+        });
+        (entries, bytes)
     }
 
+    /// Call `f` with the key, stored value length and ref count of every entry in the bucket.
+    /// Only the index is read.
+    pub fn scan_lens<F>(&self, mut f: F)
+    where
+        F: FnMut(Pubkey, u64, RefCount),
+    {
+        for i in 0..self.index.capacity() {
+            if self.index.uid(i) == UID_UNLOCKED {
+                continue;
+            }
+            let ix: &IndexEntry = self.index.get(i);
+            if !ix.is_reserved() {
+                f(self.entry_key(ix), ix.num_slots, ix.ref_count());
+            }
+        }
+    }
+
+    /// Get the items stored in the index cells in `range`.
+    /// Nothing is prefetched, see prefetch_data to have the data cells read in ahead.
+    pub fn items_in_cells(&self, range: Range<u64>) -> Vec<BucketItem<T>> {
+        let end = std::cmp::min(range.end, self.index.capacity());
+        let entries = (range.start..end)
+            .filter(|ix| self.index.uid(*ix) != UID_UNLOCKED)
+            .map(|ix| self.index.get::<IndexEntry>(ix))
+            .filter(|entry| !entry.is_reserved())
+            .collect::<Vec<_>>();
+        entries
+            .into_iter()
+            .map(|entry| {
+                let key = self.entry_key(entry);
+                BucketItem {
+                    pubkey: key,
+                    ref_count: entry.ref_count(),
+                    slot_list: self
+                        .decode_value(entry, &key)
+                        .map(|(v, _ref_count)| v.into_owned())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// The entry of `key`, unless it is only reserved
     pub fn find_entry(&self, key: &Pubkey) -> Option<(&IndexEntry, u64)> {
-        Self::bucket_find_entry(&self.index, key, self.random)
+        self.find_entry_or_reservation(key)
+            .filter(|(elem, _)| !elem.is_reserved())
     }
 
+    fn find_entry_or_reservation(&self, key: &Pubkey) -> Option<(&IndexEntry, u64)> {
+        Self::bucket_find_entry(&self.index, &self.stored_key(key), self.random)
+    }
+
+    // reserved entries included
     fn find_entry_mut(&self, key: &Pubkey) -> Option<(&mut IndexEntry, u64)> {
-        Self::bucket_find_entry_mut(&self.index, key, self.random)
+        Self::bucket_find_entry_mut(&self.index, &self.stored_key(key), self.random)
+    }
+
+    /// The key the index stores for `key`
+    fn stored_key(&self, key: &Pubkey) -> Pubkey {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = self.encryption.as_ref() {
+            return encryption.encrypt_key(key);
+        }
+        *key
+    }
+
+    /// The key of `entry`, an entry of the index
+    fn entry_key(&self, entry: &IndexEntry) -> Pubkey {
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = self.encryption.as_ref() {
+            return encryption.decrypt_key(&entry.key);
+        }
+        entry.key
     }
 
     fn bucket_find_entry_mut<'a>(
@@ -157,6 +571,7 @@ impl<T: Clone + Copy> Bucket<T> {
             elem.ref_count = ref_count;
             elem.storage_offset = 0;
             elem.storage_capacity_when_created_pow2 = 0;
+            elem.reserved = 0;
             elem.num_slots = 0;
             //debug!(                "INDEX ALLOC {:?} {} {} {}",                key, ii, index.capacity, elem_uid            );
             return Ok(ii);
@@ -164,32 +579,84 @@ impl<T: Clone + Copy> Bucket<T> {
         Err(BucketMapError::IndexNoSpace(index.capacity_pow2))
     }
 
-    pub fn addref(&mut self, key: &Pubkey) -> Option<RefCount> {
-        let (elem, _) = self.find_entry_mut(key)?;
-        elem.ref_count += 1;
-        Some(elem.ref_count)
+    pub fn addref(&mut self, key: &Pubkey) -> Result<Option<RefCount>, BucketMapError> {
+        let mode = self.ref_count_mode;
+        self.update_ref_count(key, |ref_count| mode.addref(ref_count))
     }
 
-    pub fn unref(&mut self, key: &Pubkey) -> Option<RefCount> {
-        let (elem, _) = self.find_entry_mut(key)?;
-        elem.ref_count -= 1;
-        Some(elem.ref_count)
+    pub fn unref(&mut self, key: &Pubkey) -> Result<Option<RefCount>, BucketMapError> {
+        let mode = self.ref_count_mode;
+        self.update_ref_count(key, |ref_count| mode.unref(ref_count))
+    }
+
+    fn update_ref_count<F>(
+        &mut self,
+        key: &Pubkey,
+        f: F,
+    ) -> Result<Option<RefCount>, BucketMapError>
+    where
+        F: Fn(RefCount) -> Result<RefCount, BucketMapError>,
+    {
+        let elem = match self.find_entry_mut(key) {
+            Some((elem, _)) if !elem.is_reserved() => elem,
+            _ => return Ok(None),
+        };
+        elem.ref_count = f(elem.ref_count)?;
+        self.write_data_prefix(elem);
+        Ok(Some(elem.ref_count))
     }
 
     fn create_key(&self, key: &Pubkey, ref_count: u64) -> Result<u64, BucketMapError> {
+        let key = self.stored_key(key);
         Self::bucket_create_key(
             &self.index,
-            key,
-            IndexEntry::key_uid(key),
+            &key,
+            IndexEntry::key_uid(&key),
             self.random,
             ref_count,
         )
     }
 
-    pub fn read_value(&self, key: &Pubkey) -> Option<(&[T], RefCount)> {
+    pub fn read_value(&self, key: &Pubkey) -> Option<ValueRef<'_, T>> {
+        self.try_read_value(key).ok().flatten()
+    }
+
+    /// Same as read_value, but an entry that is broken returns the violation in
+    /// AssertMode::Resilient instead of reading as missing
+    pub fn try_read_value(
+        &self,
+        key: &Pubkey,
+    ) -> Result<Option<ValueRef<'_, T>>, InvariantViolation> {
         //debug!("READ_VALUE: {:?}", key);
-        let (elem, _) = self.find_entry(key)?;
-        elem.read_value(self)
+        match self.find_entry(key) {
+            Some((elem, _)) => self.try_decode_value(elem, key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The value of `entry`, the index entry of `key`
+    fn decode_value<'a>(&'a self, entry: &IndexEntry, key: &Pubkey) -> Option<ValueRef<'a, T>> {
+        self.try_decode_value(entry, key).ok()
+    }
+
+    fn try_decode_value<'a>(
+        &'a self,
+        entry: &IndexEntry,
+        key: &Pubkey,
+    ) -> Result<ValueRef<'a, T>, InvariantViolation> {
+        let (value, ref_count) = entry.try_read_value(self)?;
+        #[cfg(feature = "encryption")]
+        if let Some(encryption) = self.encryption.as_ref() {
+            let value = encryption.decode(key, value).ok_or_else(|| {
+                self.assert_mode
+                    .violated(InvariantViolation::UndecryptableValue)
+            })?;
+            return Ok((
+                Cow::Owned(self.codec.decode(key, &value).into_owned()),
+                ref_count,
+            ));
+        }
+        Ok((self.codec.decode(key, value), ref_count))
     }
 
     pub fn try_write(
@@ -198,7 +665,16 @@ impl<T: Clone + Copy> Bucket<T> {
         data: &[T],
         ref_count: u64,
     ) -> Result<(), BucketMapError> {
+        let data = self.codec.encode(key, data);
+        #[cfg(feature = "encryption")]
+        let data = match self.encryption.as_ref() {
+            Some(encryption) => Cow::Owned(encryption.encode(key, &data).into_owned()),
+            None => data,
+        };
+        let data = &data[..];
         let best_fit_bucket = IndexEntry::data_bucket_from_num_slots(data.len() as u64);
+        // drawn up front because the index entry borrows the bucket below
+        let random_pos: u64 = self.rng.gen();
         if self.data.get(best_fit_bucket as usize).is_none() {
             // fail early if the data bucket we need doesn't exist - we don't want the index entry partially allocated
             //error!("resizing because missing bucket");
@@ -221,27 +697,43 @@ impl<T: Clone + Copy> Bucket<T> {
         let elem_uid = self.index.uid(elem_ix);
         let bucket_ix = elem.data_bucket_ix();
         let current_bucket = &self.data[bucket_ix as usize];
-        if best_fit_bucket == bucket_ix && elem.num_slots > 0 {
+        // a cell that isn't ours is left alone, and the value moves to a new one
+        let owns_data = elem.num_slots > 0 && self.owns_data_cell(elem, elem_uid);
+        if best_fit_bucket == bucket_ix && owns_data {
             //in place update
             let elem_loc = elem.data_loc(current_bucket);
             let slice: &mut [T] = current_bucket.get_mut_cell_slice(elem_loc, data.len() as u64);
             //let elem: &mut IndexEntry = self.index.get_mut(elem_ix);
-            assert!(current_bucket.uid(elem_loc) == elem_uid);
             elem.num_slots = data.len() as u64;
+            // writing a reserved entry fills it in
+            elem.reserved = 0;
             slice.clone_from_slice(data);
+            self.write_data_prefix(elem);
+            self.record_write(data);
             Ok(())
         } else {
             //need to move the allocation to a best fit spot
             let best_bucket = &self.data[best_fit_bucket as usize];
             let cap_power = best_bucket.capacity_pow2;
             let cap = best_bucket.capacity();
-            let pos = thread_rng().gen_range(0, cap);
-            for i in pos..pos + self.index.max_search() {
-                let ix = i % cap;
-                if best_bucket.uid(ix) == UID_UNLOCKED {
+            // reuse a hole left by a previous free before searching for an unused cell
+            let recycled = if data.is_empty() {
+                None
+            } else {
+                best_bucket.allocate_recycled(elem_uid)
+            };
+            let pos = random_pos % cap;
+            let ix = recycled.or_else(|| {
+                (pos..pos + self.index.max_search())
+                    .map(|i| i % cap)
+                    .find(|ix| best_bucket.uid(*ix) == UID_UNLOCKED)
+            });
+            match ix {
+                Some(ix) => {
                     let elem_loc = elem.data_loc(current_bucket);
-                    if elem.num_slots > 0 {
+                    if owns_data {
                         current_bucket.free(elem_loc, elem_uid);
+                        current_bucket.recycle(elem_loc);
                     }
                     // elem: &mut IndexEntry = self.index.get_mut(elem_ix);
                     elem.storage_offset = ix;
@@ -249,51 +741,384 @@ impl<T: Clone + Copy> Bucket<T> {
                     elem.num_slots = data.len() as u64;
                     //debug!(                        "DATA ALLOC {:?} {} {} {}",                        key, elem.data_location, best_bucket.capacity, elem_uid                    );
                     if elem.num_slots > 0 {
-                        best_bucket.allocate(ix, elem_uid).unwrap();
+                        if recycled.is_none() {
+                            best_bucket.allocate(ix, elem_uid).unwrap();
+                        }
                         let slice = best_bucket.get_mut_cell_slice(ix, data.len() as u64);
                         slice.copy_from_slice(data);
                     }
-                    return Ok(());
+                    elem.reserved = 0;
+                    self.write_data_prefix(elem);
+                    self.record_write(data);
+                    Ok(())
                 }
+                None => Err(BucketMapError::DataNoSpace((best_fit_bucket, cap_power))),
             }
-            Err(BucketMapError::DataNoSpace((best_fit_bucket, cap_power)))
         }
     }
 
-    pub fn delete_key(&mut self, key: &Pubkey) {
+    /// Returns true if `key` was present
+    pub fn delete_key(&mut self, key: &Pubkey) -> bool {
         if let Some((elem, elem_ix)) = self.find_entry(key) {
-            let elem_uid = self.index.uid(elem_ix);
+            self.free_entry(elem, elem_ix);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn free_entry(&self, elem: &IndexEntry, elem_ix: u64) {
+        let elem_uid = self.index.uid(elem_ix);
+        if elem.num_slots > 0 && self.owns_data_cell(elem, elem_uid) {
+            let data_bucket = &self.data[elem.data_bucket_ix() as usize];
+            let loc = elem.data_loc(data_bucket);
+            //debug!(                    "DATA FREE {:?} {} {} {}",                    key, elem.data_location, data_bucket.capacity, elem_uid                );
+            data_bucket.free(loc, elem_uid);
+            data_bucket.recycle(loc);
+        }
+        //debug!("INDEX FREE {:?} {}", key, elem_uid);
+        self.index.free(elem_ix, elem_uid);
+    }
+
+    /// Whether the data cell of `elem`, which has one, is locked by `uid`
+    pub(crate) fn owns_data_cell(&self, elem: &IndexEntry, uid: Uid) -> bool {
+        self.check_data_cell(elem, uid).is_ok()
+    }
+
+    /// Same as owns_data_cell, returning the violation
+    pub(crate) fn check_data_cell(
+        &self,
+        elem: &IndexEntry,
+        uid: Uid,
+    ) -> Result<(), InvariantViolation> {
+        let data_bucket = &self.data[elem.data_bucket_ix() as usize];
+        let found = data_bucket.uid(elem.data_loc(data_bucket));
+        self.assert_mode
+            .check(found == uid, || InvariantViolation::DataCellUid {
+                expected: uid,
+                found,
+            })
+    }
+
+    /// Claim an index entry for `key` and a data cell for a value of `num_slots` stored elements.
+    /// The entry is hidden from readers until the next write of `key` fills it in.
+    /// Returns false without claiming anything if `key` already has an entry.
+    /// On error nothing is claimed.
+    pub fn try_reserve(&mut self, key: &Pubkey, num_slots: u64) -> Result<bool, BucketMapError> {
+        if self.find_entry_or_reservation(key).is_some() {
+            return Ok(false);
+        }
+        let best_fit_bucket = IndexEntry::data_bucket_from_num_slots(num_slots);
+        let random_pos: u64 = self.rng.gen();
+        if self.data.get(best_fit_bucket as usize).is_none() {
+            return Err(BucketMapError::DataNoSpace((best_fit_bucket, 0)));
+        }
+        let elem_ix = self.create_key(key, 0)?;
+        let elem_uid = self.index.uid(elem_ix);
+        let elem: &mut IndexEntry = self.index.get_mut(elem_ix);
+        elem.reserved = 1;
+        if num_slots == 0 {
+            return Ok(true);
+        }
+        let data_bucket = &self.data[best_fit_bucket as usize];
+        let cap = data_bucket.capacity();
+        let pos = random_pos % cap;
+        let recycled = data_bucket.allocate_recycled(elem_uid);
+        let ix = recycled.or_else(|| {
+            (pos..pos + self.index.max_search())
+                .map(|i| i % cap)
+                .find(|ix| data_bucket.uid(*ix) == UID_UNLOCKED)
+        });
+        match ix {
+            Some(ix) => {
+                if recycled.is_none() {
+                    data_bucket.allocate(ix, elem_uid).unwrap();
+                }
+                elem.storage_offset = ix;
+                elem.storage_capacity_when_created_pow2 = data_bucket.capacity_pow2;
+                elem.num_slots = num_slots;
+                // a recycled cell still has the prefix of its previous value
+                self.write_data_prefix(elem);
+                Ok(true)
+            }
+            None => {
+                self.index.free(elem_ix, elem_uid);
+                Err(BucketMapError::DataNoSpace((
+                    best_fit_bucket,
+                    data_bucket.capacity_pow2,
+                )))
+            }
+        }
+    }
+
+    /// Copy what rebuild_index needs of `elem` in front of its value, if data cells have room
+    /// for it. Reserved entries get an empty prefix, which rebuild_index skips.
+    fn write_data_prefix(&self, elem: &IndexEntry) {
+        if self.data_layout.prefix_bytes == 0 || elem.num_slots == 0 {
+            return;
+        }
+        let data_bucket = &self.data[elem.data_bucket_ix() as usize];
+        let prefix: &mut DataCellPrefix = data_bucket.get_prefix_mut(elem.data_loc(data_bucket));
+        *prefix = if elem.is_reserved() {
+            DataCellPrefix::default()
+        } else {
+            DataCellPrefix {
+                key: elem.key,
+                ref_count: elem.ref_count,
+                num_slots: elem.num_slots,
+            }
+        };
+    }
+
+    /// Replace the index with one recreated from the prefixes of the data cells, for when the
+    /// index is lost or corrupt. Data cells need a prefix, see BucketMapConfig::data_cell_keys.
+    /// Entries with empty values have no data cell and are not recovered. If a crash leaked a
+    /// copy of a value, either copy may be picked; gc_data frees the other one afterwards.
+    /// Returns the number of entries recovered. On error the index is left as it was.
+    pub fn rebuild_index(&mut self) -> io::Result<u64> {
+        let mut capacity_pow2 = self.index.capacity_pow2;
+        loop {
+            let index = BucketStorage::new_with_capacity(
+                Arc::clone(&self.drives),
+                CellLayout::new::<IndexEntry>(None),
+                1,
+                capacity_pow2,
+                self.index.max_search,
+                Arc::clone(&self.stats.index),
+                self.rng.gen(),
+                self.fail_points.clone(),
+                self.scratch.clone(),
+            )?;
+            let random = self.rng.gen();
+            if let Some(recovered) = self.index_from_data(&index, random) {
+                self.index = index;
+                self.random = random;
+                return Ok(recovered);
+            }
+            // too many collisions, like grow_index
+            capacity_pow2 += self.grow_pow2;
+        }
+    }
+
+    /// Fill `index` with an entry for every data cell with a complete prefix.
+    /// Returns None if `index` ran out of space.
+    fn index_from_data(&self, index: &BucketStorage, random: u64) -> Option<u64> {
+        let mut recovered = 0;
+        for (data_bucket_ix, data_bucket) in self.data.iter().enumerate() {
+            for ix in 0..data_bucket.capacity() {
+                let uid = data_bucket.uid(ix);
+                if uid == UID_UNLOCKED {
+                    continue;
+                }
+                let prefix: &DataCellPrefix = data_bucket.get_prefix(ix);
+                // reserved, or reallocated and not written yet
+                if prefix.num_slots == 0
+                    || IndexEntry::key_uid(&prefix.key) != uid
+                    || IndexEntry::data_bucket_from_num_slots(prefix.num_slots)
+                        != data_bucket_ix as u64
+                    || Self::bucket_find_entry(index, &prefix.key, random).is_some()
+                {
+                    continue;
+                }
+                let elem_ix =
+                    Self::bucket_create_key(index, &prefix.key, uid, random, prefix.ref_count)
+                        .ok()?;
+                let elem: &mut IndexEntry = index.get_mut(elem_ix);
+                elem.storage_offset = ix;
+                elem.storage_capacity_when_created_pow2 = data_bucket.capacity_pow2;
+                elem.num_slots = prefix.num_slots;
+                recovered += 1;
+            }
+        }
+        Some(recovered)
+    }
+
+    /// Forget every index entry, as if the index file was lost
+    #[cfg(test)]
+    pub(crate) fn clear_index(&self) {
+        for ix in 0..self.index.capacity() {
+            let uid = self.index.uid(ix);
+            if uid != UID_UNLOCKED {
+                self.index.free(ix, uid);
+            }
+        }
+    }
+
+    /// Free the entry of `key` if it is reserved and hasn't been written yet.
+    /// Returns whether there was such an entry.
+    pub fn release(&mut self, key: &Pubkey) -> bool {
+        match self.find_entry_or_reservation(key) {
+            Some((elem, elem_ix)) if elem.is_reserved() => {
+                self.free_entry(elem, elem_ix);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check every index header and follow every `stride`th entry, up to `samples` of them, into
+    /// its data cell. See check.rs.
+    pub fn quick_check(
+        &self,
+        samples: u64,
+        cancel: Option<&CancelToken>,
+    ) -> Result<BucketCheck, Cancelled> {
+        let recorded = self.bucket_len();
+        let stride = (recorded / samples.max(1)).max(1);
+        let mut check = BucketCheck::default();
+        let mut referenced = vec![0u64; self.data.len()];
+        for i in 0..self.index.capacity() {
+            if i % CANCEL_CHECK_CELLS == 0 {
+                CancelToken::check(cancel)?;
+            }
+            let uid = self.index.uid(i);
+            if uid == UID_UNLOCKED {
+                continue;
+            }
+            let elem: &IndexEntry = self.index.get(i);
+            let sample = check.entries % stride == 0 && check.sampled < samples;
+            check.entries += 1;
+            if uid != IndexEntry::key_uid(&elem.key) {
+                check.problems.push(CheckProblem::IndexHeader { entry: i });
+                continue;
+            }
+            let data_bucket_ix = elem.data_bucket_ix() as usize;
+            if elem.num_slots > 0 {
+                match referenced.get_mut(data_bucket_ix) {
+                    Some(referenced) => *referenced += 1,
+                    None => {
+                        check.problems.push(CheckProblem::DataBucket { entry: i });
+                        continue;
+                    }
+                }
+            }
+            if !sample {
+                continue;
+            }
+            check.sampled += 1;
+            if !matches!(Self::bucket_find_entry(&self.index, &elem.key, self.random),
+                Some((_, found)) if found == i)
+            {
+                check.problems.push(CheckProblem::Unreachable { entry: i });
+            }
+            if elem.num_slots == 0 {
+                continue;
+            }
+            let data_bucket = &self.data[data_bucket_ix];
+            if elem.storage_capacity_when_created_pow2 > data_bucket.capacity_pow2
+                || elem.data_loc(data_bucket) >= data_bucket.capacity()
+            {
+                check.problems.push(CheckProblem::DataOffset { entry: i });
+            } else if data_bucket.uid(elem.data_loc(data_bucket)) != uid {
+                check.problems.push(CheckProblem::DataHeader { entry: i });
+            }
+        }
+        if check.entries != recorded {
+            check.problems.push(CheckProblem::LenMismatch {
+                counted: check.entries,
+                recorded,
+            });
+        }
+        let leaked = self
+            .data
+            .iter()
+            .zip(referenced)
+            .map(|(data_bucket, referenced)| {
+                data_bucket
+                    .used
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(referenced)
+            })
+            .sum::<u64>();
+        if leaked > 0 {
+            check
+                .problems
+                .push(CheckProblem::LeakedCells { cells: leaked });
+        }
+        Ok(check)
+    }
+
+    /// Free data allocations that are not referenced by any index entry.
+    /// Returns the number of bytes reclaimed.
+    pub fn gc_data(&mut self, cancel: Option<&CancelToken>) -> Result<u64, Cancelled> {
+        self.set_busy(true);
+        let result = self.gc_data_busy(cancel);
+        self.set_busy(false);
+        result
+    }
+
+    fn gc_data_busy(&mut self, cancel: Option<&CancelToken>) -> Result<u64, Cancelled> {
+        self.data
+            .iter_mut()
+            .for_each(|data_bucket| data_bucket.start_gc_generation());
+        for i in 0..self.index.capacity() {
+            // nothing has been freed yet, so stopping here leaves the bucket as it was
+            if i % CANCEL_CHECK_CELLS == 0 {
+                CancelToken::check(cancel)?;
+            }
+            if self.index.uid(i) == UID_UNLOCKED {
+                continue;
+            }
+            let elem: &IndexEntry = self.index.get(i);
             if elem.num_slots > 0 {
                 let data_bucket = &self.data[elem.data_bucket_ix() as usize];
-                let loc = elem.data_loc(data_bucket);
-                //debug!(                    "DATA FREE {:?} {} {} {}",                    key, elem.data_location, data_bucket.capacity, elem_uid                );
-                data_bucket.free(loc, elem_uid);
+                data_bucket.mark(elem.data_loc(data_bucket));
             }
-            //debug!("INDEX FREE {:?} {}", key, elem_uid);
-            self.index.free(elem_ix, elem_uid);
         }
+        Ok(self
+            .data
+            .iter_mut()
+            .map(|data_bucket| data_bucket.free_unmarked())
+            .sum())
     }
 
-    pub fn grow_index(&mut self, sz: u8) {
+    fn record_write(&self, data: &[T]) {
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.record_write(std::mem::size_of_val(data) as u64);
+        }
+    }
+
+    fn set_busy(&self, busy: bool) {
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.set_busy(busy);
+        }
+    }
+
+    /// On error the index is left as it was
+    pub fn grow_index(&mut self, sz: u8) -> io::Result<()> {
         if self.index.capacity_pow2 == sz {
+            FailPoints::check(self.fail_points.as_deref(), FailPointOp::Grow)?;
             let mut m = Measure::start("");
             //debug!("GROW_INDEX: {}", sz);
-            let increment = 1;
+            let increment = self.grow_pow2;
             for i in increment.. {
                 //increasing the capacity by ^4 reduces the
                 //likelyhood of a re-index collision of 2^(max_search)^2
                 //1 in 2^32
                 let index = BucketStorage::new_with_capacity(
                     Arc::clone(&self.drives),
+                    CellLayout::new::<IndexEntry>(None),
                     1,
-                    std::mem::size_of::<IndexEntry>() as u64,
                     self.index.capacity_pow2 + i, // * 2,
                     self.index.max_search,
                     Arc::clone(&self.stats.index),
-                );
-                let random = thread_rng().gen();
+                    self.rng.gen(),
+                    self.fail_points.clone(),
+                    self.scratch.clone(),
+                )?;
+                let random = self.rng.gen();
                 let mut valid = true;
+                let total_bytes = self.index.capacity() * self.index.cell_size;
                 for ix in 0..self.index.capacity() {
+                    if ix % PROGRESS_INTERVAL_CELLS == 0 {
+                        if let Some(progress) = self.progress.as_ref() {
+                            progress.report(
+                                ProgressOperation::GrowIndex,
+                                ix * self.index.cell_size,
+                                total_bytes,
+                            );
+                        }
+                    }
                     let uid = self.index.uid(ix);
                     if UID_UNLOCKED != uid {
                         let elem: &IndexEntry = self.index.get(ix);
@@ -317,6 +1142,9 @@ impl<T: Clone + Copy> Bucket<T> {
                     }
                 }
                 if valid {
+                    if let Some(progress) = self.progress.as_ref() {
+                        progress.report(ProgressOperation::GrowIndex, total_bytes, total_bytes);
+                    }
                     self.index = index;
                     self.random = random;
                     break;
@@ -334,24 +1162,79 @@ impl<T: Clone + Copy> Bucket<T> {
                 .resize_us
                 .fetch_add(m.as_us(), Ordering::Relaxed);
         }
+        Ok(())
     }
 
-    pub fn grow_data(&mut self, sz: (u64, u8)) {
-        if self.data.get(sz.0 as usize).is_none() {
-            for i in self.data.len() as u64..(sz.0 + 1) {
-                self.data.push(BucketStorage::new(
-                    Arc::clone(&self.drives),
-                    1 << i,
-                    std::mem::size_of::<T>() as u64,
-                    self.index.max_search,
-                    Arc::clone(&self.stats.data),
-                ))
-            }
+    /// create any missing data storages up to and including `data_bucket_ix`
+    fn create_data_buckets(&mut self, data_bucket_ix: u64) -> io::Result<()> {
+        for i in self.data.len() as u64..(data_bucket_ix + 1) {
+            let num_elems = 1 << i;
+            let capacity_pow2 = Self::data_capacity_pow2(
+                self.data_capacity_bytes,
+                self.data_layout.cell_bytes(num_elems),
+            );
+            self.data.push(BucketStorage::new_with_capacity(
+                Arc::clone(&self.drives),
+                self.data_layout,
+                num_elems,
+                capacity_pow2,
+                self.index.max_search,
+                Arc::clone(&self.stats.data),
+                self.rng.gen(),
+                self.fail_points.clone(),
+                self.scratch.clone(),
+            )?)
         }
+        Ok(())
+    }
+
+    /// Capacity of a new data storage with cells of `cell_bytes`
+    pub(crate) fn data_capacity_pow2(data_capacity_bytes: Option<u64>, cell_bytes: u64) -> u8 {
+        data_capacity_bytes
+            .map(|bytes| BucketStorage::capacity_pow2_for_bytes(bytes, cell_bytes))
+            .unwrap_or(DEFAULT_CAPACITY_POW2)
+    }
+
+    pub fn grow_data(&mut self, sz: (u64, u8)) -> io::Result<()> {
+        self.create_data_buckets(sz.0)?;
         if self.data[sz.0 as usize].capacity_pow2 == sz.1 {
             //debug!("GROW_DATA: {} {}", sz.0, sz.1);
-            self.data[sz.0 as usize].grow();
+            self.data[sz.0 as usize].grow(self.grow_pow2, self.progress.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Move every file of the bucket to `drives`, where later grows create their files too.
+    /// On error the files moved so far stay where they were moved to, which is still a working
+    /// bucket, the rest stay where they were and later grows still create their files on the
+    /// previous drives.
+    pub fn move_to(&mut self, drives: &Arc<Vec<PathBuf>>) -> io::Result<()> {
+        self.index.move_to(Arc::clone(drives))?;
+        for data in self.data.iter_mut() {
+            data.move_to(Arc::clone(drives))?;
+        }
+        self.drives = Arc::clone(drives);
+        Ok(())
+    }
+
+    /// Grow the index, independently of the data, until it has at least `cells` cells
+    pub fn reserve_index(&mut self, cells: u64) -> io::Result<()> {
+        while self.index.capacity() < cells {
+            self.grow_index(self.index.capacity_pow2)?;
         }
+        Ok(())
+    }
+
+    /// Grow the data storage holding values of `num_slots` elements, independently of the
+    /// index, until it is at least `bytes` large
+    pub fn reserve_data(&mut self, num_slots: u64, bytes: u64) -> io::Result<()> {
+        let data_bucket_ix = IndexEntry::data_bucket_from_num_slots(num_slots);
+        self.create_data_buckets(data_bucket_ix)?;
+        let data_bucket = &mut self.data[data_bucket_ix as usize];
+        while data_bucket.capacity() * data_bucket.cell_size < bytes {
+            data_bucket.grow(self.grow_pow2, self.progress.as_ref())?;
+        }
+        Ok(())
     }
 
     fn bucket_index_ix(index: &BucketStorage, key: &Pubkey, random: u64) -> u64 {
@@ -368,41 +1251,70 @@ impl<T: Clone + Copy> Bucket<T> {
     }
 
     /// grow the appropriate piece
-    pub fn grow(&mut self, err: BucketMapError) {
-        match err {
+    pub fn grow(&mut self, err: BucketMapError) -> io::Result<()> {
+        let mut m = Measure::start("grow");
+        self.set_busy(true);
+        let result = match err {
             BucketMapError::DataNoSpace(sz) => {
                 //debug!("GROWING SPACE {:?}", sz);
-                self.grow_data(sz);
+                self.grow_data(sz)
             }
             BucketMapError::IndexNoSpace(sz) => {
                 //debug!("GROWING INDEX {}", sz);
-                self.grow_index(sz);
+                self.grow_index(sz)
             }
+            // nothing to grow
+            BucketMapError::Io(err) => Err(err),
+            err @ (BucketMapError::RefCountOverflow
+            | BucketMapError::RefCountUnderflow
+            | BucketMapError::InvariantViolation(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't grow for {:?}", err),
+            )),
+        };
+        self.set_busy(false);
+        if let Some(throttle) = self.throttle.as_ref() {
+            throttle.record_grow();
         }
+        m.stop();
+        self.op_stats.grow.update(m.as_us());
+        result
     }
 
-    pub fn insert(&mut self, key: &Pubkey, value: (&[T], RefCount)) {
+    /// Returns the ref count that was overwritten, or None if `key` was not present
+    pub fn insert(&mut self, key: &Pubkey, value: (&[T], RefCount)) -> Option<RefCount> {
         let (new, refct) = value;
+        // a failed try_write can leave a new index entry behind, so look before writing
+        let previous = self.find_entry(key).map(|(elem, _)| elem.ref_count());
         loop {
             let rv = self.try_write(key, new, refct);
             match rv {
-                Ok(_) => return,
-                Err(err) => self.grow(err),
+                Ok(_) => return previous,
+                Err(err) => self.grow(err).expect("Unable to grow bucket"),
             }
         }
     }
 
-    pub fn update<F>(&mut self, key: &Pubkey, updatefn: F)
+    /// Returns the change made, None if `key` was neither present nor written
+    pub fn update<F>(&mut self, key: &Pubkey, updatefn: F) -> Option<ChangeKind>
     where
         F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
     {
         let current = self.read_value(key);
-        let new = updatefn(current);
+        let new = updatefn(
+            current
+                .as_ref()
+                .map(|(value, ref_count)| (&value[..], *ref_count)),
+        );
         if new.is_none() {
-            self.delete_key(key);
-            return;
+            return if self.delete_key(key) {
+                Some(ChangeKind::Delete)
+            } else {
+                None
+            };
         }
         let (new, refct) = new.unwrap();
-        self.insert(key, (&new, refct));
+        let previous = self.insert(key, (&new, refct));
+        Some(ChangeKind::of_write(previous.is_some()))
     }
 }
@@ -1,5 +1,8 @@
+use crate::bucket::Bucket;
 use crate::RefCount;
 use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
+use std::sync::{Arc, RwLockReadGuard};
 
 #[derive(Debug, Default, Clone)]
 pub struct BucketItem<T> {
@@ -7,3 +10,56 @@ pub struct BucketItem<T> {
     pub ref_count: RefCount,
     pub slot_list: Vec<T>,
 }
+
+/// A BucketItem whose value is borrowed from the map's files, unless the map's codec or
+/// encryption had to decode it
+#[derive(Debug, Clone)]
+pub struct BucketItemRef<'a, T: Clone> {
+    pub pubkey: Pubkey,
+    pub ref_count: RefCount,
+    pub slot_list: Cow<'a, [T]>,
+}
+
+impl<'a, T: Clone> BucketItemRef<'a, T> {
+    pub fn into_owned(self) -> BucketItem<T> {
+        BucketItem {
+            pubkey: self.pubkey,
+            ref_count: self.ref_count,
+            slot_list: self.slot_list.into_owned(),
+        }
+    }
+}
+
+/// The items of a bucket, see BucketMap::scan_bucket and BucketMap::scan_bucket_stale.
+/// Writers of the bucket block until the scan is dropped, unless it reads a replica.
+pub struct BucketScan<'a, T> {
+    source: ScanSource<'a, T>,
+}
+
+enum ScanSource<'a, T> {
+    Bucket(RwLockReadGuard<'a, Option<Bucket<T>>>),
+    Replica(Arc<Bucket<T>>),
+}
+
+impl<'a, T: Clone + Copy> BucketScan<'a, T> {
+    pub(crate) fn of_bucket(bucket: RwLockReadGuard<'a, Option<Bucket<T>>>) -> Self {
+        Self {
+            source: ScanSource::Bucket(bucket),
+        }
+    }
+
+    pub(crate) fn of_replica(replica: Arc<Bucket<T>>) -> Self {
+        Self {
+            source: ScanSource::Replica(replica),
+        }
+    }
+
+    /// Every item of the bucket, in index order
+    pub fn iter(&self) -> impl Iterator<Item = BucketItemRef<'_, T>> {
+        let bucket = match &self.source {
+            ScanSource::Bucket(bucket) => bucket.as_ref(),
+            ScanSource::Replica(replica) => Some(replica.as_ref()),
+        };
+        bucket.into_iter().flat_map(|bucket| bucket.iter())
+    }
+}
@@ -1,29 +1,205 @@
 //! BucketMap is a mostly contention free concurrent map backed by MmapMut
 
-use crate::bucket::Bucket;
-use crate::bucket_item::BucketItem;
-use crate::bucket_stats::BucketMapStats;
+use crate::assert_mode::{AssertMode, InvariantViolation};
+use crate::bucket::{Bucket, BucketConfig};
+use crate::bucket_item::{BucketItem, BucketScan};
+use crate::bucket_stats::{BucketMapStats, BucketMapStatsSnapshot, DEFAULT_RATE_WINDOW};
+use crate::bucket_storage::DEFAULT_CAPACITY_POW2;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::capacity_hints::CapacityHints;
+use crate::change_feed::{Change, ChangeKind};
+use crate::check::{BucketCheck, CheckReport, QUICK_CHECK_SAMPLES};
+use crate::clock::{Clock, SystemClock};
+use crate::debug_export::{self, ExportFormat};
+use crate::disk_index::DiskIndexBackend;
+#[cfg(feature = "encryption")]
+use crate::encryption::{Encryption, EncryptionKey};
+#[cfg(feature = "fail-points")]
+use crate::fail_points::FailPoints;
+use crate::grow_pool::{GrowHandle, GrowPool};
+use crate::index_entry::{DataCellPrefix, IndexEntry};
+use crate::layout::{CellLayout, MAX_ELEMENT_ALIGN, MAX_STORAGE_BYTES};
+use crate::membership::BucketMembership;
+use crate::memory_usage::{BucketMemoryUsage, MemoryReport};
+use crate::metadata::{BucketMetadata, MapMetadata, METADATA_FILE};
+use crate::prefetch_iter::{PrefetchIter, SnapshotIter};
+use crate::priority::PriorityGate;
+use crate::progress::{ProgressCallback, ProgressOperation};
+use crate::replica::{Replica, ReplicaConfig};
+use crate::scratch_pool::ScratchPool;
+use crate::throttle::{ThrottleConfig, WriteThrottle};
+use crate::tiering::{Tiering, TieringConfig};
+use crate::trace::{TraceId, TracedError};
+use crate::value_codec::{IdentityCodec, ValueCodec};
+use crate::value_report::{LargeEntry, LargestEntries, ValueLenHistogram};
 use crate::{MaxSearch, RefCount};
+use crossbeam_channel::Sender;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use siphasher::sip::SipHasher24;
+use solana_measure::measure::Measure;
 use solana_sdk::pubkey::Pubkey;
-use std::convert::TryInto;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs;
-use std::ops::RangeBounds;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::mem::{align_of, size_of};
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::{RwLock, RwLockWriteGuard};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::Duration;
 use tempfile::TempDir;
 
+/// How a Pubkey is assigned to a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketAssignment {
+    /// The high bits of the Pubkey select the bucket, so each bucket holds a contiguous range of
+    /// Pubkeys. Changing max_buckets moves nearly every key to a different bucket.
+    Prefix,
+    /// Jump consistent hash of the Pubkey. Changing max_buckets from n to m only moves about
+    /// |n - m| / max(n, m) of the keys, but buckets no longer hold contiguous Pubkey ranges.
+    JumpConsistentHash,
+}
+
+impl Default for BucketAssignment {
+    fn default() -> Self {
+        BucketAssignment::Prefix
+    }
+}
+
+/// What addref and unref do when a ref count would go past u64::MAX or below 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefCountMode {
+    /// Wrap around, as the ref count arithmetic of release builds always did
+    Wrapping,
+    /// Stay at u64::MAX or 0
+    Saturating,
+    /// Leave the ref count unchanged and fail with RefCountOverflow or RefCountUnderflow
+    Strict,
+}
+
+/// Wrapping in every build profile, so tests run the ref count semantics production does.
+/// Callers that want overflows caught opt into Saturating or Strict.
+impl Default for RefCountMode {
+    fn default() -> Self {
+        RefCountMode::Wrapping
+    }
+}
+
+impl RefCountMode {
+    pub fn addref(self, ref_count: RefCount) -> Result<RefCount, BucketMapError> {
+        match self {
+            RefCountMode::Wrapping => Ok(ref_count.wrapping_add(1)),
+            RefCountMode::Saturating => Ok(ref_count.saturating_add(1)),
+            RefCountMode::Strict => ref_count
+                .checked_add(1)
+                .ok_or(BucketMapError::RefCountOverflow),
+        }
+    }
+
+    pub fn unref(self, ref_count: RefCount) -> Result<RefCount, BucketMapError> {
+        match self {
+            RefCountMode::Wrapping => Ok(ref_count.wrapping_sub(1)),
+            RefCountMode::Saturating => Ok(ref_count.saturating_sub(1)),
+            RefCountMode::Strict => ref_count
+                .checked_sub(1)
+                .ok_or(BucketMapError::RefCountUnderflow),
+        }
+    }
+}
+
+/// SipHash key used to hash Pubkeys before a bucket is selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketHashKey(pub [u64; 2]);
+
+impl BucketHashKey {
+    pub fn new_rand() -> Self {
+        Self(thread_rng().gen())
+    }
+
+    fn hash(&self, key: &Pubkey) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.0[0], self.0[1]);
+        hasher.write(key.as_ref());
+        hasher.finish()
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct BucketMapConfig {
     pub max_buckets: usize,
     pub drives: Option<Vec<PathBuf>>,
     pub max_search: Option<MaxSearch>,
+    /// Initial number of index cells per bucket, as a power of two
+    pub index_capacity_pow2: Option<u8>,
+    /// Initial size in bytes of each data storage in a bucket, independent of the index size
+    pub data_capacity_bytes: Option<u64>,
+    pub bucket_assignment: BucketAssignment,
+    /// When set, Pubkeys are hashed with this key before a bucket is selected, so an attacker
+    /// can't grind addresses that all land in the same bucket. A map that is reopened has to
+    /// use the same key, see BucketMap::bucket_hash_key.
+    pub bucket_hash_key: Option<BucketHashKey>,
+    /// Per bucket write budget reported through BucketMap::would_block
+    pub throttle: Option<ThrottleConfig>,
+    /// Bounds handling of addref and unref, RefCountMode::Wrapping by default
+    pub ref_count_mode: RefCountMode,
+    /// What happens when an internal invariant doesn't hold, see assert_mode.rs
+    pub assert_mode: AssertMode,
+    /// Storage used by BackendIndex::new. BucketMap::new ignores this.
+    pub backend: DiskIndexBackend,
+    /// Called with the progress of grows, gc_data, export_debug and quick_check
+    pub progress: Option<ProgressCallback>,
+    /// Seed for every random choice the map makes: hash offsets, data placement, drive and file
+    /// names. Together with the capacity settings, a seeded map that sees the same operations
+    /// in the same order creates the same files and grows at the same points.
+    /// None seeds from entropy.
+    pub seed: Option<u64>,
+    /// Number of powers of two a storage grows by when it runs out of space, 1 by default
+    pub grow_pow2: Option<u8>,
+    /// Most grows BucketMap::grow_async runs at once, one per core by default
+    pub grow_workers: Option<usize>,
+    /// Buckets to keep read only replicas of, see BucketMap::refresh_replicas
+    pub replicas: Option<ReplicaConfig>,
+    /// Fast drives for the most read buckets, see tiering.rs. `drives` are the slow tier.
+    pub tiering: Option<TieringConfig>,
+    /// Alignment of the values in data cells, align_of::<T>() by default. A larger alignment
+    /// pads every cell, see layout.rs.
+    pub element_align: Option<u64>,
+    /// Length of the longest value that will be stored, after the codec and encryption.
+    /// BucketMap::try_new fails if the data storages can't hold values that long.
+    pub max_value_len: Option<u64>,
+    /// Expected size of every bucket, from a previous run. Buckets are created at that size
+    /// instead of growing to it.
+    pub capacity_hints: Option<CapacityHints>,
+    /// Sent a Change for every write that changes an entry, see change_feed.rs
+    pub change_feed: Option<Sender<Change>>,
+    /// Store the key, ref count and length of every value in front of it in its data cell, 48
+    /// more bytes per cell, so BucketMap::rebuild_index can recreate a lost index.
+    pub data_cell_keys: bool,
+    /// Up to this many bytes of files left behind by grows are kept mapped and reused by later
+    /// grows of any bucket, see scratch_pool.rs. None creates a new file for every grow.
+    pub scratch_pool_bytes: Option<u64>,
+    /// Encrypts the keys and values in every file, see encryption.rs. A map that is reopened has
+    /// to use the same key.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Time source of throttling, coalescing and the operation rates in the stats, see clock.rs.
+    /// None is the real time.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Window the operation rates in the stats are averaged over, DEFAULT_RATE_WINDOW by default
+    pub rate_window: Option<Duration>,
+    /// Injects IO errors into file creation and grows, see FailPoints
+    #[cfg(feature = "fail-points")]
+    pub fail_points: Option<Arc<FailPoints>>,
 }
 
 impl BucketMapConfig {
     /// Create a new BucketMapConfig
-    /// NOTE: BucketMap requires that max_buckets is a power of two
+    /// NOTE: With BucketAssignment::Prefix, BucketMap requires that max_buckets is a power of
+    /// two. JumpConsistentHash takes any number of buckets.
     pub fn new(max_buckets: usize) -> BucketMapConfig {
         BucketMapConfig {
             max_buckets,
@@ -32,19 +208,58 @@ impl BucketMapConfig {
     }
 }
 
+/// Group commit state for BucketMap::flush.
+/// Every caller takes the next epoch. A flush pass covers every epoch taken before it started.
+#[derive(Debug, Default)]
+struct SyncState {
+    requested: u64,
+    completed: u64,
+    in_progress: bool,
+    // result of the most recent pass
+    error: Option<io::ErrorKind>,
+}
+
 pub struct BucketMap<T: Clone + Copy + Debug> {
-    buckets: Vec<RwLock<Option<Bucket<T>>>>,
-    drives: Arc<Vec<PathBuf>>,
+    pub(crate) buckets: Vec<RwLock<Option<Bucket<T>>>>,
+    // set when a bucket is written, cleared when it is flushed
+    dirty: Vec<AtomicBool>,
+    // bumped whenever a bucket is locked for writing, see bucket_generation
+    generations: Vec<AtomicU64>,
+    // critical reads of every bucket, see priority.rs
+    priority_gates: Vec<PriorityGate>,
+    sync_state: Mutex<SyncState>,
+    sync_done: Condvar,
+    bucket_config: BucketConfig,
+    // only used by Prefix assignment, the only one that requires a power of two
     max_buckets_pow2: u8,
-    max_search: MaxSearch,
+    bucket_assignment: BucketAssignment,
+    bucket_hash_key: Option<BucketHashKey>,
+    seed: Option<u64>,
+    throttles: Option<Vec<Arc<WriteThrottle>>>,
+    replicas: HashMap<usize, Replica<T>>,
+    replica_refresh_interval: Option<Duration>,
+    tiering: Option<Tiering>,
+    capacity_hints: Option<CapacityHints>,
+    codec: Arc<dyn ValueCodec<T>>,
+    change_feed: Option<Sender<Change>>,
+    grow_pool: GrowPool,
+    clock: Arc<dyn Clock>,
     pub stats: Arc<BucketMapStats>,
     pub temp_dir: Option<TempDir>,
+    // set by into_persistent, so Drop leaves the drives alone
+    keep_files: bool,
 }
 
 impl<T: Clone + Copy + Debug> Drop for BucketMap<T> {
     fn drop(&mut self) {
+        if self.keep_files {
+            return;
+        }
         if self.temp_dir.is_none() {
-            BucketMap::<T>::erase_previous_drives(&self.drives);
+            BucketMap::<T>::erase_previous_drives(&self.bucket_config.drives);
+        }
+        if let Some(tiering) = self.tiering.as_ref() {
+            BucketMap::<T>::erase_previous_drives(&tiering.fast_drives);
         }
     }
 }
@@ -55,50 +270,363 @@ impl<T: Clone + Copy + Debug> std::fmt::Debug for BucketMap<T> {
     }
 }
 
+/// Index entry and data cell claimed by BucketMap::reserve for a key
+#[must_use = "a reservation holds space until it is committed or released"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reservation {
+    ix: usize,
+    key: Pubkey,
+}
+
+impl Reservation {
+    pub fn key(&self) -> &Pubkey {
+        &self.key
+    }
+}
+
 #[derive(Debug)]
 pub enum BucketMapError {
     DataNoSpace((u64, u8)),
     IndexNoSpace(u8),
+    /// creating or growing a storage failed
+    Io(io::Error),
+    /// addref of a ref count of u64::MAX in RefCountMode::Strict
+    RefCountOverflow,
+    /// unref of a ref count of 0 in RefCountMode::Strict
+    RefCountUnderflow,
+    /// an internal invariant didn't hold in AssertMode::Resilient, see assert_mode.rs
+    InvariantViolation(InvariantViolation),
 }
 
+impl From<InvariantViolation> for BucketMapError {
+    fn from(violation: InvariantViolation) -> Self {
+        BucketMapError::InvariantViolation(violation)
+    }
+}
+
+/// A BucketMapConfig BucketMap::try_new rejects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// max_buckets is 0
+    NoBuckets,
+    /// max_buckets isn't a power of two, which BucketAssignment::Prefix requires
+    MaxBucketsNotPowerOfTwo(usize),
+    /// index_capacity_pow2 is too large for the number of cells to fit in a u64
+    IndexCapacityTooLarge(u8),
+    /// capacity_hints has a different number of buckets than the map
+    CapacityHintsMismatch { hints: usize, max_buckets: usize },
+    /// grow_pow2 is 0, so storages wouldn't grow
+    NoGrowth,
+    /// a bucket to replicate doesn't exist
+    ReplicaOutOfRange { ix: usize, max_buckets: usize },
+    /// tiering has no fast drives
+    NoFastDrives,
+    /// grow_workers is Some(0)
+    NoGrowWorkers,
+    /// max_search is Some(0), so no cell could ever be found for a key
+    NoSearch,
+    /// element_align isn't a power of two between the alignment of the element type and
+    /// MAX_ELEMENT_ALIGN
+    InvalidElementAlign {
+        align: u64,
+        min_align: u64,
+        max_align: u64,
+    },
+    /// a setting BackendIndex with DiskIndexBackend::Kv can't honor, named by its field
+    UnsupportedByKvBackend(&'static str),
+    /// Values of max_value_len elements need a data storage larger than MAX_STORAGE_BYTES
+    ValueTooLarge {
+        max_value_len: u64,
+        element_bytes: u64,
+        /// longest value the data storages can hold
+        max_supported_len: u64,
+        /// size of the first data storage for values of max_supported_len elements
+        max_supported_storage_bytes: u64,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NoBuckets => write!(f, "max number of buckets must be non-zero"),
+            ConfigError::MaxBucketsNotPowerOfTwo(max_buckets) => write!(
+                f,
+                "prefix assignment needs a power of two buckets, not {}",
+                max_buckets
+            ),
+            ConfigError::IndexCapacityTooLarge(pow2) => {
+                write!(f, "index capacity 2^{} doesn't fit in a u64", pow2)
+            }
+            ConfigError::CapacityHintsMismatch { hints, max_buckets } => write!(
+                f,
+                "capacity hints are for {} buckets, the map has {}",
+                hints, max_buckets
+            ),
+            ConfigError::NoGrowth => write!(f, "storages have to grow by at least a power of two"),
+            ConfigError::ReplicaOutOfRange { ix, max_buckets } => write!(
+                f,
+                "replicated bucket {} doesn't exist, the map has {} buckets",
+                ix, max_buckets
+            ),
+            ConfigError::NoFastDrives => write!(f, "the fast tier needs at least one drive"),
+            ConfigError::NoGrowWorkers => write!(f, "grow_workers must be non-zero"),
+            ConfigError::NoSearch => write!(f, "max_search must be non-zero"),
+            ConfigError::UnsupportedByKvBackend(setting) => {
+                write!(f, "the Kv backend doesn't support {}", setting)
+            }
+            ConfigError::InvalidElementAlign {
+                align,
+                min_align,
+                max_align,
+            } => write!(
+                f,
+                "element alignment {} has to be a power of two between {} and {}",
+                align, min_align, max_align
+            ),
+            ConfigError::ValueTooLarge {
+                max_value_len,
+                element_bytes,
+                max_supported_len,
+                max_supported_storage_bytes,
+            } => write!(
+                f,
+                "values of {} elements of {} bytes don't fit in a data storage, the longest \
+                 supported value has {} elements in a storage of {} bytes",
+                max_value_len, element_bytes, max_supported_len, max_supported_storage_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 impl<T: Clone + Copy + Debug> BucketMap<T> {
     pub fn new(config: BucketMapConfig) -> Self {
-        assert_ne!(
-            config.max_buckets, 0,
-            "Max number of buckets must be non-zero"
-        );
-        assert!(
-            config.max_buckets.is_power_of_two(),
-            "Max number of buckets must be a power of two"
-        );
+        Self::new_with_codec(config, Arc::new(IdentityCodec))
+    }
+
+    /// Create a map that stores every value as `codec` encodes it, see ValueCodec
+    pub fn new_with_codec(config: BucketMapConfig, codec: Arc<dyn ValueCodec<T>>) -> Self {
+        Self::try_new_with_codec(config, codec)
+            .unwrap_or_else(|err| panic!("Invalid BucketMapConfig: {:?}", err))
+    }
+
+    /// Same as new, but returns an error for a config the map can't work with, before any
+    /// drive is erased
+    pub fn try_new(config: BucketMapConfig) -> Result<Self, ConfigError> {
+        Self::try_new_with_codec(config, Arc::new(IdentityCodec))
+    }
+
+    pub fn try_new_with_codec(
+        config: BucketMapConfig,
+        codec: Arc<dyn ValueCodec<T>>,
+    ) -> Result<Self, ConfigError> {
+        Self::check_config(&config)?;
+        let prefix = config.bucket_assignment == BucketAssignment::Prefix;
         let mut buckets = Vec::with_capacity(config.max_buckets);
         buckets.resize_with(config.max_buckets, || RwLock::new(None));
-        let stats = Arc::new(BucketMapStats::default());
+        let mut dirty = Vec::with_capacity(config.max_buckets);
+        dirty.resize_with(config.max_buckets, AtomicBool::default);
+        let mut generations = Vec::with_capacity(config.max_buckets);
+        generations.resize_with(config.max_buckets, AtomicU64::default);
+        let mut priority_gates = Vec::with_capacity(config.max_buckets);
+        priority_gates.resize_with(config.max_buckets, PriorityGate::default);
+        let clock = config.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let stats = Arc::new(BucketMapStats::new_with_clock(
+            config.max_buckets,
+            clock.clone(),
+            config.rate_window.unwrap_or(DEFAULT_RATE_WINDOW),
+        ));
         // this should be <= 1 << DEFAULT_CAPACITY or we end up searching the same items over and over - probably not a big deal since it is so small anyway
         const MAX_SEARCH: MaxSearch = 32;
         let max_search = config.max_search.unwrap_or(MAX_SEARCH);
+        let index_capacity_pow2 = config.index_capacity_pow2.unwrap_or(DEFAULT_CAPACITY_POW2);
+        let mut data_layout = CellLayout::new::<T>(config.element_align);
+        if config.data_cell_keys {
+            data_layout = data_layout.with_prefix(size_of::<DataCellPrefix>() as u64);
+        }
+        if let Some(max_value_len) = config.max_value_len {
+            Self::check_max_value_len(&data_layout, config.data_capacity_bytes, max_value_len)?;
+        }
+        let grow_pow2 = config.grow_pow2.unwrap_or(1);
 
         if let Some(drives) = config.drives.as_ref() {
             Self::erase_previous_drives(drives);
         }
+        if let Some(tiering) = config.tiering.as_ref() {
+            Self::erase_previous_drives(&tiering.fast_drives);
+        }
         let mut temp_dir = None;
         let drives = config.drives.unwrap_or_else(|| {
             temp_dir = Some(TempDir::new().unwrap());
             vec![temp_dir.as_ref().unwrap().path().to_path_buf()]
         });
         let drives = Arc::new(drives);
+        let max_buckets = config.max_buckets;
+        let throttles = config.throttle.map(|throttle| {
+            (0..max_buckets)
+                .map(|_| Arc::new(WriteThrottle::with_clock(throttle, clock.clone())))
+                .collect()
+        });
 
         // A simple log2 function that is correct if x is a power of two
         let log2 = |x: usize| usize::BITS - x.leading_zeros() - 1;
+        let replica_refresh_interval = config
+            .replicas
+            .as_ref()
+            .map(|replicas| replicas.refresh_interval);
+        let seed = config.seed;
+        let replicas = config
+            .replicas
+            .map(|replicas| replicas.buckets)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|ix| {
+                // streams after the buckets' own
+                let rng = new_rng(seed, (max_buckets + ix) as u64);
+                (ix, Replica::new(rng))
+            })
+            .collect();
+        #[cfg(feature = "fail-points")]
+        let fail_points = config.fail_points;
+        #[cfg(not(feature = "fail-points"))]
+        let fail_points = None;
+        #[cfg(feature = "encryption")]
+        let encryption = config
+            .encryption_key
+            .as_ref()
+            .map(|key| Arc::new(Encryption::new(key)));
 
-        Self {
+        Ok(Self {
             buckets,
-            drives,
-            max_buckets_pow2: log2(config.max_buckets) as u8,
+            dirty,
+            generations,
+            priority_gates,
+            sync_state: Mutex::default(),
+            sync_done: Condvar::new(),
+            bucket_config: BucketConfig {
+                drives,
+                max_search,
+                stats: Arc::clone(&stats),
+                index_capacity_pow2,
+                data_capacity_bytes: config.data_capacity_bytes,
+                grow_pow2,
+                progress: config.progress,
+                fail_points,
+                data_layout,
+                ref_count_mode: config.ref_count_mode,
+                assert_mode: config.assert_mode,
+                scratch: config
+                    .scratch_pool_bytes
+                    .map(|bytes| Arc::new(ScratchPool::new(bytes))),
+                #[cfg(feature = "encryption")]
+                encryption,
+            },
+            max_buckets_pow2: if prefix { log2(max_buckets) as u8 } else { 0 },
+            bucket_assignment: config.bucket_assignment,
+            bucket_hash_key: config.bucket_hash_key,
+            seed,
             stats,
-            max_search,
+            throttles,
+            replicas,
+            replica_refresh_interval,
+            tiering: config
+                .tiering
+                .map(|tiering| Tiering::new(tiering, max_buckets)),
+            capacity_hints: config.capacity_hints,
+            codec,
+            change_feed: config.change_feed,
+            grow_pool: GrowPool::new(config.grow_workers),
+            clock,
             temp_dir,
+            keep_files: false,
+        })
+    }
+
+    /// Err for the settings of `config` the map can't work with, other than max_value_len
+    fn check_config(config: &BucketMapConfig) -> Result<(), ConfigError> {
+        let max_buckets = config.max_buckets;
+        if max_buckets == 0 {
+            return Err(ConfigError::NoBuckets);
+        }
+        if config.bucket_assignment == BucketAssignment::Prefix && !max_buckets.is_power_of_two() {
+            return Err(ConfigError::MaxBucketsNotPowerOfTwo(max_buckets));
+        }
+        if let Some(pow2) = config
+            .index_capacity_pow2
+            .filter(|pow2| *pow2 >= u64::BITS as u8)
+        {
+            return Err(ConfigError::IndexCapacityTooLarge(pow2));
+        }
+        if let Some(hints) = config.capacity_hints.as_ref() {
+            if hints.bucket_entries.len() != max_buckets {
+                return Err(ConfigError::CapacityHintsMismatch {
+                    hints: hints.bucket_entries.len(),
+                    max_buckets,
+                });
+            }
+        }
+        if config.grow_pow2 == Some(0) {
+            return Err(ConfigError::NoGrowth);
+        }
+        if let Some(replicas) = config.replicas.as_ref() {
+            if let Some(ix) = replicas.buckets.iter().find(|ix| **ix >= max_buckets) {
+                return Err(ConfigError::ReplicaOutOfRange {
+                    ix: *ix,
+                    max_buckets,
+                });
+            }
+        }
+        if matches!(config.tiering.as_ref(), Some(tiering) if tiering.fast_drives.is_empty()) {
+            return Err(ConfigError::NoFastDrives);
+        }
+        if config.grow_workers == Some(0) {
+            return Err(ConfigError::NoGrowWorkers);
+        }
+        if config.max_search == Some(0) {
+            return Err(ConfigError::NoSearch);
+        }
+        if let Some(align) = config
+            .element_align
+            .filter(|align| !CellLayout::valid_align::<T>(*align))
+        {
+            return Err(ConfigError::InvalidElementAlign {
+                align,
+                min_align: align_of::<T>() as u64,
+                max_align: MAX_ELEMENT_ALIGN,
+            });
+        }
+        Ok(())
+    }
+
+    /// Err if the first data storage for values of `max_value_len` elements would be larger
+    /// than MAX_STORAGE_BYTES
+    fn check_max_value_len(
+        data_layout: &CellLayout,
+        data_capacity_bytes: Option<u64>,
+        max_value_len: u64,
+    ) -> Result<(), ConfigError> {
+        // data storage `ix` holds values of up to 2^ix elements
+        let storage_bytes = |ix: u64| {
+            let cell_bytes = data_layout.checked_cell_bytes(1u64.checked_shl(ix as u32)?)?;
+            let capacity_pow2 = Bucket::<T>::data_capacity_pow2(data_capacity_bytes, cell_bytes);
+            cell_bytes
+                .checked_mul(1u64.checked_shl(capacity_pow2 as u32)?)
+                .filter(|bytes| *bytes <= MAX_STORAGE_BYTES)
+        };
+        if storage_bytes(IndexEntry::data_bucket_from_num_slots(max_value_len)).is_some() {
+            return Ok(());
         }
+        let (max_supported_len, max_supported_storage_bytes) = (0..u64::BITS as u64)
+            .rev()
+            .find_map(|ix| storage_bytes(ix).map(|bytes| (1 << ix, bytes)))
+            .unwrap_or((0, 0));
+        Err(ConfigError::ValueTooLarge {
+            max_value_len,
+            element_bytes: data_layout.element_bytes,
+            max_supported_len,
+            max_supported_storage_bytes,
+        })
     }
 
     fn erase_previous_drives(drives: &[PathBuf]) {
@@ -112,6 +640,36 @@ impl<T: Clone + Copy + Debug> BucketMap<T> {
         self.buckets.len()
     }
 
+    /// Layout of the cells values are stored in
+    pub fn data_layout(&self) -> CellLayout {
+        self.bucket_config.data_layout
+    }
+
+    /// Generation of bucket `ix`, which changes whenever the bucket is written or grown.
+    /// It may also change when a write leaves the bucket as it was. A scan of the bucket that
+    /// starts after the generation is read sees every write up to that generation.
+    pub fn bucket_generation(&self, ix: usize) -> u64 {
+        self.generations[ix].load(Ordering::Acquire)
+    }
+
+    // called with the write lock of the key's bucket held, so the bucket's changes stay in order
+    fn notify(&self, key: &Pubkey, kind: ChangeKind) {
+        self.notify_traced(key, kind, None);
+    }
+
+    fn notify_traced(&self, key: &Pubkey, kind: ChangeKind, trace_id: Option<TraceId>) {
+        if let Some(change_feed) = self.change_feed.as_ref() {
+            // a receiver that went away doesn't want any more changes
+            let _ = change_feed.send((*key, kind, trace_id));
+        }
+    }
+
+    // called with the write lock of bucket `ix` held, before the bucket is written
+    fn mark_written(&self, ix: usize) {
+        self.dirty[ix].store(true, Ordering::Relaxed);
+        self.generations[ix].fetch_add(1, Ordering::Release);
+    }
+
     pub fn bucket_len(&self, ix: usize) -> u64 {
         self.buckets[ix]
             .read()
@@ -126,298 +684,3232 @@ impl<T: Clone + Copy + Debug> BucketMap<T> {
     where
         R: RangeBounds<Pubkey>,
     {
-        self.buckets[ix]
-            .read()
-            .unwrap()
-            .as_ref()
-            .map(|bucket| bucket.items_in_range(range))
-            .unwrap_or_default()
+        self.items_in_range_cancellable(ix, range, None).unwrap()
     }
 
-    /// Get the Pubkeys for bucket `ix`
-    pub fn keys(&self, ix: usize) -> Vec<Pubkey> {
-        self.buckets[ix]
-            .read()
-            .unwrap()
-            .as_ref()
-            .map_or_else(Vec::default, |bucket| bucket.keys())
+    /// Get the items for bucket `ix` in `range`, giving up if `cancel` is cancelled
+    pub fn items_in_range_cancellable<R>(
+        &self,
+        ix: usize,
+        range: &Option<&R>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<BucketItem<T>>, Cancelled>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        self.filter_items_in_range_cancellable(ix, range, cancel, |_, _| true)
     }
 
-    /// Get the values for Pubkey `key`
-    pub fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
-        let ix = self.bucket_ix(key);
-        self.buckets[ix]
-            .read()
+    /// Get the items for bucket `ix` in `range` whose value and ref count match `pred`.
+    /// `pred` is applied to values in place, so only the matching values are copied.
+    pub fn filter_items_in_range<R, F>(
+        &self,
+        ix: usize,
+        range: &Option<&R>,
+        pred: F,
+    ) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+        F: Fn(&[T], RefCount) -> bool,
+    {
+        self.filter_items_in_range_cancellable(ix, range, None, pred)
             .unwrap()
-            .as_ref()
-            .and_then(|bucket| {
-                bucket
-                    .read_value(key)
-                    .map(|(value, ref_count)| (value.to_vec(), ref_count))
-            })
     }
 
-    /// Delete the Pubkey `key`
-    pub fn delete_key(&self, key: &Pubkey) {
-        let ix = self.bucket_ix(key);
-        if let Some(bucket) = self.buckets[ix].write().unwrap().as_mut() {
-            bucket.delete_key(key);
+    /// filter_items_in_range, giving up if `cancel` is cancelled
+    pub fn filter_items_in_range_cancellable<R, F>(
+        &self,
+        ix: usize,
+        range: &Option<&R>,
+        cancel: Option<&CancelToken>,
+        pred: F,
+    ) -> Result<Vec<BucketItem<T>>, Cancelled>
+    where
+        R: RangeBounds<Pubkey>,
+        F: Fn(&[T], RefCount) -> bool,
+    {
+        self.scan_lock(ix).as_ref().map_or_else(
+            || Ok(Vec::default()),
+            |bucket| bucket.filter_items_in_range(range, cancel, pred),
+        )
+    }
+
+    /// items_in_range_cancellable, but reads the replica of bucket `ix` if it has one, see
+    /// replica.rs. The items are as of the last refresh of the replica, so they may miss writes
+    /// that bucket_generation already covers.
+    pub fn items_in_range_stale<R>(
+        &self,
+        ix: usize,
+        range: &Option<&R>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<BucketItem<T>>, Cancelled>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        self.filter_items_in_range_stale(ix, range, cancel, |_, _| true)
+    }
+
+    /// filter_items_in_range_cancellable, but reads the replica of bucket `ix` if it has one,
+    /// see items_in_range_stale
+    pub fn filter_items_in_range_stale<R, F>(
+        &self,
+        ix: usize,
+        range: &Option<&R>,
+        cancel: Option<&CancelToken>,
+        pred: F,
+    ) -> Result<Vec<BucketItem<T>>, Cancelled>
+    where
+        R: RangeBounds<Pubkey>,
+        F: Fn(&[T], RefCount) -> bool,
+    {
+        match self.replica(ix) {
+            Some(replica) => replica.filter_items_in_range(range, cancel, pred),
+            None => self.filter_items_in_range_cancellable(ix, range, cancel, pred),
         }
     }
 
-    /// Update Pubkey `key`'s value with 'value'
-    pub fn insert(&self, ix: usize, key: &Pubkey, value: (&[T], RefCount)) {
-        let mut bucket = self.get_bucket(ix);
-        bucket.as_mut().unwrap().insert(key, value)
+    /// Replace the contents of `items` with the items of bucket `ix`.
+    /// The items already in `items` are overwritten in place, so a caller that scans repeatedly
+    /// with the same Vec reuses its allocation and those of the values instead of allocating
+    /// new ones on every scan.
+    pub fn copy_bucket(&self, ix: usize, items: &mut Vec<BucketItem<T>>) {
+        match self.scan_lock(ix).as_ref() {
+            Some(bucket) => bucket.copy_items(items),
+            None => items.clear(),
+        }
     }
 
-    fn get_bucket(&self, ix: usize) -> RwLockWriteGuard<Option<Bucket<T>>> {
-        let mut bucket = self.buckets[ix].write().unwrap();
-        if bucket.is_none() {
-            *bucket = Some(Bucket::new(
-                Arc::clone(&self.drives),
-                self.max_search,
-                Arc::clone(&self.stats),
-            ));
+    /// copy_bucket, but reads the replica of bucket `ix` if it has one, see items_in_range_stale
+    pub fn copy_bucket_stale(&self, ix: usize, items: &mut Vec<BucketItem<T>>) {
+        match self.replica(ix) {
+            Some(replica) => replica.copy_items(items),
+            None => self.copy_bucket(ix, items),
         }
-        bucket
     }
 
-    /// Update Pubkey `key`'s value with 'value'
-    pub fn try_insert(
+    /// Read lock bucket `ix` to iterate over its items without copying their values, e.g. to
+    /// aggregate statistics over the whole map. Writers of the bucket block until the scan is
+    /// dropped.
+    pub fn scan_bucket(&self, ix: usize) -> BucketScan<'_, T> {
+        BucketScan::of_bucket(self.scan_lock(ix))
+    }
+
+    /// scan_bucket, but takes the replica of bucket `ix` if it has one, so writers don't block,
+    /// see items_in_range_stale
+    pub fn scan_bucket_stale(&self, ix: usize) -> BucketScan<'_, T> {
+        match self.replica(ix) {
+            Some(replica) => BucketScan::of_replica(replica),
+            None => self.scan_bucket(ix),
+        }
+    }
+
+    /// Iterate over the items of every bucket, `chunk_size` index cells at a time.
+    /// The pages of the next chunk are prefetched while the current chunk is being processed.
+    /// The bucket being iterated stays read locked between calls to next, so writing to it
+    /// from the iterating thread deadlocks.
+    pub fn prefetch_iter(&self, chunk_size: usize) -> PrefetchIter<'_, T> {
+        PrefetchIter::new(self, chunk_size)
+    }
+
+    /// Same as prefetch_iter, but every chunk comes with the entries and value bytes of its
+    /// bucket, counted under the read lock its chunks are read with. The chunks of a bucket add
+    /// up to exactly those counts.
+    pub fn iter_with_stats(&self, chunk_size: usize) -> SnapshotIter<'_, T> {
+        SnapshotIter::new(self, chunk_size)
+    }
+
+    /// Write every entry to `writer` in `format`, sorted by Pubkey within each bucket.
+    /// Each bucket is read locked while its entries are written.
+    pub fn export_debug<W: Write>(&self, writer: &mut W, format: ExportFormat) -> io::Result<()> {
+        debug_export::write_header(writer, format)?;
+        let total_bytes = self.index_bytes();
+        let mut processed_bytes = 0;
+        for ix in 0..self.buckets.len() {
+            let bucket = self.scan_lock(ix);
+            if let Some(bucket) = bucket.as_ref() {
+                let mut entries = vec![];
+                bucket.scan_stored(|pubkey, value, ref_count| {
+                    entries.push((pubkey, value, ref_count))
+                });
+                entries.sort_unstable_by_key(|(pubkey, _, _)| *pubkey);
+                for (pubkey, value, ref_count) in entries {
+                    debug_export::write_entry(writer, format, &pubkey, value, ref_count)?;
+                }
+                processed_bytes += bucket.index_bytes();
+                self.report_progress(ProgressOperation::Export, processed_bytes, total_bytes);
+            }
+        }
+        self.report_progress(ProgressOperation::Export, total_bytes, total_bytes);
+        writer.flush()
+    }
+
+    /// Get the Pubkeys for bucket `ix`
+    pub fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        self.keys_cancellable(ix, None).unwrap()
+    }
+
+    /// Get the Pubkeys for bucket `ix`, giving up if `cancel` is cancelled
+    pub fn keys_cancellable(
         &self,
         ix: usize,
-        key: &Pubkey,
-        value: (&[T], RefCount),
-    ) -> Result<(), BucketMapError> {
-        let mut bucket = self.get_bucket(ix);
-        bucket.as_mut().unwrap().try_write(key, value.0, value.1)
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<Pubkey>, Cancelled> {
+        self.scan_lock(ix)
+            .as_ref()
+            .map_or_else(|| Ok(Vec::default()), |bucket| bucket.keys(cancel))
     }
 
-    /// if err is a grow error, then grow the appropriate piece
-    pub fn grow(&self, ix: usize, err: BucketMapError) {
-        let mut bucket = self.get_bucket(ix);
-        bucket.as_mut().unwrap().grow(err);
+    /// keys_cancellable, but reads the replica of bucket `ix` if it has one, see
+    /// items_in_range_stale
+    pub fn keys_stale(
+        &self,
+        ix: usize,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<Pubkey>, Cancelled> {
+        match self.replica(ix) {
+            Some(replica) => replica.keys(cancel),
+            None => self.keys_cancellable(ix, cancel),
+        }
     }
 
-    /// Update Pubkey `key`'s value with function `updatefn`
-    pub fn update<F>(&self, key: &Pubkey, updatefn: F)
-    where
-        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
-    {
-        let ix = self.bucket_ix(key);
-        let mut bucket = self.get_bucket(ix);
-        bucket.as_mut().unwrap().update(key, updatefn)
+    /// Filter of every key in the map, see membership.rs.
+    /// Buckets are read one at a time, so keys written meanwhile may or may not be included.
+    pub fn export_membership(&self) -> BucketMembership {
+        let keys = (0..self.num_buckets())
+            .flat_map(|ix| self.keys(ix))
+            .collect::<Vec<_>>();
+        BucketMembership::new(&keys)
     }
 
-    /// Get the bucket index for Pubkey `key`
-    pub fn bucket_ix(&self, key: &Pubkey) -> usize {
-        if self.max_buckets_pow2 > 0 {
-            let location = read_be_u64(key.as_ref());
-            (location >> (u64::BITS - self.max_buckets_pow2 as u32)) as usize
-        } else {
-            0
-        }
+    /// Get the values for Pubkey `key`
+    pub fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        self.try_read_value(key).ok().flatten()
     }
 
-    /// Increment the refcount for Pubkey `key`
-    pub fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+    /// Same as read_value, but an entry that is broken returns BucketMapError::InvariantViolation
+    /// in AssertMode::Resilient instead of reading as missing
+    pub fn try_read_value(
+        &self,
+        key: &Pubkey,
+    ) -> Result<Option<(Vec<T>, RefCount)>, BucketMapError> {
+        let mut m = Measure::start("read");
         let ix = self.bucket_ix(key);
-        let mut bucket = self.buckets[ix].write().unwrap();
-        bucket.as_mut()?.addref(key)
+        let result = match self.buckets[ix].read().unwrap().as_ref() {
+            Some(bucket) => bucket
+                .try_read_value(key)
+                .map(|value| value.map(|(value, ref_count)| (value.into_owned(), ref_count)))
+                .map_err(BucketMapError::from),
+            None => Ok(None),
+        };
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].read, m.as_us(), None);
+        result
     }
 
-    /// Decrement the refcount for Pubkey `key`
-    pub fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+    /// Same as read_value, but ahead of writers and background scans waiting for the bucket,
+    /// for the reads of consensus critical paths, see priority.rs
+    pub fn read_value_critical(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
         let ix = self.bucket_ix(key);
-        let mut bucket = self.buckets[ix].write().unwrap();
-        bucket.as_mut()?.unref(key)
+        self.stats
+            .priority
+            .critical_reads
+            .fetch_add(1, Ordering::Relaxed);
+        let critical = self.priority_gates[ix].enter();
+        let mut m = Measure::start("read");
+        let bucket = self.buckets[ix].read().unwrap();
+        drop(critical);
+        let result = bucket.as_ref().and_then(|bucket| {
+            bucket
+                .read_value(key)
+                .map(|(value, ref_count)| (value.into_owned(), ref_count))
+        });
+        drop(bucket);
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].read, m.as_us(), None);
+        result
     }
-}
 
-/// Look at the first 8 bytes of the input and reinterpret them as a u64
-fn read_be_u64(input: &[u8]) -> u64 {
-    assert!(input.len() >= std::mem::size_of::<u64>());
-    u64::from_be_bytes(input[0..std::mem::size_of::<u64>()].try_into().unwrap())
-}
+    /// Lock bucket `ix` for writing, after the critical reads waiting for it
+    fn write_bucket(&self, ix: usize) -> RwLockWriteGuard<'_, Option<Bucket<T>>> {
+        self.yield_to_critical(ix);
+        self.buckets[ix].write().unwrap()
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::thread_rng;
-    use rand::Rng;
-    use std::collections::HashMap;
+    /// Read lock bucket `ix` for a scan, after the critical reads waiting for it
+    fn scan_lock(&self, ix: usize) -> RwLockReadGuard<'_, Option<Bucket<T>>> {
+        self.yield_to_critical(ix);
+        self.buckets[ix].read().unwrap()
+    }
 
-    #[test]
-    fn bucket_map_test_insert() {
-        let key = Pubkey::new_unique();
-        let config = BucketMapConfig::new(1 << 1);
-        let index = BucketMap::new(config);
-        index.update(&key, |_| Some((vec![0], 0)));
-        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+    fn yield_to_critical(&self, ix: usize) {
+        if self.priority_gates[ix].yield_to_critical() {
+            self.stats.priority.yields.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    #[test]
-    fn bucket_map_test_insert2() {
-        for pass in 0..3 {
-            let key = Pubkey::new_unique();
-            let config = BucketMapConfig::new(1 << 1);
-            let index = BucketMap::new(config);
-            let ix = index.bucket_ix(&key);
-            if pass == 0 {
-                index.insert(ix, &key, (&[0], 0));
-            } else {
-                let result = index.try_insert(ix, &key, (&[0], 0));
-                assert!(result.is_err());
-                assert_eq!(index.read_value(&key), None);
-                if pass == 2 {
-                    // another call to try insert again - should still return an error
-                    let result = index.try_insert(ix, &key, (&[0], 0));
-                    assert!(result.is_err());
-                    assert_eq!(index.read_value(&key), None);
-                }
-                index.grow(ix, result.unwrap_err());
-                let result = index.try_insert(ix, &key, (&[0], 0));
-                assert!(result.is_ok());
+    /// Delete the Pubkey `key`
+    pub fn delete_key(&self, key: &Pubkey) {
+        self.delete_key_with_trace(key, None);
+    }
+
+    /// delete_key, tagged with `trace_id`, see trace.rs
+    pub fn delete_key_traced(&self, key: &Pubkey, trace_id: TraceId) {
+        self.delete_key_with_trace(key, Some(trace_id));
+    }
+
+    fn delete_key_with_trace(&self, key: &Pubkey, trace_id: Option<TraceId>) {
+        let mut m = Measure::start("delete");
+        let ix = self.bucket_ix(key);
+        if let Some(bucket) = self.write_bucket(ix).as_mut() {
+            self.mark_written(ix);
+            if bucket.delete_key(key) {
+                self.notify_traced(key, ChangeKind::Delete, trace_id);
             }
-            assert_eq!(index.read_value(&key), Some((vec![0], 0)));
         }
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].delete, m.as_us(), trace_id);
     }
 
-    #[test]
-    fn bucket_map_test_update2() {
+    /// Delete every Pubkey in `keys`, taking each bucket's lock once for all of its keys.
+    /// Returns whether each key was present, in the order of `keys`.
+    pub fn delete_keys(&self, keys: &[Pubkey]) -> Vec<bool> {
+        let mut deleted = vec![false; keys.len()];
+        for (ix, positions) in self.group_by_bucket(keys) {
+            let mut m = Measure::start("delete");
+            if let Some(bucket) = self.write_bucket(ix).as_mut() {
+                self.mark_written(ix);
+                for i in positions {
+                    deleted[i] = bucket.delete_key(&keys[i]);
+                    if deleted[i] {
+                        self.notify(&keys[i], ChangeKind::Delete);
+                    }
+                }
+            }
+            m.stop();
+            self.stats
+                .record(&self.stats.per_bucket[ix].delete, m.as_us(), None);
+        }
+        deleted
+    }
+
+    /// Delete every entry whose ref count is 0, in every bucket.
+    /// Returns the number of entries deleted.
+    pub fn purge_zero_refcount(&self) -> u64 {
+        self.purge_zero_refcount_with(|_, _| true)
+    }
+
+    /// Same as purge_zero_refcount, but only deletes the entries `confirm` returns true for.
+    /// `confirm` is called with the key and value of each entry with a ref count of 0, while
+    /// holding the lock of the entry's bucket.
+    pub fn purge_zero_refcount_with<F>(&self, mut confirm: F) -> u64
+    where
+        F: FnMut(&Pubkey, &[T]) -> bool,
+    {
+        let mut purged = 0;
+        for ix in 0..self.num_buckets() {
+            let mut m = Measure::start("delete");
+            let mut deleted = 0;
+            if let Some(bucket) = self.write_bucket(ix).as_mut() {
+                let mut keys = vec![];
+                bucket.scan(|key, value, ref_count| {
+                    if ref_count == 0 && confirm(&key, &value) {
+                        keys.push(key);
+                    }
+                });
+                if !keys.is_empty() {
+                    self.mark_written(ix);
+                }
+                for key in keys {
+                    if bucket.delete_key(&key) {
+                        self.notify(&key, ChangeKind::Delete);
+                        deleted += 1;
+                    }
+                }
+            }
+            m.stop();
+            // a bucket with nothing to purge was only scanned
+            if deleted > 0 {
+                self.stats
+                    .record(&self.stats.per_bucket[ix].delete, m.as_us(), None);
+            }
+            purged += deleted;
+        }
+        purged
+    }
+
+    /// Split the positions in `keys` by the bucket their key belongs to.
+    /// Positions stay in ascending order within a bucket, so repeated keys are applied in order.
+    fn group_by_bucket(&self, keys: &[Pubkey]) -> Vec<(usize, Vec<usize>)> {
+        let mut order = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (self.bucket_ix(key), i))
+            .collect::<Vec<_>>();
+        order.sort_unstable();
+        let mut groups: Vec<(usize, Vec<usize>)> = vec![];
+        for (ix, i) in order {
+            match groups.last_mut() {
+                Some((last_ix, positions)) if *last_ix == ix => positions.push(i),
+                _ => groups.push((ix, vec![i])),
+            }
+        }
+        groups
+    }
+
+    /// Recreate the index of bucket `ix` from its data cells, for when the index file is lost or
+    /// corrupt. Requires BucketMapConfig::data_cell_keys. Entries with empty values have no data
+    /// cell and are lost. Returns the number of entries recovered.
+    pub fn rebuild_index(&self, ix: usize) -> io::Result<u64> {
+        assert_ne!(
+            self.bucket_config.data_layout.prefix_bytes, 0,
+            "Rebuilding an index requires data_cell_keys"
+        );
+        match self.write_bucket(ix).as_mut() {
+            Some(bucket) => {
+                self.mark_written(ix);
+                bucket.rebuild_index()
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Free data allocations that are not reachable from any index entry, in every bucket.
+    /// These can be leaked by a crash while a value is being relocated.
+    /// Returns the number of bytes reclaimed.
+    pub fn gc_data(&self) -> u64 {
+        self.gc_data_cancellable(None).unwrap()
+    }
+
+    /// gc_data, giving up if `cancel` is cancelled.
+    /// A bucket that was being collected when the token was cancelled is left untouched, but
+    /// buckets that were already collected stay collected.
+    pub fn gc_data_cancellable(&self, cancel: Option<&CancelToken>) -> Result<u64, Cancelled> {
+        let total_bytes = self.index_bytes();
+        let mut processed_bytes = 0;
+        let mut reclaimed = 0;
+        for ix in 0..self.buckets.len() {
+            if let Some(bucket) = self.write_bucket(ix).as_mut() {
+                self.mark_written(ix);
+                reclaimed += bucket.gc_data(cancel)?;
+                processed_bytes += bucket.index_bytes();
+                self.report_progress(ProgressOperation::Compaction, processed_bytes, total_bytes);
+            }
+        }
+        self.report_progress(ProgressOperation::Compaction, total_bytes, total_bytes);
+        Ok(reclaimed)
+    }
+
+    /// Check the headers and a sample of the entries of every bucket, see check.rs.
+    /// Meant to run before a map restored from disk is used. Buckets are read one at a time.
+    pub fn quick_check(&self) -> CheckReport {
+        self.quick_check_samples(QUICK_CHECK_SAMPLES)
+    }
+
+    /// quick_check, following up to `samples` entries of each bucket into their data cells
+    pub fn quick_check_samples(&self, samples: u64) -> CheckReport {
+        self.quick_check_cancellable(samples, None).unwrap()
+    }
+
+    /// quick_check_samples, giving up if `cancel` is cancelled
+    pub fn quick_check_cancellable(
+        &self,
+        samples: u64,
+        cancel: Option<&CancelToken>,
+    ) -> Result<CheckReport, Cancelled> {
+        let total_bytes = self.index_bytes();
+        let mut processed_bytes = 0;
+        let mut buckets = Vec::with_capacity(self.buckets.len());
+        for ix in 0..self.buckets.len() {
+            let bucket = self.scan_lock(ix);
+            buckets.push(match bucket.as_ref() {
+                Some(bucket) => {
+                    let check = bucket.quick_check(samples, cancel)?;
+                    processed_bytes += bucket.index_bytes();
+                    self.report_progress(ProgressOperation::Verify, processed_bytes, total_bytes);
+                    check
+                }
+                None => BucketCheck::default(),
+            });
+        }
+        self.report_progress(ProgressOperation::Verify, total_bytes, total_bytes);
+        let actions = buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(ix, check)| check.action(ix))
+            .collect();
+        Ok(CheckReport { buckets, actions })
+    }
+
+    /// Estimate the resident and heap memory of every bucket.
+    /// Residency is sampled with mincore, so this touches no pages of the maps.
+    pub fn memory_usage(&self) -> MemoryReport {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                bucket
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|bucket| bucket.memory_usage())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<BucketMemoryUsage>>();
+        let drives_bytes = self
+            .bucket_config
+            .drives
+            .iter()
+            .map(|drive| std::mem::size_of::<PathBuf>() + drive.as_os_str().len())
+            .sum::<usize>();
+        let throttle_bytes = self.throttles.as_ref().map_or(0, |throttles| {
+            throttles.capacity()
+                * (std::mem::size_of::<Arc<WriteThrottle>>() + std::mem::size_of::<WriteThrottle>())
+        });
+        let map_heap_bytes = self.buckets.capacity()
+            * std::mem::size_of::<RwLock<Option<Bucket<T>>>>()
+            + self.dirty.capacity() * std::mem::size_of::<AtomicBool>()
+            + self.priority_gates.capacity() * std::mem::size_of::<PriorityGate>()
+            + drives_bytes
+            + throttle_bytes;
+        MemoryReport {
+            buckets,
+            map_heap_bytes: map_heap_bytes as u64,
+        }
+    }
+
+    /// Distribution of the stored lengths of every value, see value_report.rs.
+    /// Buckets are read one at a time.
+    pub fn value_len_histogram(&self) -> ValueLenHistogram {
+        let mut histogram = ValueLenHistogram::default();
+        self.scan_lens(|_, len, _| histogram.add(len));
+        histogram
+    }
+
+    /// The `n` entries with the longest stored values, longest first, see value_report.rs.
+    /// Buckets are read one at a time.
+    pub fn largest_entries(&self, n: usize) -> Vec<LargeEntry> {
+        let mut largest = LargestEntries::new(n);
+        self.scan_lens(|pubkey, len, ref_count| {
+            largest.add(LargeEntry {
+                len,
+                pubkey,
+                ref_count,
+            })
+        });
+        largest.into_sorted_vec()
+    }
+
+    fn scan_lens<F>(&self, mut f: F)
+    where
+        F: FnMut(Pubkey, u64, RefCount),
+    {
+        for ix in 0..self.buckets.len() {
+            if let Some(bucket) = self.scan_lock(ix).as_ref() {
+                bucket.scan_lens(&mut f);
+            }
+        }
+    }
+
+    /// Total size of the bucket indexes, which whole map operations report progress in
+    fn index_bytes(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| {
+                bucket
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|bucket| bucket.index_bytes())
+                    .unwrap_or_default()
+            })
+            .sum()
+    }
+
+    fn report_progress(
+        &self,
+        operation: ProgressOperation,
+        processed_bytes: u64,
+        total_bytes: u64,
+    ) {
+        if let Some(progress) = self.bucket_config.progress.as_ref() {
+            // a bucket can grow after the total was taken
+            progress.report(operation, processed_bytes.min(total_bytes), total_bytes);
+        }
+    }
+
+    /// Update Pubkey `key`'s value with 'value'
+    pub fn insert(&self, ix: usize, key: &Pubkey, value: (&[T], RefCount)) {
+        self.insert_and_get_previous_ref_count(ix, key, value);
+    }
+
+    /// Update Pubkey `key`'s value with 'value'.
+    /// Returns the ref count that was overwritten, or None if `key` was not present.
+    /// This lets a caller detect that it clobbered a concurrent addref.
+    pub fn insert_and_get_previous_ref_count(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+    ) -> Option<RefCount> {
+        self.insert_with_trace(ix, key, value, None)
+    }
+
+    /// insert_and_get_previous_ref_count, tagged with `trace_id`, see trace.rs
+    pub fn insert_traced(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+        trace_id: TraceId,
+    ) -> Option<RefCount> {
+        self.insert_with_trace(ix, key, value, Some(trace_id))
+    }
+
+    fn insert_with_trace(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+        trace_id: Option<TraceId>,
+    ) -> Option<RefCount> {
+        let mut m = Measure::start("insert");
+        let mut bucket = self.get_bucket(ix);
+        let previous = bucket.as_mut().unwrap().insert(key, value);
+        self.notify_traced(key, ChangeKind::of_write(previous.is_some()), trace_id);
+        drop(bucket);
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].insert, m.as_us(), trace_id);
+        previous
+    }
+
+    /// Update the values of `items`, which all have to belong to bucket `ix`, while holding the
+    /// bucket's lock once for the whole batch
+    pub fn insert_batch(&self, ix: usize, items: &[(Pubkey, Vec<T>, RefCount)]) {
+        let mut m = Measure::start("insert");
+        let mut bucket = self.get_bucket(ix);
+        let bucket = bucket.as_mut().unwrap();
+        for (key, value, ref_count) in items {
+            debug_assert_eq!(self.bucket_ix(key), ix);
+            let previous = bucket.insert(key, (value, *ref_count));
+            self.notify(key, ChangeKind::of_write(previous.is_some()));
+        }
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].insert, m.as_us(), None);
+    }
+
+    /// Get a point in time copy of the stats
+    /// Entry counts are read from every bucket in turn, so they aren't from a single point in time
+    /// while the map is being written.
+    pub fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        let mut snapshot = self.stats.snapshot();
+        snapshot.bucket_entries = (0..self.num_buckets())
+            .map(|ix| self.bucket_len(ix))
+            .collect();
+        snapshot
+    }
+
+    /// Make sure everything written before this call is on disk.
+    /// Concurrent callers are batched: whichever caller finds no flush in progress msyncs every
+    /// dirty bucket on behalf of all callers that arrived before it started, and the rest wait
+    /// for that pass instead of issuing their own.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.sync_state.lock().unwrap();
+        state.requested += 1;
+        let epoch = state.requested;
+        loop {
+            if state.completed >= epoch {
+                return match state.error {
+                    Some(kind) => Err(io::Error::new(kind, "bucket map flush failed")),
+                    None => Ok(()),
+                };
+            }
+            if !state.in_progress {
+                state.in_progress = true;
+                let target = state.requested;
+                drop(state);
+                let result = self.flush_dirty();
+                state = self.sync_state.lock().unwrap();
+                state.in_progress = false;
+                state.completed = target;
+                state.error = result.as_ref().err().map(|err| err.kind());
+                self.sync_done.notify_all();
+                return result;
+            }
+            state = self.sync_done.wait(state).unwrap();
+        }
+    }
+
+    /// Flush every bucket and close the map, leaving its files on disk so another process can
+    /// take them over. Returns the paths of the index and data files of every bucket, and of the
+    /// METADATA_FILE written to every drive, see metadata.rs.
+    /// Nothing is erased even if a flush fails, then the first error is returned. A temporary
+    /// directory the map created for lack of drives is kept as well.
+    pub fn into_persistent(mut self) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut result = Ok(());
+        let mut buckets = Vec::with_capacity(self.buckets.len());
+        for bucket in self.buckets.iter() {
+            let mut bucket = bucket.write().unwrap();
+            let bucket = match bucket.as_mut() {
+                Some(bucket) => bucket,
+                None => {
+                    buckets.push(None);
+                    continue;
+                }
+            };
+            if let Err(err) = bucket.flush() {
+                result = result.and(Err(err));
+            }
+            let kept = bucket.keep_files();
+            files.extend(kept.iter().cloned());
+            let mut kept = kept.into_iter();
+            buckets.push(Some(BucketMetadata {
+                random: bucket.random(),
+                index_file: kept.next().unwrap(),
+                data_files: kept.collect(),
+            }));
+        }
+        self.keep_files = true;
+        if let Some(temp_dir) = self.temp_dir.take() {
+            temp_dir.into_path();
+        }
+        let metadata = MapMetadata {
+            max_buckets: self.buckets.len(),
+            bucket_assignment: self.bucket_assignment,
+            bucket_hash_key: self.bucket_hash_key,
+            buckets,
+        };
+        let fast_drives = self
+            .tiering
+            .as_ref()
+            .map(|tiering| tiering.fast_drives.iter());
+        for drive in self
+            .bucket_config
+            .drives
+            .iter()
+            .chain(fast_drives.into_iter().flatten())
+        {
+            let path = drive.join(METADATA_FILE);
+            match metadata.write(&path) {
+                Ok(()) => files.push(path),
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result.map(|()| files)
+    }
+
+    fn flush_dirty(&self) -> io::Result<()> {
+        let mut m = Measure::start("sync");
+        let mut result = Ok(());
+        for (bucket, dirty) in self.buckets.iter().zip(self.dirty.iter()) {
+            if dirty.swap(false, Ordering::Relaxed) {
+                if let Some(bucket) = bucket.read().unwrap().as_ref() {
+                    if let Err(err) = bucket.flush() {
+                        // try again on the next pass
+                        dirty.store(true, Ordering::Relaxed);
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        m.stop();
+        self.stats.sync.update(m.as_us());
+        result
+    }
+
+    fn get_bucket(&self, ix: usize) -> RwLockWriteGuard<Option<Bucket<T>>> {
+        self.try_get_bucket(ix).expect("Unable to create bucket")
+    }
+
+    /// Lock bucket `ix` for writing, creating it if it doesn't exist yet
+    fn try_get_bucket(&self, ix: usize) -> io::Result<RwLockWriteGuard<'_, Option<Bucket<T>>>> {
+        let mut bucket = self.write_bucket(ix);
+        self.mark_written(ix);
+        if bucket.is_none() {
+            // each bucket gets its own stream, so the order buckets are created in doesn't matter
+            let rng = new_rng(self.seed, ix as u64);
+            let index_capacity_pow2 = self
+                .capacity_hints
+                .as_ref()
+                .map_or(self.bucket_config.index_capacity_pow2, |hints| {
+                    hints.index_capacity_pow2(ix, self.bucket_config.index_capacity_pow2)
+                });
+            *bucket = Some(Bucket::new(
+                &self.bucket_config,
+                index_capacity_pow2,
+                self.throttles
+                    .as_ref()
+                    .map(|throttles| Arc::clone(&throttles[ix])),
+                Arc::clone(&self.stats.per_bucket[ix]),
+                rng,
+                Arc::clone(&self.codec),
+            )?);
+        }
+        Ok(bucket)
+    }
+
+    /// Update Pubkey `key`'s value with 'value'
+    pub fn try_insert(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+    ) -> Result<(), BucketMapError> {
+        self.try_insert_with_trace(ix, key, value, None)
+    }
+
+    /// try_insert, tagged with `trace_id`, see trace.rs
+    pub fn try_insert_traced(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+        trace_id: TraceId,
+    ) -> Result<(), TracedError> {
+        self.try_insert_with_trace(ix, key, value, Some(trace_id))
+            .map_err(|error| TracedError { trace_id, error })
+    }
+
+    fn try_insert_with_trace(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value: (&[T], RefCount),
+        trace_id: Option<TraceId>,
+    ) -> Result<(), BucketMapError> {
+        let mut m = Measure::start("insert");
+        let mut bucket = self.try_get_bucket(ix).map_err(BucketMapError::Io)?;
+        let bucket_ref = bucket.as_mut().unwrap();
+        let present = bucket_ref.find_entry(key).is_some();
+        let result = bucket_ref.try_write(key, value.0, value.1);
+        if result.is_ok() {
+            self.notify_traced(key, ChangeKind::of_write(present), trace_id);
+        }
+        drop(bucket);
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].insert, m.as_us(), trace_id);
+        result
+    }
+
+    /// Claim an index entry for `key` and a data cell for a stored value of `value_len` elements
+    /// in bucket `ix`, so that committing the value later doesn't have to grow anything.
+    /// The key stays invisible to readers until the reservation is committed.
+    /// Fails fast with DataNoSpace or IndexNoSpace instead of growing; grow with the error and
+    /// try again. `value_len` is the length of the value as stored, after the map's codec and
+    /// encryption. If `key` already has an entry, nothing is claimed and commit acts as insert.
+    pub fn reserve(
+        &self,
+        ix: usize,
+        key: &Pubkey,
+        value_len: u64,
+    ) -> Result<Reservation, BucketMapError> {
+        let mut bucket = self.try_get_bucket(ix).map_err(BucketMapError::Io)?;
+        bucket.as_mut().unwrap().try_reserve(key, value_len)?;
+        Ok(Reservation { ix, key: *key })
+    }
+
+    /// Write the value of a reserved key. Grows the bucket if the value doesn't fit what was
+    /// reserved.
+    pub fn commit(&self, reservation: Reservation, value: (&[T], RefCount)) {
+        self.insert(reservation.ix, &reservation.key, value);
+    }
+
+    /// Give back the space of a reservation that won't be committed
+    pub fn release(&self, reservation: Reservation) {
+        let mut bucket = self.get_bucket(reservation.ix);
+        bucket.as_mut().unwrap().release(&reservation.key);
+    }
+
+    /// if err is a grow error, then grow the appropriate piece
+    pub fn grow(&self, ix: usize, err: BucketMapError) {
+        self.try_grow(ix, err).expect("Unable to grow bucket");
+    }
+
+    /// Same as grow, but returns IO errors instead of panicking.
+    /// The bucket is unchanged on error, so the grow can be retried.
+    pub fn try_grow(&self, ix: usize, err: BucketMapError) -> Result<(), BucketMapError> {
+        let mut bucket = self.try_get_bucket(ix).map_err(BucketMapError::Io)?;
+        bucket
+            .as_mut()
+            .unwrap()
+            .grow(err)
+            .map_err(BucketMapError::Io)
+    }
+
+    /// Same as try_grow, but on one of the map's grow workers, so several buckets can grow at
+    /// once. The returned handle can be polled or waited on for the result.
+    pub fn grow_async(map: &Arc<Self>, ix: usize, err: BucketMapError) -> GrowHandle
+    where
+        T: Send + Sync + 'static,
+    {
+        let worker_map = map.clone();
+        map.grow_pool
+            .spawn(ix, move || worker_map.try_grow(ix, err))
+    }
+
+    /// Grow the index of bucket `ix` until it has at least `cells` cells.
+    /// The data storages are left alone.
+    pub fn reserve_index(&self, ix: usize, cells: u64) {
+        let mut bucket = self.get_bucket(ix);
+        bucket
+            .as_mut()
+            .unwrap()
+            .reserve_index(cells)
+            .expect("Unable to grow bucket");
+    }
+
+    /// Grow the data storage of bucket `ix` that holds values of `num_slots` elements until it
+    /// is at least `bytes` large. The index is left alone.
+    pub fn reserve_data(&self, ix: usize, num_slots: u64, bytes: u64) {
+        let mut bucket = self.get_bucket(ix);
+        bucket
+            .as_mut()
+            .unwrap()
+            .reserve_data(num_slots, bytes)
+            .expect("Unable to grow bucket");
+    }
+
+    /// Number of cells bucket `ix` searches for a key or a free cell
+    pub fn max_search(&self, ix: usize) -> MaxSearch {
+        self.buckets[ix]
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(self.bucket_config.max_search, |bucket| bucket.max_search())
+    }
+
+    /// Let bucket `ix` search up to `max_search` cells, e.g. because its keys cluster and it
+    /// keeps growing its index while other buckets are fine with the configured max_search.
+    /// The bucket is created if it doesn't exist yet. max_search is never lowered below the
+    /// bucket's current one, see Bucket::set_max_search. Returns the bucket's max_search.
+    pub fn set_max_search(&self, ix: usize, max_search: MaxSearch) -> MaxSearch {
+        let mut bucket = self.get_bucket(ix);
+        bucket.as_mut().unwrap().set_max_search(max_search)
+    }
+
+    /// Update Pubkey `key`'s value with function `updatefn`
+    pub fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        self.update_with_trace(key, updatefn, None);
+    }
+
+    /// update, tagged with `trace_id`, see trace.rs
+    pub fn update_traced<F>(&self, key: &Pubkey, updatefn: F, trace_id: TraceId)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        self.update_with_trace(key, updatefn, Some(trace_id));
+    }
+
+    fn update_with_trace<F>(&self, key: &Pubkey, updatefn: F, trace_id: Option<TraceId>)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        let mut m = Measure::start("update");
+        let ix = self.bucket_ix(key);
+        let mut bucket = self.get_bucket(ix);
+        if let Some(kind) = bucket.as_mut().unwrap().update(key, updatefn) {
+            self.notify_traced(key, kind, trace_id);
+        }
+        drop(bucket);
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].update, m.as_us(), trace_id);
+    }
+
+    /// Exchange the values and ref counts of `a` and `b`. If only one of them is present, it
+    /// moves to the other key.
+    /// Both buckets stay locked for the whole swap, so no reader sees both keys or neither key
+    /// populated when one of them was. Buckets are locked in ascending order, so concurrent swaps
+    /// can't deadlock.
+    pub fn swap(&self, a: &Pubkey, b: &Pubkey) {
+        if a == b {
+            return;
+        }
+        let mut m = Measure::start("update");
+        let (ix_a, ix_b) = (self.bucket_ix(a), self.bucket_ix(b));
+        let low_ix = ix_a.min(ix_b);
+        let mut low = self.get_bucket(low_ix);
+        let mut high = if ix_a == ix_b {
+            None
+        } else {
+            Some(self.get_bucket(ix_a.max(ix_b)))
+        };
+        let value_a = Self::locked_bucket(ix_a, low_ix, &mut low, &mut high)
+            .read_value(a)
+            .map(|(value, ref_count)| (value.into_owned(), ref_count));
+        let value_b = Self::locked_bucket(ix_b, low_ix, &mut low, &mut high)
+            .read_value(b)
+            .map(|(value, ref_count)| (value.into_owned(), ref_count));
+        for (key, ix, value) in [(a, ix_a, value_b), (b, ix_b, value_a)] {
+            let bucket = Self::locked_bucket(ix, low_ix, &mut low, &mut high);
+            match value {
+                Some((value, ref_count)) => {
+                    let previous = bucket.insert(key, (&value, ref_count));
+                    self.notify(key, ChangeKind::of_write(previous.is_some()));
+                }
+                None => {
+                    if bucket.delete_key(key) {
+                        self.notify(key, ChangeKind::Delete);
+                    }
+                }
+            }
+        }
+        drop(high);
+        drop(low);
+        m.stop();
+        // each bucket counts the swap as one of its updates
+        self.stats
+            .record(&self.stats.per_bucket[ix_a].update, m.as_us(), None);
+        if ix_b != ix_a {
+            self.stats
+                .record(&self.stats.per_bucket[ix_b].update, m.as_us(), None);
+        }
+    }
+
+    /// The bucket `ix` of the two locked by swap
+    fn locked_bucket<'a>(
+        ix: usize,
+        low_ix: usize,
+        low: &'a mut RwLockWriteGuard<Option<Bucket<T>>>,
+        high: &'a mut Option<RwLockWriteGuard<Option<Bucket<T>>>>,
+    ) -> &'a mut Bucket<T> {
+        if ix == low_ix {
+            low.as_mut().unwrap()
+        } else {
+            high.as_mut().unwrap().as_mut().unwrap()
+        }
+    }
+
+    /// Get the bucket index for Pubkey `key`
+    pub fn bucket_ix(&self, key: &Pubkey) -> usize {
+        let location = match self.bucket_hash_key.as_ref() {
+            Some(hash_key) => hash_key.hash(key),
+            None => read_be_u64(key.as_ref()),
+        };
+        match self.bucket_assignment {
+            BucketAssignment::Prefix => {
+                if self.max_buckets_pow2 > 0 {
+                    (location >> (u64::BITS - self.max_buckets_pow2 as u32)) as usize
+                } else {
+                    0
+                }
+            }
+            BucketAssignment::JumpConsistentHash => {
+                jump_consistent_hash(location, self.num_buckets())
+            }
+        }
+    }
+
+    /// Buckets hold contiguous Pubkey ranges only with Prefix assignment of unhashed Pubkeys
+    fn buckets_are_ranges(&self) -> bool {
+        self.bucket_assignment == BucketAssignment::Prefix && self.bucket_hash_key.is_none()
+    }
+
+    /// Get the buckets that can hold Pubkeys in `range`.
+    /// If buckets don't hold contiguous Pubkey ranges, that is every bucket.
+    pub fn bucket_range_for_pubkey_range<R>(&self, range: &R) -> Range<usize>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        if !self.buckets_are_ranges() {
+            return 0..self.num_buckets();
+        }
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => self.bucket_ix(key),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.bucket_ix(key) + 1,
+            Bound::Excluded(key) => {
+                let ix = self.bucket_ix(key);
+                if *key == self.pubkey_bounds_of_bucket(ix).0 {
+                    // nothing in bucket ix is below its first Pubkey
+                    ix
+                } else {
+                    ix + 1
+                }
+            }
+            Bound::Unbounded => self.num_buckets(),
+        };
+        start..std::cmp::max(start, end)
+    }
+
+    /// Get the first and last Pubkey, inclusive, that bucket `ix` can hold.
+    /// If buckets don't hold contiguous Pubkey ranges, any bucket can hold any Pubkey.
+    pub fn pubkey_bounds_of_bucket(&self, ix: usize) -> (Pubkey, Pubkey) {
+        assert!(ix < self.num_buckets());
+        let mut first = [0u8; 32];
+        let mut last = [0xffu8; 32];
+        if self.buckets_are_ranges() && self.max_buckets_pow2 > 0 {
+            let shift = u64::BITS - self.max_buckets_pow2 as u32;
+            let prefix = (ix as u64) << shift;
+            let prefix_len = std::mem::size_of::<u64>();
+            first[..prefix_len].copy_from_slice(&prefix.to_be_bytes());
+            last[..prefix_len].copy_from_slice(&(prefix | ((1 << shift) - 1)).to_be_bytes());
+        }
+        (Pubkey::new(&first), Pubkey::new(&last))
+    }
+
+    /// Returns true if a writer should hold off on writing `bytes` to bucket `ix` because the
+    /// bucket is over its write budget or is busy growing or compacting.
+    /// Always false if no throttle is configured.
+    pub fn would_block(&self, ix: usize, bytes: u64) -> bool {
+        self.throttles
+            .as_ref()
+            .map(|throttles| throttles[ix].would_block(bytes))
+            .unwrap_or_default()
+    }
+
+    /// The latest replica of bucket `ix`, if it is replicated and has been refreshed
+    fn replica(&self, ix: usize) -> Option<Arc<Bucket<T>>> {
+        self.replicas.get(&ix).and_then(|replica| replica.current())
+    }
+
+    /// Copy every replicated bucket into its replica. Until a bucket's replica has been
+    /// refreshed for the first time, scans of the bucket read the bucket itself.
+    /// Each bucket is read locked while it is copied.
+    pub fn refresh_replicas(&self) -> io::Result<()> {
+        for (ix, replica) in self.replicas.iter() {
+            let bucket = self.scan_lock(*ix);
+            if let Some(bucket) = bucket.as_ref() {
+                replica.refresh(bucket)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call refresh_replicas every ReplicaConfig::refresh_interval until `exit` is set.
+    /// Returns None if no replicas are configured.
+    pub fn spawn_replica_refresher(map: Arc<Self>, exit: Arc<AtomicBool>) -> Option<JoinHandle<()>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let refresh_interval = map.replica_refresh_interval?;
+        Some(
+            Builder::new()
+                .name("solana-bucket-map-replica".to_string())
+                .spawn(move || {
+                    while !exit.load(Ordering::Relaxed) {
+                        if let Err(err) = map.refresh_replicas() {
+                            // scans keep reading the previous copies
+                            log::error!("bucket map replica refresh failed: {}", err);
+                        }
+                        sleep(refresh_interval);
+                    }
+                })
+                .unwrap(),
+        )
+    }
+
+    /// True if bucket `ix` is on the fast tier, see rebalance_tiers
+    pub fn bucket_on_fast_tier(&self, ix: usize) -> bool {
+        self.tiering
+            .as_ref()
+            .map(|tiering| tiering.is_fast(ix))
+            .unwrap_or_default()
+    }
+
+    /// Move the buckets that were read the most since the previous call to the fast tier, and
+    /// the buckets that no longer are back to the slow tier, see tiering.rs.
+    /// Each bucket is write locked while its files are copied.
+    /// Returns the number of buckets moved, 0 if no tiering is configured. A bucket that fails
+    /// to move stays on its tier and keeps the reads it was planned with, so the next call moves
+    /// it if it is still as hot or as cold. The other buckets are still moved, then the first
+    /// error is returned.
+    pub fn rebalance_tiers(&self) -> io::Result<usize> {
+        let tiering = match self.tiering.as_ref() {
+            Some(tiering) => tiering,
+            None => return Ok(0),
+        };
+        let reads = self
+            .stats
+            .per_bucket
+            .iter()
+            .map(|stats| stats.read.count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let mut moved = 0;
+        let mut result = Ok(());
+        for (ix, to_fast) in tiering.plan(&reads) {
+            let drives = if to_fast {
+                &tiering.fast_drives
+            } else {
+                &self.bucket_config.drives
+            };
+            // a bucket that doesn't exist yet has nothing to move and is created on the slow tier
+            if let Some(bucket) = self.write_bucket(ix).as_mut() {
+                match bucket.move_to(drives) {
+                    Ok(()) => {
+                        tiering.set_fast(ix, to_fast);
+                        moved += 1;
+                    }
+                    Err(err) => {
+                        tiering.move_failed(ix);
+                        result = result.and(Err(err));
+                    }
+                }
+            }
+        }
+        result.map(|()| moved)
+    }
+
+    /// Call rebalance_tiers every TieringConfig::migrate_interval until `exit` is set.
+    /// Returns None if no tiering is configured.
+    pub fn spawn_tier_migrator(map: Arc<Self>, exit: Arc<AtomicBool>) -> Option<JoinHandle<()>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let migrate_interval = map.tiering.as_ref()?.migrate_interval;
+        Some(
+            Builder::new()
+                .name("solana-bucket-map-tiering".to_string())
+                .spawn(move || {
+                    while !exit.load(Ordering::Relaxed) {
+                        if let Err(err) = map.rebalance_tiers() {
+                            // buckets stay readable on whichever drive they are
+                            log::error!("bucket map tier migration failed: {}", err);
+                        }
+                        sleep(migrate_interval);
+                    }
+                })
+                .unwrap(),
+        )
+    }
+
+    /// What addref and unref do when a ref count would go past u64::MAX or below 0
+    pub fn ref_count_mode(&self) -> RefCountMode {
+        self.bucket_config.ref_count_mode
+    }
+
+    /// The time source of this map
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Get the key Pubkeys are hashed with before a bucket is selected, if any
+    pub fn bucket_hash_key(&self) -> Option<BucketHashKey> {
+        self.bucket_hash_key
+    }
+
+    /// Increment the refcount for Pubkey `key`.
+    /// Panics on overflow in RefCountMode::Strict, see try_addref.
+    pub fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        self.try_addref(key).expect("Unable to addref")
+    }
+
+    /// Decrement the refcount for Pubkey `key`.
+    /// Panics on underflow in RefCountMode::Strict, see try_unref.
+    pub fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        self.try_unref(key).expect("Unable to unref")
+    }
+
+    /// Same as addref, but returns RefCountOverflow instead of panicking.
+    /// The ref count is unchanged on error.
+    pub fn try_addref(&self, key: &Pubkey) -> Result<Option<RefCount>, BucketMapError> {
+        self.try_update_ref_count(key, |bucket, key| bucket.addref(key))
+    }
+
+    /// Same as unref, but returns RefCountUnderflow instead of panicking.
+    /// The ref count is unchanged on error.
+    pub fn try_unref(&self, key: &Pubkey) -> Result<Option<RefCount>, BucketMapError> {
+        self.try_update_ref_count(key, |bucket, key| bucket.unref(key))
+    }
+
+    fn try_update_ref_count<F>(
+        &self,
+        key: &Pubkey,
+        f: F,
+    ) -> Result<Option<RefCount>, BucketMapError>
+    where
+        F: Fn(&mut Bucket<T>, &Pubkey) -> Result<Option<RefCount>, BucketMapError>,
+    {
+        let mut m = Measure::start("update");
+        let ix = self.bucket_ix(key);
+        let mut bucket = self.write_bucket(ix);
+        self.mark_written(ix);
+        let ref_count = match bucket.as_mut() {
+            Some(bucket) => f(bucket, key),
+            None => Ok(None),
+        };
+        if matches!(ref_count, Ok(Some(_))) {
+            self.notify(key, ChangeKind::Update);
+        }
+        m.stop();
+        self.stats
+            .record(&self.stats.per_bucket[ix].update, m.as_us(), None);
+        ref_count
+    }
+
+    /// Increment the refcount of every Pubkey in `keys`, taking each bucket's lock once for all
+    /// of its keys. A key that appears n times is incremented n times.
+    /// Returns the new refcounts in the order of `keys`, None for keys that are not present.
+    /// Panics on overflow in RefCountMode::Strict, once every other key was incremented, see
+    /// try_addref_batch.
+    pub fn addref_batch(&self, keys: &[Pubkey]) -> Vec<Option<RefCount>> {
+        self.try_addref_batch(keys)
+            .into_iter()
+            .map(|ref_count| ref_count.expect("Unable to addref"))
+            .collect()
+    }
+
+    /// Decrement the refcount of every Pubkey in `keys`, see addref_batch
+    pub fn unref_batch(&self, keys: &[Pubkey]) -> Vec<Option<RefCount>> {
+        self.try_unref_batch(keys)
+            .into_iter()
+            .map(|ref_count| ref_count.expect("Unable to unref"))
+            .collect()
+    }
+
+    /// Same as addref_batch, but returns a result per key instead of panicking.
+    /// The ref count of a key that fails is unchanged, the other keys are still incremented.
+    pub fn try_addref_batch(
+        &self,
+        keys: &[Pubkey],
+    ) -> Vec<Result<Option<RefCount>, BucketMapError>> {
+        self.update_ref_counts(keys, |bucket, key| bucket.addref(key))
+    }
+
+    /// Same as unref_batch, but returns a result per key instead of panicking, see
+    /// try_addref_batch
+    pub fn try_unref_batch(
+        &self,
+        keys: &[Pubkey],
+    ) -> Vec<Result<Option<RefCount>, BucketMapError>> {
+        self.update_ref_counts(keys, |bucket, key| bucket.unref(key))
+    }
+
+    fn update_ref_counts<F>(
+        &self,
+        keys: &[Pubkey],
+        f: F,
+    ) -> Vec<Result<Option<RefCount>, BucketMapError>>
+    where
+        F: Fn(&mut Bucket<T>, &Pubkey) -> Result<Option<RefCount>, BucketMapError>,
+    {
+        let mut ref_counts = Vec::with_capacity(keys.len());
+        ref_counts.resize_with(keys.len(), || Ok(None));
+        for (ix, positions) in self.group_by_bucket(keys) {
+            let mut m = Measure::start("update");
+            if let Some(bucket) = self.write_bucket(ix).as_mut() {
+                self.mark_written(ix);
+                for i in positions {
+                    ref_counts[i] = f(bucket, &keys[i]);
+                    if matches!(ref_counts[i], Ok(Some(_))) {
+                        self.notify(&keys[i], ChangeKind::Update);
+                    }
+                }
+            }
+            m.stop();
+            self.stats
+                .record(&self.stats.per_bucket[ix].update, m.as_us(), None);
+        }
+        ref_counts
+    }
+}
+
+/// Stream `stream` of `seed`, or a random stream if there is no seed
+fn new_rng(seed: Option<u64>, stream: u64) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(stream)),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Look at the first 8 bytes of the input and reinterpret them as a u64.
+/// Shorter input, which no caller passes, is padded with zeros instead of panicking.
+pub(crate) fn read_be_u64(input: &[u8]) -> u64 {
+    debug_assert!(input.len() >= size_of::<u64>());
+    let mut bytes = [0; size_of::<u64>()];
+    let len = input.len().min(bytes.len());
+    bytes[..len].copy_from_slice(&input[..len]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Lamping and Veach's jump consistent hash: maps `key` to a bucket in `0..num_buckets` such that
+/// growing num_buckets by one only moves keys into the new bucket
+fn jump_consistent_hash(mut key: u64, num_buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_storage::UID_UNLOCKED;
+    use crate::check::{CheckProblem, RepairAction};
+    use crate::clock::ManualClock;
+    use crate::prefetch_iter::IterStats;
+    use rand::thread_rng;
+    use rand::Rng;
+    use std::borrow::Cow;
+    use std::collections::{HashMap, HashSet};
+    use std::ops::RangeInclusive;
+
+    #[test]
+    fn bucket_map_test_insert() {
+        let key = Pubkey::new_unique();
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        index.update(&key, |_| Some((vec![0], 0)));
+        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+    }
+
+    #[test]
+    fn bucket_map_test_insert2() {
+        for pass in 0..3 {
+            let key = Pubkey::new_unique();
+            let config = BucketMapConfig::new(1 << 1);
+            let index = BucketMap::new(config);
+            let ix = index.bucket_ix(&key);
+            if pass == 0 {
+                index.insert(ix, &key, (&[0], 0));
+            } else {
+                let result = index.try_insert(ix, &key, (&[0], 0));
+                assert!(result.is_err());
+                assert_eq!(index.read_value(&key), None);
+                if pass == 2 {
+                    // another call to try insert again - should still return an error
+                    let result = index.try_insert(ix, &key, (&[0], 0));
+                    assert!(result.is_err());
+                    assert_eq!(index.read_value(&key), None);
+                }
+                index.grow(ix, result.unwrap_err());
+                let result = index.try_insert(ix, &key, (&[0], 0));
+                assert!(result.is_ok());
+            }
+            assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_update2() {
+        let key = Pubkey::new_unique();
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        index.insert(index.bucket_ix(&key), &key, (&[0], 0));
+        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+        index.insert(index.bucket_ix(&key), &key, (&[1], 0));
+        assert_eq!(index.read_value(&key), Some((vec![1], 0)));
+    }
+
+    #[test]
+    fn bucket_map_test_update() {
+        let key = Pubkey::new_unique();
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        index.update(&key, |_| Some((vec![0], 0)));
+        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+        index.update(&key, |_| Some((vec![1], 0)));
+        assert_eq!(index.read_value(&key), Some((vec![1], 0)));
+    }
+
+    #[test]
+    fn bucket_map_test_update_to_0_len() {
+        solana_logger::setup();
+        let key = Pubkey::new_unique();
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        index.update(&key, |_| Some((vec![0], 1)));
+        assert_eq!(index.read_value(&key), Some((vec![0], 1)));
+        // sets len to 0, updates in place
+        index.update(&key, |_| Some((vec![], 1)));
+        assert_eq!(index.read_value(&key), Some((vec![], 1)));
+        // sets len to 0, doesn't update in place - finds a new place, which causes us to no longer have an allocation in data
+        index.update(&key, |_| Some((vec![], 2)));
+        assert_eq!(index.read_value(&key), Some((vec![], 2)));
+        // sets len to 1, doesn't update in place - finds a new place
+        index.update(&key, |_| Some((vec![1], 2)));
+        assert_eq!(index.read_value(&key), Some((vec![1], 2)));
+    }
+
+    #[test]
+    fn bucket_map_test_delete() {
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        for i in 0..10 {
+            let key = Pubkey::new_unique();
+            assert_eq!(index.read_value(&key), None);
+
+            index.update(&key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+
+            index.delete_key(&key);
+            assert_eq!(index.read_value(&key), None);
+
+            index.update(&key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+            index.delete_key(&key);
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_delete_2() {
+        let config = BucketMapConfig::new(1 << 2);
+        let index = BucketMap::new(config);
+        for i in 0..100 {
+            let key = Pubkey::new_unique();
+            assert_eq!(index.read_value(&key), None);
+
+            index.update(&key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+
+            index.delete_key(&key);
+            assert_eq!(index.read_value(&key), None);
+
+            index.update(&key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+            index.delete_key(&key);
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_n_drives() {
+        let config = BucketMapConfig::new(1 << 2);
+        let index = BucketMap::new(config);
+        for i in 0..100 {
+            let key = Pubkey::new_unique();
+            index.update(&key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+        }
+    }
+    #[test]
+    fn bucket_map_test_grow_read() {
+        let config = BucketMapConfig::new(1 << 2);
+        let index = BucketMap::new(config);
+        let keys: Vec<Pubkey> = (0..100).into_iter().map(|_| Pubkey::new_unique()).collect();
+        for k in 0..keys.len() {
+            let key = &keys[k];
+            let i = read_be_u64(key.as_ref());
+            index.update(key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(key), Some((vec![i], 0)));
+            for (ix, key) in keys.iter().enumerate() {
+                let i = read_be_u64(key.as_ref());
+                //debug!("READ: {:?} {}", key, i);
+                let expected = if ix <= k { Some((vec![i], 0)) } else { None };
+                assert_eq!(index.read_value(key), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_n_delete() {
+        let config = BucketMapConfig::new(1 << 2);
+        let index = BucketMap::new(config);
+        let keys: Vec<Pubkey> = (0..20).into_iter().map(|_| Pubkey::new_unique()).collect();
+        for key in keys.iter() {
+            let i = read_be_u64(key.as_ref());
+            index.update(key, |_| Some((vec![i], 0)));
+            assert_eq!(index.read_value(key), Some((vec![i], 0)));
+        }
+        for key in keys.iter() {
+            let i = read_be_u64(key.as_ref());
+            //debug!("READ: {:?} {}", key, i);
+            assert_eq!(index.read_value(key), Some((vec![i], 0)));
+        }
+        for k in 0..keys.len() {
+            let key = &keys[k];
+            index.delete_key(key);
+            assert_eq!(index.read_value(key), None);
+            for key in keys.iter().skip(k + 1) {
+                let i = read_be_u64(key.as_ref());
+                assert_eq!(index.read_value(key), Some((vec![i], 0)));
+            }
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_prefetch_iter() {
+        let config = BucketMapConfig::new(1 << 2);
+        let index = BucketMap::new(config);
+        let mut keys = (0..100)
+            .map(|i| {
+                let key = Pubkey::new_unique();
+                index.update(&key, |_| Some((vec![i], i)));
+                key
+            })
+            .collect::<Vec<_>>();
+        for chunk_size in [1, 7, 1024].iter() {
+            let mut found = vec![];
+            for chunk in index.prefetch_iter(*chunk_size) {
+                assert!(!chunk.is_empty());
+                assert!(chunk.len() <= *chunk_size);
+                for item in chunk {
+                    assert_eq!(item.slot_list, vec![item.ref_count]);
+                    found.push(item.pubkey);
+                }
+            }
+            found.sort();
+            keys.sort();
+            assert_eq!(found, keys);
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_ref_count_mode() {
+        let key = Pubkey::new_unique();
+        let missing = Pubkey::new_unique();
+        let new_index = |mode| {
+            let index = BucketMap::<u64>::new(BucketMapConfig {
+                ref_count_mode: mode,
+                ..BucketMapConfig::new(1 << 2)
+            });
+            index.update(&key, |_| Some((vec![1], 0)));
+            index
+        };
+
+        let index = new_index(RefCountMode::Wrapping);
+        assert_eq!(index.unref(&key), Some(u64::MAX));
+        assert_eq!(index.addref(&key), Some(0));
+
+        let index = new_index(RefCountMode::Saturating);
+        assert_eq!(index.unref(&key), Some(0));
+        index.update(&key, |_| Some((vec![1], u64::MAX)));
+        assert_eq!(index.addref(&key), Some(u64::MAX));
+
+        let index = new_index(RefCountMode::Strict);
+        assert!(matches!(
+            index.try_unref(&key),
+            Err(BucketMapError::RefCountUnderflow)
+        ));
+        assert_eq!(index.read_value(&key), Some((vec![1], 0)));
+        assert_eq!(index.try_addref(&key).unwrap(), Some(1));
+        assert_eq!(index.try_unref(&key).unwrap(), Some(0));
+        index.update(&key, |_| Some((vec![1], u64::MAX)));
+        assert!(matches!(
+            index.try_addref(&key),
+            Err(BucketMapError::RefCountOverflow)
+        ));
+        assert_eq!(index.read_value(&key), Some((vec![1], u64::MAX)));
+        // a missing key isn't an error in any mode
+        assert_eq!(index.try_unref(&missing).unwrap(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to unref")]
+    fn bucket_map_test_ref_count_mode_strict_panics() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            ref_count_mode: RefCountMode::Strict,
+            ..BucketMapConfig::new(1 << 2)
+        });
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![], 0)));
+        index.unref(&key);
+    }
+
+    #[test]
+    fn bucket_map_test_max_value_len() {
+        let drive = TempDir::new().unwrap();
+        let marker = drive.path().join("marker");
+        fs::write(&marker, b"").unwrap();
+        let config = |max_value_len| BucketMapConfig {
+            drives: Some(vec![drive.path().to_path_buf()]),
+            max_value_len: Some(max_value_len),
+            ..BucketMapConfig::new(1)
+        };
+        let err = BucketMap::<u64>::try_new(config(u64::MAX)).unwrap_err();
+        // cells of 2^k u64s after an 8 byte header, 2^DEFAULT_CAPACITY_POW2 of them
+        let max_supported_len = 1 << (62 - 3 - DEFAULT_CAPACITY_POW2);
+        assert_eq!(
+            err,
+            ConfigError::ValueTooLarge {
+                max_value_len: u64::MAX,
+                element_bytes: 8,
+                max_supported_len,
+                max_supported_storage_bytes: (8 + 8 * max_supported_len) << DEFAULT_CAPACITY_POW2,
+            }
+        );
+        assert!(BucketMap::<u64>::try_new(config(max_supported_len * 2)).is_err());
+        // nothing was erased
+        assert!(marker.exists());
+
+        let index = BucketMap::<u64>::try_new(config(max_supported_len)).unwrap();
+        assert!(!marker.exists());
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![1; 1000], 1)));
+        assert_eq!(index.read_value(&key), Some((vec![1; 1000], 1)));
+
+        // a larger initial data storage leaves room for shorter values
+        let err = BucketMap::<u64>::try_new(BucketMapConfig {
+            data_capacity_bytes: Some(1 << 63),
+            ..config(max_supported_len)
+        })
+        .unwrap_err();
+        assert!(
+            matches!(err, ConfigError::ValueTooLarge { max_supported_len: len, .. }
+            if len < max_supported_len)
+        );
+    }
+
+    #[test]
+    fn bucket_map_test_config_errors() {
+        let drive = TempDir::new().unwrap();
+        let marker = drive.path().join("marker");
+        fs::write(&marker, b"").unwrap();
+        let config = || BucketMapConfig {
+            drives: Some(vec![drive.path().to_path_buf()]),
+            ..BucketMapConfig::new(4)
+        };
+        let try_new = |config| BucketMap::<u64>::try_new(config).unwrap_err();
+        assert_eq!(
+            try_new(BucketMapConfig {
+                max_buckets: 0,
+                ..config()
+            }),
+            ConfigError::NoBuckets
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                index_capacity_pow2: Some(64),
+                ..config()
+            }),
+            ConfigError::IndexCapacityTooLarge(64)
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                capacity_hints: Some(CapacityHints {
+                    bucket_entries: vec![1, 2],
+                }),
+                ..config()
+            }),
+            ConfigError::CapacityHintsMismatch {
+                hints: 2,
+                max_buckets: 4
+            }
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                grow_pow2: Some(0),
+                ..config()
+            }),
+            ConfigError::NoGrowth
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                replicas: Some(ReplicaConfig {
+                    buckets: vec![1, 4],
+                    refresh_interval: Duration::from_secs(1),
+                }),
+                ..config()
+            }),
+            ConfigError::ReplicaOutOfRange {
+                ix: 4,
+                max_buckets: 4
+            }
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                tiering: Some(TieringConfig {
+                    fast_drives: vec![],
+                    max_fast_buckets: 1,
+                    migrate_interval: Duration::from_secs(1),
+                }),
+                ..config()
+            }),
+            ConfigError::NoFastDrives
+        );
+        let err = try_new(BucketMapConfig {
+            grow_workers: Some(0),
+            ..config()
+        });
+        assert_eq!(err, ConfigError::NoGrowWorkers);
+        assert_eq!(err.to_string(), "grow_workers must be non-zero");
+        assert_eq!(
+            try_new(BucketMapConfig {
+                max_search: Some(0),
+                ..config()
+            }),
+            ConfigError::NoSearch
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                element_align: Some(3),
+                ..config()
+            }),
+            ConfigError::InvalidElementAlign {
+                align: 3,
+                min_align: 8,
+                max_align: MAX_ELEMENT_ALIGN
+            }
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                element_align: Some(4),
+                ..config()
+            }),
+            ConfigError::InvalidElementAlign {
+                align: 4,
+                min_align: 8,
+                max_align: MAX_ELEMENT_ALIGN
+            }
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                max_buckets: 3,
+                ..config()
+            }),
+            ConfigError::MaxBucketsNotPowerOfTwo(3)
+        );
+        let err: Box<dyn std::error::Error> = Box::new(err);
+        assert!(err.source().is_none());
+        // nothing was erased
+        assert!(marker.exists());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid BucketMapConfig: ValueTooLarge")]
+    fn bucket_map_test_max_value_len_panics() {
+        BucketMap::<u64>::new(BucketMapConfig {
+            max_value_len: Some(1 << 62),
+            ..BucketMapConfig::new(1)
+        });
+    }
+
+    #[test]
+    fn bucket_map_test_scan_bucket() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        assert_eq!(index.scan_bucket(0).iter().count(), 0);
+        for i in 0..100 {
+            let key = Pubkey::new_unique();
+            index.update(&key, |_| Some((vec![i; i as usize % 4], i)));
+        }
+        for ix in 0..index.num_buckets() {
+            let scan = index.scan_bucket(ix);
+            assert!(scan
+                .iter()
+                .all(|item| matches!(item.slot_list, Cow::Borrowed(_))));
+            let mut expected = index.items_in_range(ix, &None::<&RangeInclusive<Pubkey>>);
+            expected.sort_unstable_by_key(|item| item.pubkey);
+            let mut items = scan
+                .iter()
+                .map(|item| item.into_owned())
+                .collect::<Vec<_>>();
+            items.sort_unstable_by_key(|item| item.pubkey);
+            assert_eq!(items.len(), expected.len());
+            for (item, expected) in items.iter().zip(expected.iter()) {
+                assert_eq!(
+                    (item.pubkey, &item.slot_list, item.ref_count),
+                    (expected.pubkey, &expected.slot_list, expected.ref_count)
+                );
+            }
+        }
+        let total = (0..index.num_buckets())
+            .map(|ix| {
+                index
+                    .scan_bucket(ix)
+                    .iter()
+                    .map(|item| item.slot_list.iter().sum::<u64>())
+                    .sum::<u64>()
+            })
+            .sum::<u64>();
+        assert_eq!(total, (0..100).map(|i| i * (i % 4)).sum::<u64>());
+    }
+
+    #[test]
+    fn bucket_map_test_set_max_search() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            max_search: Some(2),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        assert_eq!(index.max_search(1), 2);
+        assert_eq!(index.set_max_search(1, 8), 8);
+        // never lowered
+        assert_eq!(index.set_max_search(1, 4), 8);
+        assert_eq!(index.max_search(1), 8);
+        assert_eq!(index.max_search(0), 2);
+
+        let keys = (0..200).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64; i % 3], 1)));
+        }
+        // grown storages keep it
+        assert_eq!(index.max_search(1), 8);
+        assert_eq!(index.max_search(0), 2);
+        assert_eq!(index.set_max_search(0, 16), 16);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(index.read_value(key), Some((vec![i as u64; i % 3], 1)));
+        }
+        for key in keys.iter().step_by(2) {
+            index.update(key, |_| Some((vec![0; 4], 2)));
+        }
+        index.reserve_index(1, 1 << 10);
+        assert_eq!(index.max_search(1), 8);
+        for (i, key) in keys.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                (vec![0; 4], 2)
+            } else {
+                (vec![i as u64; i % 3], 1)
+            };
+            assert_eq!(index.read_value(key), Some(expected));
+        }
+        assert!(index.quick_check().is_ok());
+    }
+
+    #[test]
+    fn bucket_map_test_read_value_critical() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 1));
+        let key = Pubkey::new_unique();
+        let ix = index.bucket_ix(&key);
+        index.update(&key, |_| Some((vec![1], 1)));
+        assert_eq!(index.read_value_critical(&key), Some((vec![1], 1)));
+        assert_eq!(index.read_value_critical(&Pubkey::new_unique()), None);
+        let priority = index.stats_snapshot().priority;
+        assert_eq!(priority.critical_reads, 2);
+        assert_eq!(priority.yields, 0);
+
+        // a critical read waiting for the lock holds up writers and scans of its bucket only
+        let critical = index.priority_gates[ix].enter();
+        index.update(&key, |_| Some((vec![2], 1)));
+        assert_eq!(index.scan_bucket(ix).iter().count(), 1);
+        assert_eq!(index.scan_bucket(1 - ix).iter().count(), 0);
+        drop(critical);
+        index.update(&key, |_| Some((vec![3], 1)));
+        assert_eq!(index.read_value(&key), Some((vec![3], 1)));
+        assert_eq!(index.stats_snapshot().priority.yields, 2);
+    }
+
+    #[test]
+    fn bucket_map_test_rates() {
+        let clock = Arc::new(ManualClock::default());
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            clock: Some(clock.clone()),
+            rate_window: Some(Duration::from_secs(10)),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        let keys = (0..20).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let rates = index.stats_snapshot().rates;
+        assert_eq!(rates.window, Duration::from_secs(10));
+        assert_eq!(rates.update, 0.0);
+
+        // 20 updates and 40 reads in each of 5 seconds
+        for _ in 0..5 {
+            for key in keys.iter() {
+                index.update(key, |_| Some((vec![1], 1)));
+                index.read_value(key);
+                index.read_value(key);
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        let rates = index.stats_snapshot().rates;
+        assert_eq!(rates.update, 20.0);
+        assert_eq!(rates.read, 40.0);
+        assert_eq!(rates.delete, 0.0);
+
+        // the window moves on, so a pause in traffic shows up
+        clock.advance(Duration::from_secs(5));
+        keys.iter().for_each(|key| index.delete_key(key));
+        clock.advance(Duration::from_secs(5));
+        let rates = index.stats_snapshot().rates;
+        assert_eq!(rates.update, 0.0);
+        assert_eq!(rates.delete, 2.0);
+    }
+
+    #[test]
+    fn bucket_map_test_rates_updates_only() {
+        let clock = Arc::new(ManualClock::default());
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            clock: Some(clock.clone()),
+            rate_window: Some(Duration::from_secs(10)),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        let keys = (0..10).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+
+        // 10 updates and 10 addrefs in each of 5 seconds, nothing else samples the counts
+        for _ in 0..5 {
+            for key in keys.iter() {
+                index.update(key, |_| Some((vec![1], 1)));
+                index.addref(key);
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        let rates = index.stats_snapshot().rates;
+        assert_eq!(rates.update, 20.0);
+        assert_eq!(rates.insert, 0.0);
+        assert_eq!(rates.read, 0.0);
+        assert_eq!(rates.delete, 0.0);
+    }
+
+    #[test]
+    fn bucket_map_test_into_persistent() {
+        let drive = TempDir::new().unwrap();
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            drives: Some(vec![drive.path().to_path_buf()]),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64; 1 + i % 2], 1)));
+        }
+        let expected = index
+            .buckets
+            .iter()
+            .map(|bucket| {
+                let bucket = bucket.read().unwrap();
+                bucket.as_ref().map_or(0, |bucket| 1 + bucket.data.len())
+            })
+            .sum::<usize>();
+        let random = index
+            .buckets
+            .iter()
+            .map(|bucket| {
+                bucket
+                    .read()
+                    .unwrap()
+                    .as_ref()
+                    .map(|bucket| bucket.random())
+            })
+            .collect::<Vec<_>>();
+        let files = index.into_persistent().unwrap();
+        // plus the metadata file of the drive
+        assert_eq!(files.len(), expected + 1);
+        assert!(files.iter().all(|file| file.starts_with(drive.path())));
+        let metadata = MapMetadata::read(&drive.path().join(METADATA_FILE)).unwrap();
+        assert_eq!(metadata.max_buckets, 2);
+        assert_eq!(metadata.bucket_assignment, BucketAssignment::Prefix);
+        assert_eq!(metadata.bucket_hash_key, None);
+        assert_eq!(
+            metadata
+                .buckets
+                .iter()
+                .map(|bucket| bucket.as_ref().map(|bucket| bucket.random))
+                .collect::<Vec<_>>(),
+            random
+        );
+        for bucket in metadata.buckets.iter().flatten() {
+            assert!(files.contains(&bucket.index_file));
+            assert!(bucket.data_files.iter().all(|file| files.contains(file)));
+        }
+        let mut on_disk = fs::read_dir(drive.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        on_disk.sort();
+        let mut files = files;
+        files.sort();
+        assert_eq!(on_disk, files);
+
+        // a map without drives keeps its temporary directory
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 1));
+        index.update(&keys[0], |_| Some((vec![0], 1)));
+        let dir = index.temp_dir.as_ref().unwrap().path().to_path_buf();
+        let files = index.into_persistent().unwrap();
+        assert!(!files.is_empty());
+        assert!(files
+            .iter()
+            .all(|file| file.starts_with(&dir) && file.exists()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Take the data cell of `key` from it, as if another key had been written over it
+    fn steal_data_cell(index: &BucketMap<u64>, key: &Pubkey) {
+        let bucket = index.buckets[index.bucket_ix(key)].read().unwrap();
+        let bucket = bucket.as_ref().unwrap();
+        let (elem, _) = bucket.find_entry(key).unwrap();
+        let data_bucket = &bucket.data[elem.data_bucket_ix() as usize];
+        let loc = elem.data_loc(data_bucket);
+        data_bucket.free(loc, IndexEntry::key_uid(&elem.key));
+        data_bucket.allocate(loc, 1).unwrap();
+    }
+
+    #[test]
+    fn bucket_map_test_assert_mode_resilient() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            assert_mode: AssertMode::Resilient,
+            ..BucketMapConfig::new(1 << 3)
+        });
+
+        let keys = (0..3).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            index.update(key, |_| Some((vec![1, 2], 1)));
+        }
+        for key in keys.iter() {
+            steal_data_cell(&index, key);
+        }
+        // the entries read as missing, and the stolen cells are left to their new owner
+        assert!(keys.iter().all(|key| index.read_value(key).is_none()));
+        assert!(keys.iter().all(|key| matches!(
+            index.try_read_value(key),
+            Err(BucketMapError::InvariantViolation(
+                InvariantViolation::DataCellUid { .. }
+            ))
+        )));
+        assert_eq!(index.try_read_value(&Pubkey::new_unique()).unwrap(), None);
+        index.insert(index.bucket_ix(&keys[0]), &keys[0], (&[3, 4], 2));
+        assert_eq!(index.read_value(&keys[0]), Some((vec![3, 4], 2)));
+        index.update(&keys[1], |_| Some((vec![5; 3], 1)));
+        assert_eq!(index.read_value(&keys[1]), Some((vec![5; 3], 1)));
+        index.delete_key(&keys[2]);
+        assert_eq!(index.read_value(&keys[2]), None);
+        let report = index.quick_check();
+        assert_eq!(report.buckets.iter().map(|b| b.entries).sum::<u64>(), 2);
+        assert!(report
+            .buckets
+            .iter()
+            .flat_map(|b| b.problems.iter())
+            .all(|problem| matches!(problem, CheckProblem::LeakedCells { .. })));
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant violated: DataCellUid")]
+    fn bucket_map_test_assert_mode_strict_panics() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            assert_mode: AssertMode::Strict,
+            ..BucketMapConfig::new(1)
+        });
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![1], 1)));
+        steal_data_cell(&index, &key);
+        index.read_value(&key);
+    }
+
+    #[test]
+    fn bucket_map_test_max_buckets_not_power_of_two() {
+        // a config error in either assert mode, never rounded
+        for assert_mode in [AssertMode::Strict, AssertMode::Resilient] {
+            assert_eq!(
+                BucketMap::<u64>::try_new(BucketMapConfig {
+                    assert_mode,
+                    ..BucketMapConfig::new(6)
+                })
+                .err(),
+                Some(ConfigError::MaxBucketsNotPowerOfTwo(6))
+            );
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_swap() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        let keys = (0..8).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate().take(6) {
+            index.update(key, |_| Some((vec![i as u64; i], i as RefCount)));
+        }
+        let value = |i: usize| Some((vec![i as u64; i], i as RefCount));
+        // both present, then one missing either way, then both missing
+        index.swap(&keys[0], &keys[1]);
+        assert_eq!(index.read_value(&keys[0]), value(1));
+        assert_eq!(index.read_value(&keys[1]), value(0));
+        index.swap(&keys[2], &keys[6]);
+        assert_eq!(index.read_value(&keys[2]), None);
+        assert_eq!(index.read_value(&keys[6]), value(2));
+        index.swap(&keys[7], &keys[3]);
+        assert_eq!(index.read_value(&keys[7]), value(3));
+        assert_eq!(index.read_value(&keys[3]), None);
+        index.swap(&keys[2], &keys[3]);
+        assert_eq!(index.read_value(&keys[2]), None);
+        assert_eq!(index.read_value(&keys[3]), None);
+        index.swap(&keys[4], &keys[4]);
+        assert_eq!(index.read_value(&keys[4]), value(4));
+
+        // keys in one bucket
+        let ix = index.bucket_ix(&keys[4]);
+        let same = std::iter::repeat_with(Pubkey::new_unique)
+            .find(|key| index.bucket_ix(key) == ix)
+            .unwrap();
+        let updates = |ix: usize| index.stats_snapshot().per_bucket[ix].update.count;
+        let before = updates(ix);
+        index.swap(&keys[4], &same);
+        assert_eq!(index.read_value(&keys[4]), None);
+        assert_eq!(index.read_value(&same), value(4));
+        assert_eq!(updates(ix), before + 1);
+
+        // a swap across buckets is counted in both
+        let other = std::iter::repeat_with(Pubkey::new_unique)
+            .find(|key| index.bucket_ix(key) != ix)
+            .unwrap();
+        let other_ix = index.bucket_ix(&other);
+        let before = (updates(ix), updates(other_ix));
+        index.swap(&same, &other);
+        assert_eq!(index.read_value(&other), value(4));
+        assert_eq!(
+            (updates(ix), updates(other_ix)),
+            (before.0 + 1, before.1 + 1)
+        );
+    }
+
+    #[test]
+    fn bucket_map_test_swap_atomic() {
+        let index = Arc::new(BucketMap::<u64>::new(BucketMapConfig::new(1 << 2)));
+        let a = Pubkey::new_unique();
+        let b = std::iter::repeat_with(Pubkey::new_unique)
+            .find(|b| index.bucket_ix(b) != index.bucket_ix(&a))
+            .unwrap();
+        let keys = (a, b);
+        index.update(&keys.0, |_| Some((vec![1], 1)));
+        let done = Arc::new(AtomicBool::new(false));
+        let swapper = {
+            let index = Arc::clone(&index);
+            let done = Arc::clone(&done);
+            // the reverse order locks the buckets the same way
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    index.swap(&keys.0, &keys.1);
+                    index.swap(&keys.1, &keys.0);
+                }
+                done.store(true, Ordering::Relaxed);
+            })
+        };
+        // the swapper stops on its own, so a failed assert can't leave it running
+        let (low, high) = {
+            let ix = (index.bucket_ix(&keys.0), index.bucket_ix(&keys.1));
+            (ix.0.min(ix.1), ix.0.max(ix.1))
+        };
+        while !done.load(Ordering::Relaxed) {
+            // read locked in bucket order, like swap locks them
+            let guards = (
+                index.buckets[low].read().unwrap(),
+                index.buckets[high].read().unwrap(),
+            );
+            let present = |key: &Pubkey| {
+                let guard = if index.bucket_ix(key) == low {
+                    &guards.0
+                } else {
+                    &guards.1
+                };
+                guard
+                    .as_ref()
+                    .and_then(|bucket| bucket.read_value(key))
+                    .is_some()
+            };
+            assert_eq!(present(&keys.0) as usize + present(&keys.1) as usize, 1);
+        }
+        swapper.join().unwrap();
+    }
+
+    #[test]
+    fn bucket_map_test_iter_with_stats() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        assert_eq!(index.iter_with_stats(16).count(), 0);
+
+        let keys = (0..100)
+            .map(|i| {
+                let key = Pubkey::new_unique();
+                index.update(&key, |_| Some((vec![i; i as usize % 3], i)));
+                key
+            })
+            .collect::<Vec<_>>();
+        // reserved keys aren't counted or returned
+        let reserved = Pubkey::new_unique();
+        let ix = index.bucket_ix(&reserved);
+        let reservation = loop {
+            match index.reserve(ix, &reserved, 2) {
+                Ok(reservation) => break reservation,
+                Err(err) => index.grow(ix, err),
+            }
+        };
+        for chunk_size in [1, 7, 1024].iter() {
+            let mut found = vec![IterStats::default(); index.num_buckets()];
+            let mut expected = found.clone();
+            for chunk in index.iter_with_stats(*chunk_size) {
+                assert!(!chunk.items.is_empty());
+                assert!(chunk.items.len() <= *chunk_size);
+                expected[chunk.bucket_ix] = chunk.bucket_stats;
+                for item in chunk.items {
+                    assert_eq!(index.bucket_ix(&item.pubkey), chunk.bucket_ix);
+                    assert_eq!(
+                        item.slot_list,
+                        vec![item.ref_count; item.ref_count as usize % 3]
+                    );
+                    let found = &mut found[chunk.bucket_ix];
+                    found.entries += 1;
+                    found.bytes += (item.slot_list.len() * std::mem::size_of::<u64>()) as u64;
+                }
+            }
+            assert_eq!(found, expected);
+            let entries = found.iter().map(|stats| stats.entries).sum::<u64>();
+            assert_eq!(entries, keys.len() as u64);
+        }
+
+        // only the bucket being iterated is locked
+        let mut iter = index.iter_with_stats(16);
+        let ix = iter.next().unwrap().bucket_ix;
+        let other = keys.iter().find(|key| index.bucket_ix(key) != ix).unwrap();
+        index.update(other, |_| Some((vec![], 0)));
+        drop(iter);
+        index.release(reservation);
+    }
+
+    #[test]
+    fn bucket_map_test_gc_data() {
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        let keys = (0..10)
+            .map(|i| {
+                let key = Pubkey::new_unique();
+                index.update(&key, |_| Some((vec![i], 0)));
+                key
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(index.gc_data(), 0);
+
+        // leak a data allocation that no index entry points to
+        let ix = index.bucket_ix(&keys[0]);
+        let cell_size = {
+            let bucket = index.buckets[ix].read().unwrap();
+            let data_bucket = &bucket.as_ref().unwrap().data[0];
+            let leaked = (0..data_bucket.capacity())
+                .find(|i| data_bucket.uid(*i) == UID_UNLOCKED)
+                .unwrap();
+            data_bucket.allocate(leaked, 1).unwrap();
+            data_bucket.cell_size
+        };
+        // a cancelled collection frees nothing
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        assert_eq!(index.gc_data_cancellable(Some(&cancel)), Err(Cancelled));
+        assert_eq!(index.gc_data(), cell_size);
+        assert_eq!(index.gc_data(), 0);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(index.read_value(key), Some((vec![i as u64], 0)));
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_quick_check() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            data_cell_keys: true,
+            ..BucketMapConfig::new(1 << 1)
+        });
+        assert_eq!(index.quick_check().buckets, vec![BucketCheck::default(); 2]);
+        let keys = (0..100)
+            .map(|i| {
+                // random keys, so both buckets get more than 10 whatever other tests take
+                let key = solana_sdk::pubkey::new_rand();
+                index.update(&key, |_| Some((vec![i; i as usize % 3], 1)));
+                key
+            })
+            .collect::<Vec<_>>();
+        let report = index.quick_check_samples(10);
+        assert!(report.is_ok());
+        assert_eq!(report.buckets.iter().map(|b| b.entries).sum::<u64>(), 100);
+        assert!(report.buckets.iter().all(|b| b.sampled == 10));
+
+        // leak a data allocation that no index entry points to
+        let ix = index.bucket_ix(&keys[1]);
+        {
+            let bucket = index.buckets[ix].read().unwrap();
+            let data_bucket = &bucket.as_ref().unwrap().data[0];
+            let leaked = (0..data_bucket.capacity())
+                .find(|i| data_bucket.uid(*i) == UID_UNLOCKED)
+                .unwrap();
+            data_bucket.allocate(leaked, 1).unwrap();
+        }
+        let report = index.quick_check();
+        assert_eq!(
+            report.buckets[ix].problems,
+            vec![CheckProblem::LeakedCells { cells: 1 }]
+        );
+        assert_eq!(report.actions, vec![RepairAction::Compact(ix)]);
+        index.gc_data();
+        assert!(index.quick_check().is_ok());
+
+        // free the data cell of an entry behind the index's back
+        let entry = {
+            let bucket = index.buckets[ix].read().unwrap();
+            let bucket = bucket.as_ref().unwrap();
+            let (elem, entry) = bucket.find_entry(&keys[1]).unwrap();
+            let data_bucket = &bucket.data[elem.data_bucket_ix() as usize];
+            data_bucket.free(elem.data_loc(data_bucket), IndexEntry::key_uid(&elem.key));
+            entry
+        };
+        let report = index.quick_check_samples(u64::MAX);
+        assert_eq!(
+            report.buckets[ix].problems,
+            vec![CheckProblem::DataHeader { entry }]
+        );
+        assert!(report.buckets[1 - ix].problems.is_empty());
+        assert_eq!(report.actions, vec![RepairAction::Rebuild(ix)]);
+        index.rebuild_index(ix).unwrap();
+        assert!(index.quick_check_samples(u64::MAX).is_ok());
+        assert_eq!(index.read_value(&keys[1]), None);
+        assert_eq!(index.read_value(&keys[2]), Some((vec![2; 2], 1)));
+    }
+
+    #[test]
+    fn bucket_map_test_progress() {
+        let reports = Arc::new(Mutex::new(vec![]));
+        let config = BucketMapConfig {
+            progress: Some(ProgressCallback::new({
+                let reports = Arc::clone(&reports);
+                move |progress| reports.lock().unwrap().push(progress)
+            })),
+            ..BucketMapConfig::new(1)
+        };
+        let index = BucketMap::new(config);
+        for i in 0..100 {
+            index.update(&Pubkey::new_unique(), |_| Some((vec![i], 0)));
+        }
+        index.reserve_data(0, 1, 1 << 16);
+        index.gc_data();
+        index.export_debug(&mut vec![], ExportFormat::Csv).unwrap();
+        assert!(index.quick_check().is_ok());
+
+        let reports = reports.lock().unwrap();
+        for operation in [
+            ProgressOperation::GrowIndex,
+            ProgressOperation::GrowData,
+            ProgressOperation::Compaction,
+            ProgressOperation::Export,
+            ProgressOperation::Verify,
+        ]
+        .iter()
+        {
+            let reports = reports
+                .iter()
+                .filter(|progress| progress.operation == *operation)
+                .collect::<Vec<_>>();
+            let last = reports.last().unwrap();
+            assert_eq!(last.processed_bytes, last.total_bytes);
+            assert!(reports
+                .iter()
+                .all(|progress| progress.processed_bytes <= progress.total_bytes));
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_memory_usage() {
+        let index = BucketMap::new(BucketMapConfig::new(1 << 1));
+        let empty = index.memory_usage();
+        assert_eq!(empty.buckets.len(), 2);
+        assert_eq!(empty.mapped_bytes(), 0);
+        assert_eq!(empty.heap_bytes(), empty.map_heap_bytes);
+        assert!(empty.map_heap_bytes > 0);
+
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![0; 3], 1)));
+        let report = index.memory_usage();
+        let ix = index.bucket_ix(&key);
+        let bucket = report.buckets[ix];
+        assert_eq!(report.buckets[1 - ix], BucketMemoryUsage::default());
+        // an index and one data storage
+        assert!(bucket.mapped_bytes > 0);
+        assert!(bucket.resident_bytes <= bucket.mapped_bytes);
+        #[cfg(unix)]
+        assert!(bucket.resident_bytes > 0);
+        assert!(bucket.heap_bytes > 0);
+        assert_eq!(report.mapped_bytes(), bucket.mapped_bytes);
+        assert_eq!(
+            report.heap_bytes(),
+            report.map_heap_bytes + bucket.heap_bytes
+        );
+    }
+
+    #[test]
+    fn bucket_map_test_bucket_ranges() {
+        for max_buckets_pow2 in 0..6 {
+            let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << max_buckets_pow2));
+            let num_buckets = index.num_buckets();
+            assert_eq!(index.pubkey_bounds_of_bucket(0).0, Pubkey::new(&[0; 32]));
+            assert_eq!(
+                index.pubkey_bounds_of_bucket(num_buckets - 1).1,
+                Pubkey::new(&[0xff; 32])
+            );
+            for ix in 0..num_buckets {
+                let (first, last) = index.pubkey_bounds_of_bucket(ix);
+                assert_eq!(index.bucket_ix(&first), ix);
+                assert_eq!(index.bucket_ix(&last), ix);
+                assert_eq!(
+                    index.bucket_range_for_pubkey_range(&(first..=last)),
+                    ix..ix + 1
+                );
+                assert_eq!(
+                    index.bucket_range_for_pubkey_range(&(first..last)),
+                    ix..ix + 1
+                );
+                assert_eq!(index.bucket_range_for_pubkey_range(&(first..first)), ix..ix);
+                assert_eq!(index.bucket_range_for_pubkey_range(&(..first)), 0..ix);
+                assert_eq!(
+                    index.bucket_range_for_pubkey_range(&(first..)),
+                    ix..num_buckets
+                );
+                if ix + 1 < num_buckets {
+                    let (next, _) = index.pubkey_bounds_of_bucket(ix + 1);
+                    assert!(last < next);
+                }
+            }
+            assert_eq!(index.bucket_range_for_pubkey_range(&(..)), 0..num_buckets);
+            for _ in 0..100 {
+                let key = solana_sdk::pubkey::new_rand();
+                let ix = index.bucket_ix(&key);
+                let (first, last) = index.pubkey_bounds_of_bucket(ix);
+                assert!(first <= key && key <= last);
+                assert_eq!(
+                    index.bucket_range_for_pubkey_range(&(key..=key)),
+                    ix..ix + 1
+                );
+            }
+        }
+
+        let config = BucketMapConfig {
+            bucket_hash_key: Some(BucketHashKey::new_rand()),
+            ..BucketMapConfig::new(1 << 2)
+        };
+        let index = BucketMap::<u64>::new(config);
         let key = Pubkey::new_unique();
-        let config = BucketMapConfig::new(1 << 1);
-        let index = BucketMap::new(config);
-        index.insert(index.bucket_ix(&key), &key, (&[0], 0));
-        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
-        index.insert(index.bucket_ix(&key), &key, (&[1], 0));
-        assert_eq!(index.read_value(&key), Some((vec![1], 0)));
+        assert_eq!(index.bucket_range_for_pubkey_range(&(key..=key)), 0..4);
+        assert_eq!(
+            index.pubkey_bounds_of_bucket(3),
+            (Pubkey::new(&[0; 32]), Pubkey::new(&[0xff; 32]))
+        );
     }
 
     #[test]
-    fn bucket_map_test_update() {
+    fn bucket_map_test_cancellable_scans() {
+        let index = BucketMap::new(BucketMapConfig::new(1));
         let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![0], 1)));
+        let range = None::<&std::ops::RangeFull>;
+
+        let cancel = CancelToken::from(Arc::new(AtomicBool::default()));
+        assert_eq!(index.keys_cancellable(0, Some(&cancel)), Ok(vec![key]));
+        assert_eq!(
+            index
+                .items_in_range_cancellable(0, &range, Some(&cancel))
+                .unwrap()
+                .len(),
+            1
+        );
+        cancel.cancel();
+        assert_eq!(index.keys_cancellable(0, Some(&cancel)), Err(Cancelled));
+        assert!(index
+            .items_in_range_cancellable(0, &range, Some(&cancel))
+            .is_err());
+        assert_eq!(
+            index.quick_check_cancellable(QUICK_CHECK_SAMPLES, Some(&cancel)),
+            Err(Cancelled)
+        );
+        assert_eq!(index.keys(0), vec![key]);
+    }
+
+    #[test]
+    fn bucket_map_test_reuse_freed_data() {
         let config = BucketMapConfig::new(1 << 1);
         let index = BucketMap::new(config);
+        let data_loc = |key: &Pubkey| {
+            let bucket = index.buckets[index.bucket_ix(key)].read().unwrap();
+            let bucket = bucket.as_ref().unwrap();
+            let (elem, _) = bucket.find_entry(key).unwrap();
+            elem.data_loc(&bucket.data[elem.data_bucket_ix() as usize])
+        };
+        let key = Pubkey::new_unique();
         index.update(&key, |_| Some((vec![0], 0)));
-        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
+        let freed = data_loc(&key);
+        index.delete_key(&key);
+
         index.update(&key, |_| Some((vec![1], 0)));
+        assert_eq!(data_loc(&key), freed);
         assert_eq!(index.read_value(&key), Some((vec![1], 0)));
     }
 
     #[test]
-    fn bucket_map_test_update_to_0_len() {
-        solana_logger::setup();
+    fn bucket_map_test_independent_capacity() {
+        let config = BucketMapConfig {
+            index_capacity_pow2: Some(3),
+            data_capacity_bytes: Some(1 << 12),
+            ..BucketMapConfig::new(1)
+        };
+        let index = BucketMap::<u64>::new(config);
         let key = Pubkey::new_unique();
-        let config = BucketMapConfig::new(1 << 1);
-        let index = BucketMap::new(config);
-        index.update(&key, |_| Some((vec![0], 1)));
-        assert_eq!(index.read_value(&key), Some((vec![0], 1)));
-        // sets len to 0, updates in place
-        index.update(&key, |_| Some((vec![], 1)));
-        assert_eq!(index.read_value(&key), Some((vec![], 1)));
-        // sets len to 0, doesn't update in place - finds a new place, which causes us to no longer have an allocation in data
-        index.update(&key, |_| Some((vec![], 2)));
-        assert_eq!(index.read_value(&key), Some((vec![], 2)));
-        // sets len to 1, doesn't update in place - finds a new place
-        index.update(&key, |_| Some((vec![1], 2)));
-        assert_eq!(index.read_value(&key), Some((vec![1], 2)));
+        index.update(&key, |_| Some((vec![0], 0)));
+        let capacities = || {
+            let bucket = index.buckets[0].read().unwrap();
+            let bucket = bucket.as_ref().unwrap();
+            let data_bucket = &bucket.data[0];
+            (
+                bucket.index_capacity(),
+                data_bucket.capacity() * data_bucket.cell_size,
+            )
+        };
+        let (index_capacity, data_bytes) = capacities();
+        assert_eq!(index_capacity, 1 << 3);
+        assert!(data_bytes >= 1 << 12);
+
+        index.reserve_index(0, 1 << 6);
+        assert_eq!(capacities(), (1 << 6, data_bytes));
+
+        index.reserve_data(0, 1, data_bytes * 4);
+        assert_eq!(capacities(), (1 << 6, data_bytes * 4));
+        assert_eq!(index.read_value(&key), Some((vec![0], 0)));
     }
 
     #[test]
-    fn bucket_map_test_delete() {
+    fn bucket_map_test_insert_previous_ref_count() {
         let config = BucketMapConfig::new(1 << 1);
         let index = BucketMap::new(config);
-        for i in 0..10 {
-            let key = Pubkey::new_unique();
-            assert_eq!(index.read_value(&key), None);
+        let key = Pubkey::new_unique();
+        let ix = index.bucket_ix(&key);
+        assert_eq!(
+            index.insert_and_get_previous_ref_count(ix, &key, (&[0], 1)),
+            None
+        );
+        index.addref(&key);
+        assert_eq!(
+            index.insert_and_get_previous_ref_count(ix, &key, (&[1, 2], 5)),
+            Some(2)
+        );
+        assert_eq!(index.read_value(&key), Some((vec![1, 2], 5)));
+        index.delete_key(&key);
+        assert_eq!(
+            index.insert_and_get_previous_ref_count(ix, &key, (&[3], 7)),
+            None
+        );
+    }
 
-            index.update(&key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+    #[test]
+    fn bucket_map_test_delete_keys() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        let keys = (0..100)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64], 1)));
+        }
+        let missing = Pubkey::new_unique();
+        let mut batch = keys.iter().step_by(2).cloned().collect::<Vec<_>>();
+        batch.push(missing);
+        batch.push(keys[0]);
+        let deleted = index.delete_keys(&batch);
+        assert_eq!(deleted.len(), batch.len());
+        assert!(deleted[..50].iter().all(|deleted| *deleted));
+        // missing keys and keys already deleted earlier in the batch
+        assert_eq!(deleted[50..], [false, false]);
+        for (i, key) in keys.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some((vec![i as u64], 1))
+            };
+            assert_eq!(index.read_value(key), expected);
+        }
+        assert!(index.delete_keys(&[]).is_empty());
+    }
 
-            index.delete_key(&key);
-            assert_eq!(index.read_value(&key), None);
+    #[test]
+    fn bucket_map_test_copy_bucket() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(2));
+        let mut items = vec![];
+        index.copy_bucket(0, &mut items);
+        assert!(items.is_empty());
 
-            index.update(&key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
-            index.delete_key(&key);
+        let keys = std::iter::repeat_with(Pubkey::new_unique)
+            .filter(|key| index.bucket_ix(key) == 1)
+            .take(50)
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64; i % 4], i as u64)));
+        }
+        let sorted = |items: &[BucketItem<u64>]| {
+            let mut items = items
+                .iter()
+                .map(|item| (item.pubkey, item.slot_list.clone(), item.ref_count))
+                .collect::<Vec<_>>();
+            items.sort_unstable();
+            items
+        };
+        let none = None::<&RangeInclusive<Pubkey>>;
+        index.copy_bucket(1, &mut items);
+        assert_eq!(sorted(&items), sorted(&index.items_in_range(1, &none)));
+        let buffer = items.as_ptr();
+
+        for key in keys.iter().step_by(2) {
+            index.delete_key(key);
         }
+        index.copy_bucket(1, &mut items);
+        assert_eq!(items.len(), 25);
+        assert_eq!(sorted(&items), sorted(&index.items_in_range(1, &none)));
+        // the same allocation was filled again
+        assert_eq!(items.as_ptr(), buffer);
+
+        index.copy_bucket(0, &mut items);
+        assert!(items.is_empty());
     }
 
     #[test]
-    fn bucket_map_test_delete_2() {
-        let config = BucketMapConfig::new(1 << 2);
-        let index = BucketMap::new(config);
-        for i in 0..100 {
-            let key = Pubkey::new_unique();
-            assert_eq!(index.read_value(&key), None);
+    fn bucket_map_test_filter_items_in_range() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1));
+        let mut keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        keys.sort();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64; i % 4], i as u64 % 3)));
+        }
+        let check = |range: Option<&RangeInclusive<Pubkey>>, items: Vec<BucketItem<u64>>| {
+            let mut found = items
+                .into_iter()
+                .map(|item| {
+                    let i = keys.iter().position(|key| *key == item.pubkey).unwrap();
+                    assert_eq!(item.slot_list, vec![i as u64; i % 4]);
+                    assert_eq!(item.ref_count, i as u64 % 3);
+                    i
+                })
+                .collect::<Vec<_>>();
+            found.sort_unstable();
+            let expected = (0..keys.len())
+                .filter(|i| i % 4 == 2 && i % 3 != 0)
+                .filter(|i| range.map(|range| range.contains(&keys[*i])).unwrap_or(true))
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected);
+        };
+        let pred = |value: &[u64], ref_count| value.len() == 2 && ref_count != 0;
+        check(
+            None,
+            index.filter_items_in_range(0, &None::<&RangeInclusive<Pubkey>>, pred),
+        );
+        let range = keys[10]..=keys[50];
+        check(
+            Some(&range),
+            index.filter_items_in_range(0, &Some(&range), pred),
+        );
+        assert!(index
+            .filter_items_in_range(0, &None::<&RangeInclusive<Pubkey>>, |_, _| false)
+            .is_empty());
+    }
 
-            index.update(&key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+    #[test]
+    fn bucket_map_test_purge_zero_refcount() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(4));
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64 / 4; i % 3], i as u64 % 4)));
+        }
+        let unreferenced = |i: &usize| i % 4 == 0;
+        // entries with an odd value are kept
+        let odd = |i: &usize| i % 3 != 0 && (i / 4) % 2 == 1;
+        assert_eq!(
+            index.purge_zero_refcount_with(|_, value| value.iter().all(|v| v % 2 == 0)),
+            (0..100).filter(|i| unreferenced(i) && !odd(i)).count() as u64
+        );
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                index.read_value(key).is_none(),
+                unreferenced(&i) && !odd(&i)
+            );
+        }
+        assert_eq!(
+            index.purge_zero_refcount(),
+            (0..100).filter(|i| unreferenced(i) && odd(i)).count() as u64
+        );
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(index.read_value(key).is_none(), unreferenced(&i));
+        }
+        // a purge that deletes nothing isn't counted as a delete
+        let deletes = index.stats_snapshot().delete.count;
+        assert!(deletes > 0 && deletes <= 8);
+        assert_eq!(index.purge_zero_refcount(), 0);
+        assert_eq!(index.stats_snapshot().delete.count, deletes);
+    }
 
-            index.delete_key(&key);
-            assert_eq!(index.read_value(&key), None);
+    #[test]
+    fn bucket_map_test_rebuild_index() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            data_cell_keys: true,
+            ..BucketMapConfig::new(1)
+        });
+        let keys = (0..200).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64; i % 5], 1)));
+        }
+        // moved to another data storage and ref counts changed since
+        for (i, key) in keys.iter().enumerate().step_by(3) {
+            index.update(key, |_| Some((vec![i as u64; i % 5 + 1], 1)));
+            index.addref(key);
+        }
+        index.delete_key(&keys[1]);
+        let expected = keys
+            .iter()
+            .map(|key| index.read_value(key))
+            .collect::<Vec<_>>();
+        let reserved = Pubkey::new_unique();
+        let reservation = loop {
+            match index.reserve(0, &reserved, 4) {
+                Ok(reservation) => break reservation,
+                Err(err) => index.grow(0, err),
+            }
+        };
 
-            index.update(&key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
-            index.delete_key(&key);
+        index.buckets[0]
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .clear_index();
+        assert_eq!(index.read_value(&keys[2]), None);
+        let recovered = index.rebuild_index(0).unwrap();
+        // empty values aren't recovered
+        assert_eq!(
+            recovered,
+            expected
+                .iter()
+                .filter(|value| matches!(value, Some((value, _)) if !value.is_empty()))
+                .count() as u64
+        );
+        for (key, expected) in keys.iter().zip(expected.iter()) {
+            match expected {
+                Some((value, _)) if value.is_empty() => assert_eq!(index.read_value(key), None),
+                _ => assert_eq!(&index.read_value(key), expected),
+            }
         }
+        assert_eq!(index.read_value(&reserved), None);
+        // the reserved data cell isn't reachable anymore
+        assert!(index.gc_data() > 0);
+        index.release(reservation);
+        assert_eq!(index.bucket_len(0), recovered);
     }
 
     #[test]
-    fn bucket_map_test_n_drives() {
-        let config = BucketMapConfig::new(1 << 2);
-        let index = BucketMap::new(config);
-        for i in 0..100 {
-            let key = Pubkey::new_unique();
-            index.update(&key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(&key), Some((vec![i], 0)));
+    #[should_panic(expected = "requires data_cell_keys")]
+    fn bucket_map_test_rebuild_index_without_keys() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1));
+        index.rebuild_index(0).unwrap();
+    }
+
+    #[test]
+    fn bucket_map_test_reserve() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1));
+        let reserve = |key: &Pubkey, value_len| loop {
+            match index.reserve(0, key, value_len) {
+                Ok(reservation) => return reservation,
+                Err(err) => index.grow(0, err),
+            }
+        };
+        let key = Pubkey::new_unique();
+        let reservation = reserve(&key, 3);
+        assert_eq!(reservation.key(), &key);
+        // reserved keys aren't visible
+        assert_eq!(index.read_value(&key), None);
+        assert!(index.keys(0).is_empty());
+        assert_eq!(index.addref(&key), None);
+        assert!(index
+            .items_in_range(0, &None::<&RangeInclusive<Pubkey>>)
+            .is_empty());
+        index.commit(reservation, (&[1, 2, 3], 1));
+        assert_eq!(index.read_value(&key), Some((vec![1, 2, 3], 1)));
+        assert_eq!(index.keys(0), vec![key]);
+
+        // a released reservation leaves nothing behind and can be made again
+        let other = Pubkey::new_unique();
+        index.release(reserve(&other, 2));
+        assert_eq!(index.read_value(&other), None);
+        let reservation = reserve(&other, 0);
+        index.commit(reservation, (&[], 2));
+        assert_eq!(index.read_value(&other), Some((vec![], 2)));
+
+        // reserving a present key claims nothing, releasing it keeps the value
+        index.release(reserve(&key, 1));
+        assert_eq!(index.read_value(&key), Some((vec![1, 2, 3], 1)));
+        index.commit(reserve(&key, 1), (&[4], 3));
+        assert_eq!(index.read_value(&key), Some((vec![4], 3)));
+    }
+
+    #[test]
+    fn bucket_map_test_bucket_generation() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        let key = Pubkey::new_from_array([0; 32]);
+        let ix = index.bucket_ix(&key);
+        let other = Pubkey::new_from_array([0xff; 32]);
+        assert_ne!(index.bucket_ix(&other), ix);
+        assert!((0..4).all(|ix| index.bucket_generation(ix) == 0));
+
+        let mut last = 0;
+        let mut assert_bumped = |index: &BucketMap<u64>| {
+            let generation = index.bucket_generation(ix);
+            assert!(generation > last);
+            last = generation;
+        };
+        index.update(&key, |_| Some((vec![0], 0)));
+        assert_bumped(&index);
+        index.insert(ix, &key, (&[1], 0));
+        assert_bumped(&index);
+        index.addref(&key);
+        assert_bumped(&index);
+        index.unref_batch(&[key]);
+        assert_bumped(&index);
+        index.reserve_index(ix, 1 << 10);
+        assert_bumped(&index);
+        index.gc_data();
+        assert_bumped(&index);
+        index.delete_keys(&[key]);
+        assert_bumped(&index);
+
+        // reads and other buckets leave the generation alone
+        index.read_value(&key);
+        index.items_in_range(ix, &None::<&RangeInclusive<Pubkey>>);
+        index.update(&other, |_| Some((vec![0], 0)));
+        assert_eq!(index.bucket_generation(ix), last);
+    }
+
+    #[test]
+    fn bucket_map_test_ref_count_batch() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        let keys = (0..100)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            index.update(key, |_| Some((vec![i as u64], 5)));
+        }
+        let missing = Pubkey::new_unique();
+        let mut batch = keys.clone();
+        batch.push(missing);
+        batch.push(keys[1]);
+        let ref_counts = index.addref_batch(&batch);
+        assert!(ref_counts[..100]
+            .iter()
+            .all(|ref_count| *ref_count == Some(6)));
+        // a repeated key is incremented again
+        assert_eq!(ref_counts[100..], [None, Some(7)]);
+
+        let ref_counts = index.unref_batch(&keys[..10]);
+        assert_eq!(ref_counts[0], Some(5));
+        assert_eq!(ref_counts[1], Some(6));
+        assert!(ref_counts[2..]
+            .iter()
+            .all(|ref_count| *ref_count == Some(5)));
+        for (i, key) in keys.iter().enumerate() {
+            let expected = if i < 10 && i != 1 { 5 } else { 6 };
+            assert_eq!(index.read_value(key), Some((vec![i as u64], expected)));
         }
     }
+
     #[test]
-    fn bucket_map_test_grow_read() {
-        let config = BucketMapConfig::new(1 << 2);
-        let index = BucketMap::new(config);
-        let keys: Vec<Pubkey> = (0..100).into_iter().map(|_| Pubkey::new_unique()).collect();
-        for k in 0..keys.len() {
-            let key = &keys[k];
-            let i = read_be_u64(key.as_ref());
-            index.update(key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(key), Some((vec![i], 0)));
-            for (ix, key) in keys.iter().enumerate() {
-                let i = read_be_u64(key.as_ref());
-                //debug!("READ: {:?} {}", key, i);
-                let expected = if ix <= k { Some((vec![i], 0)) } else { None };
-                assert_eq!(index.read_value(key), expected);
+    fn bucket_map_test_try_ref_count_batch() {
+        let index = BucketMap::<u64>::new(BucketMapConfig {
+            ref_count_mode: RefCountMode::Strict,
+            ..BucketMapConfig::new(1 << 2)
+        });
+        let keys = (0..3)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        index.update(&keys[0], |_| Some((vec![0], 0)));
+        index.update(&keys[1], |_| Some((vec![1], 1)));
+        index.update(&keys[2], |_| Some((vec![2], u64::MAX)));
+
+        // the failing keys are left alone, the others are still updated
+        let ref_counts = index.try_unref_batch(&[keys[0], keys[1], keys[1]]);
+        assert!(matches!(
+            ref_counts[..],
+            [
+                Err(BucketMapError::RefCountUnderflow),
+                Ok(Some(0)),
+                Err(BucketMapError::RefCountUnderflow)
+            ]
+        ));
+        let ref_counts = index.try_addref_batch(&[keys[2], keys[0], Pubkey::new_unique()]);
+        assert!(matches!(
+            ref_counts[..],
+            [Err(BucketMapError::RefCountOverflow), Ok(Some(1)), Ok(None)]
+        ));
+        assert_eq!(index.read_value(&keys[0]), Some((vec![0], 1)));
+        assert_eq!(index.read_value(&keys[1]), Some((vec![1], 0)));
+        assert_eq!(index.read_value(&keys[2]), Some((vec![2], u64::MAX)));
+    }
+
+    #[test]
+    fn bucket_map_test_ref_count_mode_default() {
+        assert_eq!(RefCountMode::default(), RefCountMode::Wrapping);
+        assert_eq!(
+            BucketMapConfig::new(1).ref_count_mode,
+            RefCountMode::Wrapping
+        );
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1));
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![], 0)));
+        assert_eq!(index.unref(&key), Some(u64::MAX));
+        assert_eq!(index.addref(&key), Some(0));
+    }
+
+    #[test]
+    fn bucket_map_test_seeded_layout() {
+        let run = |grow_pow2| {
+            let index = BucketMap::<u64>::new(BucketMapConfig {
+                index_capacity_pow2: Some(2),
+                seed: Some(42),
+                grow_pow2,
+                ..BucketMapConfig::new(1 << 2)
+            });
+            for i in 0..200u8 {
+                let key = Pubkey::new_from_array([i; 32]);
+                index.update(&key, |_| Some((vec![i as u64; (i % 3) as usize], 0)));
+            }
+            let drive = index.temp_dir.as_ref().unwrap().path();
+            let mut files = fs::read_dir(drive)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>();
+            files.sort();
+            let capacities = (0..index.num_buckets())
+                .map(|ix| {
+                    index.buckets[ix]
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .unwrap()
+                        .index_capacity()
+                })
+                .collect::<Vec<_>>();
+            let stats = index.stats_snapshot();
+            (
+                files,
+                capacities,
+                stats.index.resizes,
+                stats.data.resizes,
+                index.memory_usage().mapped_bytes(),
+            )
+        };
+        let first = run(None);
+        assert!(first.2 > 0);
+        assert_eq!(first, run(None));
+
+        // a larger growth factor takes fewer resizes to reach at least the same capacities
+        let quadrupled = run(Some(2));
+        assert!(quadrupled.2 < first.2);
+        assert!(quadrupled
+            .1
+            .iter()
+            .zip(first.1.iter())
+            .all(|(quadrupled, doubled)| quadrupled >= doubled));
+        assert_eq!(quadrupled, run(Some(2)));
+    }
+
+    #[test]
+    fn test_jump_consistent_hash() {
+        for _ in 0..1000 {
+            let key = thread_rng().gen::<u64>();
+            assert_eq!(jump_consistent_hash(key, 1), 0);
+            for num_buckets in 1..64 {
+                let before = jump_consistent_hash(key, num_buckets);
+                let after = jump_consistent_hash(key, num_buckets + 1);
+                assert!(before < num_buckets);
+                // a key either stays put or moves to the new bucket
+                assert!(after == before || after == num_buckets);
             }
         }
     }
 
     #[test]
-    fn bucket_map_test_n_delete() {
-        let config = BucketMapConfig::new(1 << 2);
-        let index = BucketMap::new(config);
-        let keys: Vec<Pubkey> = (0..20).into_iter().map(|_| Pubkey::new_unique()).collect();
+    fn bucket_map_test_jump_consistent_hash_assignment() {
+        let new_map = |max_buckets| {
+            BucketMap::<u64>::new(BucketMapConfig {
+                bucket_assignment: BucketAssignment::JumpConsistentHash,
+                ..BucketMapConfig::new(max_buckets)
+            })
+        };
+        let small = new_map(1 << 2);
+        let large = new_map(1 << 3);
+        let keys = (0..1000)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        let moved = keys
+            .iter()
+            .filter(|key| small.bucket_ix(key) != large.bucket_ix(key))
+            .count();
+        // about half of the keys move from 4 to 8 buckets, instead of 7/8 with prefix assignment
+        assert!(moved < 600, "{}", moved);
         for key in keys.iter() {
-            let i = read_be_u64(key.as_ref());
-            index.update(key, |_| Some((vec![i], 0)));
-            assert_eq!(index.read_value(key), Some((vec![i], 0)));
+            large.update(key, |_| Some((vec![1], 0)));
+            assert_eq!(large.read_value(key), Some((vec![1], 0)));
         }
+
+        // any number of buckets, in either assert mode
+        let twelve = new_map(12);
+        let thirteen = BucketMap::<u64>::new(BucketMapConfig {
+            bucket_assignment: BucketAssignment::JumpConsistentHash,
+            assert_mode: AssertMode::Strict,
+            ..BucketMapConfig::new(13)
+        });
+        assert_eq!(twelve.num_buckets(), 12);
+        assert_eq!(thirteen.num_buckets(), 13);
+        let moved = keys
+            .iter()
+            .filter(|key| {
+                let after = thirteen.bucket_ix(key);
+                // keys only move to the new bucket
+                assert!(after == twelve.bucket_ix(key) || after == 12);
+                after == 12
+            })
+            .count();
+        // about 1/13 of the keys
+        assert!(moved > 30 && moved < 150, "{}", moved);
         for key in keys.iter() {
-            let i = read_be_u64(key.as_ref());
-            //debug!("READ: {:?} {}", key, i);
-            assert_eq!(index.read_value(key), Some((vec![i], 0)));
+            thirteen.update(key, |_| Some((vec![2], 0)));
+            assert_eq!(thirteen.read_value(key), Some((vec![2], 0)));
         }
-        for k in 0..keys.len() {
-            let key = &keys[k];
-            index.delete_key(key);
-            assert_eq!(index.read_value(key), None);
-            for key in keys.iter().skip(k + 1) {
-                let i = read_be_u64(key.as_ref());
-                assert_eq!(index.read_value(key), Some((vec![i], 0)));
+        assert_eq!(
+            (0..13).map(|ix| thirteen.bucket_len(ix)).sum::<u64>(),
+            keys.len() as u64
+        );
+    }
+
+    #[test]
+    fn bucket_map_test_bucket_hash_key() {
+        let new_map = |bucket_hash_key| {
+            BucketMap::<u64>::new(BucketMapConfig {
+                bucket_hash_key,
+                ..BucketMapConfig::new(1 << 4)
+            })
+        };
+        // ground keys that share a prefix all land in the same bucket without a hash key
+        let keys = (0..100u8)
+            .map(|i| {
+                let mut key = [0u8; 32];
+                key[31] = i;
+                Pubkey::new_from_array(key)
+            })
+            .collect::<Vec<_>>();
+        let unkeyed = new_map(None);
+        assert!(keys.iter().all(|key| unkeyed.bucket_ix(key) == 0));
+
+        let hash_key = BucketHashKey::new_rand();
+        let keyed = new_map(Some(hash_key));
+        assert_eq!(keyed.bucket_hash_key(), Some(hash_key));
+        let buckets = keys
+            .iter()
+            .map(|key| keyed.bucket_ix(key))
+            .collect::<HashSet<_>>();
+        assert!(buckets.len() > 1);
+        // the same key always selects the same buckets
+        let reopened = new_map(Some(hash_key));
+        for key in keys.iter() {
+            assert_eq!(keyed.bucket_ix(key), reopened.bucket_ix(key));
+            keyed.update(key, |_| Some((vec![1], 0)));
+            assert_eq!(keyed.read_value(key), Some((vec![1], 0)));
+        }
+    }
+
+    #[test]
+    fn bucket_map_test_would_block() {
+        let index = BucketMap::new(BucketMapConfig::new(1 << 1));
+        let key = Pubkey::new_unique();
+        let ix = index.bucket_ix(&key);
+        index.insert(ix, &key, (&[0u64; 16], 0));
+        assert!(!index.would_block(ix, u64::MAX));
+
+        let index = BucketMap::new(BucketMapConfig {
+            throttle: Some(ThrottleConfig {
+                bytes_per_sec: 1 << 20,
+                grows_per_sec: 1 << 10,
+            }),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        assert!(!index.would_block(ix, 1024));
+        let value = vec![0u64; 1 << 17];
+        index.insert(ix, &key, (&value, 0));
+        assert!(index.would_block(ix, 1024));
+        assert!(!index.would_block(ix ^ 1, 1024));
+    }
+
+    #[test]
+    fn bucket_map_test_export_debug() {
+        let index = BucketMap::<u64>::new(BucketMapConfig::new(1 << 2));
+        let mut keys = (0..20).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            index.update(key, |_| Some((vec![0x0102, 3], 4)));
+        }
+        let empty = Pubkey::new_unique();
+        index.update(&empty, |_| Some((vec![], 5)));
+        keys.push(empty);
+
+        let mut csv = vec![];
+        index.export_debug(&mut csv, ExportFormat::Csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), keys.len() + 1);
+        assert_eq!(lines[0], "pubkey,ref_count,value");
+        for line in lines.iter().skip(1) {
+            let key = keys
+                .iter()
+                .find(|key| line.starts_with(&key.to_string()))
+                .unwrap();
+            if key == &empty {
+                assert_eq!(*line, format!("{},5,", key));
+            } else {
+                assert_eq!(*line, format!("{},4,02010000000000000300000000000000", key));
             }
         }
+
+        let mut json = vec![];
+        index
+            .export_debug(&mut json, ExportFormat::JsonLines)
+            .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(json.lines().count(), keys.len());
+        assert!(json.contains(&format!(
+            r#"{{"pubkey":"{}","ref_count":5,"value":""}}"#,
+            empty
+        )));
+    }
+
+    #[test]
+    fn bucket_map_test_op_stats() {
+        let config = BucketMapConfig::new(1 << 1);
+        let index = BucketMap::new(config);
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![0], 0)));
+        index.insert(index.bucket_ix(&key), &key, (&[1], 0));
+        assert_eq!(index.read_value(&key), Some((vec![1], 0)));
+        assert_eq!(index.read_value(&Pubkey::new_unique()), None);
+        index.delete_key(&key);
+
+        let stats = index.stats_snapshot();
+        assert_eq!(stats.update.count, 1);
+        assert_eq!(stats.insert.count, 1);
+        assert_eq!(stats.read.count, 2);
+        assert_eq!(stats.delete.count, 1);
+        // the first write into a new bucket has to create the data storage
+        assert!(stats.grow.count >= 1);
+        assert!(stats.grow.max_us <= stats.grow.total_us);
+
+        // every operation is counted in the shard of the key's bucket
+        let ix = index.bucket_ix(&key);
+        assert_eq!(stats.per_bucket.len(), 2);
+        assert_eq!(stats.per_bucket[ix].update.count, 1);
+        assert_eq!(stats.per_bucket[ix].insert.count, 1);
+        assert_eq!(stats.per_bucket[ix].delete.count, 1);
+        assert_eq!(stats.per_bucket[ix].grow, stats.grow);
+        assert_eq!(stats.per_bucket[1 - ix].update.count, 0);
+        assert_eq!(
+            stats.per_bucket[0].read.add(&stats.per_bucket[1].read),
+            stats.read
+        );
+    }
+
+    #[test]
+    fn bucket_map_test_group_flush() {
+        let index = Arc::new(BucketMap::new(BucketMapConfig::new(1 << 2)));
+        index.flush().unwrap();
+        assert_eq!(index.stats_snapshot().sync.count, 1);
+
+        let threads = 16;
+        let handles = (0..threads)
+            .map(|_| {
+                let index = Arc::clone(&index);
+                std::thread::spawn(move || {
+                    for i in 0..10 {
+                        let key = Pubkey::new_unique();
+                        index.update(&key, |_| Some((vec![i], 1)));
+                        index.flush().unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        let passes = index.stats_snapshot().sync.count;
+        assert!(passes > 1 && passes <= 1 + threads * 10);
+        assert!(index
+            .dirty
+            .iter()
+            .all(|dirty| !dirty.load(Ordering::Relaxed)));
     }
 
     #[test]
@@ -1,5 +1,15 @@
+use crate::clock::{Clock, SystemClock};
+use crate::trace::TraceId;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::sync::{atomic::AtomicU64, Mutex};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+
+/// Window the operation rates of BucketMapStats are averaged over by default
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Default)]
 pub struct BucketStats {
@@ -9,10 +19,373 @@ pub struct BucketStats {
     pub new_file_us: AtomicU64,
     pub flush_file_us: AtomicU64,
     pub mmap_us: AtomicU64,
+    /// files taken from the scratch pool instead of being created
+    pub scratch_reuses: AtomicU64,
+}
+
+impl BucketStats {
+    pub fn snapshot(&self) -> BucketStatsSnapshot {
+        BucketStatsSnapshot {
+            resizes: self.resizes.load(Ordering::Relaxed),
+            max_size: *self.max_size.lock().unwrap(),
+            resize_us: self.resize_us.load(Ordering::Relaxed),
+            new_file_us: self.new_file_us.load(Ordering::Relaxed),
+            flush_file_us: self.flush_file_us.load(Ordering::Relaxed),
+            mmap_us: self.mmap_us.load(Ordering::Relaxed),
+            scratch_reuses: self.scratch_reuses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// count and elapsed time of one kind of operation
+#[derive(Debug, Default)]
+pub struct OpStats {
+    pub count: AtomicU64,
+    pub total_us: AtomicU64,
+    pub max_us: AtomicU64,
+    /// trace id of the last operation that took max_us, if it was traced
+    pub max_trace_id: Mutex<Option<TraceId>>,
+}
+
+impl OpStats {
+    pub fn update(&self, elapsed_us: u64) {
+        self.update_traced(elapsed_us, None);
+    }
+
+    pub fn update_traced(&self, elapsed_us: u64, trace_id: Option<TraceId>) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        // only an operation at the maximum takes the lock
+        if self.max_us.fetch_max(elapsed_us, Ordering::Relaxed) <= elapsed_us {
+            *self.max_trace_id.lock().unwrap() = trace_id;
+        }
+    }
+
+    pub fn snapshot(&self) -> OpStatsSnapshot {
+        OpStatsSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+            max_trace_id: *self.max_trace_id.lock().unwrap(),
+        }
+    }
+}
+
+/// Operation counters of a single bucket.
+/// Aligned so the counters of neighbouring buckets never share a cache line (or the pair of lines
+/// adjacent line prefetching pulls in), which keeps threads working on different buckets from
+/// contending on them.
+#[repr(align(128))]
+#[derive(Debug, Default)]
+pub struct BucketOpStats {
+    pub insert: OpStats,
+    pub update: OpStats,
+    pub read: OpStats,
+    pub delete: OpStats,
+    pub grow: OpStats,
+}
+
+impl BucketOpStats {
+    pub fn snapshot(&self) -> BucketOpStatsSnapshot {
+        BucketOpStatsSnapshot {
+            insert: self.insert.snapshot(),
+            update: self.update.snapshot(),
+            read: self.read.snapshot(),
+            delete: self.delete.snapshot(),
+            grow: self.grow.snapshot(),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct BucketMapStats {
     pub index: Arc<BucketStats>,
     pub data: Arc<BucketStats>,
+    /// operations that aren't counted in a bucket's shard, e.g. those of a KvIndex
+    pub insert: Arc<OpStats>,
+    pub update: Arc<OpStats>,
+    pub read: Arc<OpStats>,
+    pub delete: Arc<OpStats>,
+    pub grow: Arc<OpStats>,
+    /// one per msync pass, which may cover many BucketMap::flush callers
+    pub sync: Arc<OpStats>,
+    /// operation counters of every bucket of a BucketMap
+    pub per_bucket: Vec<Arc<BucketOpStats>>,
+    pub priority: Arc<PriorityStats>,
+    pub rates: Arc<OpRates>,
+}
+
+impl BucketMapStats {
+    /// Stats with a shard of operation counters for each of `num_buckets` buckets
+    pub fn new(num_buckets: usize) -> Self {
+        Self::new_with_clock(num_buckets, Arc::new(SystemClock), DEFAULT_RATE_WINDOW)
+    }
+
+    /// Same as new, with rates averaged over `rate_window` of `clock`
+    pub fn new_with_clock(
+        num_buckets: usize,
+        clock: Arc<dyn Clock>,
+        rate_window: Duration,
+    ) -> Self {
+        Self {
+            per_bucket: (0..num_buckets)
+                .map(|_| Arc::new(BucketOpStats::default()))
+                .collect(),
+            rates: Arc::new(OpRates::new(clock, rate_window)),
+            ..Self::default()
+        }
+    }
+
+    /// Count an operation that took `elapsed_us` in `op`, one of these stats' counters, and
+    /// tick. Every read and write path records itself through here so the rates see it.
+    pub fn record(&self, op: &OpStats, elapsed_us: u64, trace_id: Option<TraceId>) {
+        op.update_traced(elapsed_us, trace_id);
+        self.tick();
+    }
+
+    /// Sample the operation counts for the rates, if no sample was taken this second.
+    /// Called after every operation, see record.
+    pub fn tick(&self) {
+        self.rates.tick(|| {
+            let count = |shared: &OpStats, shard: fn(&BucketOpStats) -> &OpStats| {
+                self.per_bucket
+                    .iter()
+                    .fold(shared.count.load(Ordering::Relaxed), |total, stats| {
+                        total + shard(stats).count.load(Ordering::Relaxed)
+                    })
+            };
+            OpCounts {
+                insert: count(&self.insert, |stats| &stats.insert),
+                update: count(&self.update, |stats| &stats.update),
+                read: count(&self.read, |stats| &stats.read),
+                delete: count(&self.delete, |stats| &stats.delete),
+            }
+        });
+    }
+
+    /// The operation counts of the shards are added into the totals
+    pub fn snapshot(&self) -> BucketMapStatsSnapshot {
+        let per_bucket = self
+            .per_bucket
+            .iter()
+            .map(|stats| stats.snapshot())
+            .collect::<Vec<_>>();
+        let total = |shared: &OpStats, shard: fn(&BucketOpStatsSnapshot) -> OpStatsSnapshot| {
+            per_bucket
+                .iter()
+                .fold(shared.snapshot(), |total, stats| total.add(&shard(stats)))
+        };
+        let insert = total(&self.insert, |stats| stats.insert);
+        let update = total(&self.update, |stats| stats.update);
+        let read = total(&self.read, |stats| stats.read);
+        let delete = total(&self.delete, |stats| stats.delete);
+        let counts = OpCounts {
+            insert: insert.count,
+            update: update.count,
+            read: read.count,
+            delete: delete.count,
+        };
+        self.rates.tick(|| counts);
+        BucketMapStatsSnapshot {
+            index: self.index.snapshot(),
+            data: self.data.snapshot(),
+            insert,
+            update,
+            read,
+            delete,
+            grow: total(&self.grow, |stats| stats.grow),
+            sync: self.sync.snapshot(),
+            priority: self.priority.snapshot(),
+            rates: self.rates.snapshot(counts),
+            bucket_entries: vec![],
+            per_bucket,
+        }
+    }
+}
+
+/// How often the priority lane of critical reads kicked in, see priority.rs
+#[derive(Debug, Default)]
+pub struct PriorityStats {
+    /// reads with BucketMap::read_value_critical
+    pub critical_reads: AtomicU64,
+    /// times a writer or background scan waited for the critical reads of its bucket
+    pub yields: AtomicU64,
+}
+
+impl PriorityStats {
+    pub fn snapshot(&self) -> PriorityStatsSnapshot {
+        PriorityStatsSnapshot {
+            critical_reads: self.critical_reads.load(Ordering::Relaxed),
+            yields: self.yields.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PriorityStatsSnapshot {
+    pub critical_reads: u64,
+    pub yields: u64,
+}
+
+/// Number of operations of each kind so far
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OpCounts {
+    pub insert: u64,
+    pub update: u64,
+    pub read: u64,
+    pub delete: u64,
+}
+
+/// Samples of the operation counts, at most one per second of the clock, covering the last
+/// window. The rates are the counts since the oldest sample over the time since it was taken.
+#[derive(Debug)]
+pub struct OpRates {
+    clock: Arc<dyn Clock>,
+    window: Duration,
+    origin: Instant,
+    // whole seconds from origin to the newest sample
+    last_sample: AtomicU64,
+    samples: Mutex<VecDeque<(Instant, OpCounts)>>,
+}
+
+impl Default for OpRates {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock), DEFAULT_RATE_WINDOW)
+    }
+}
+
+impl OpRates {
+    pub fn new(clock: Arc<dyn Clock>, window: Duration) -> Self {
+        assert!(
+            window > Duration::from_secs(0),
+            "rate window must be non-zero"
+        );
+        let origin = clock.now();
+        Self {
+            clock,
+            window,
+            origin,
+            last_sample: AtomicU64::default(),
+            samples: Mutex::new(VecDeque::from(vec![(origin, OpCounts::default())])),
+        }
+    }
+
+    /// Add a sample of `counts` unless one was taken in the current second
+    fn tick<F>(&self, counts: F)
+    where
+        F: FnOnce() -> OpCounts,
+    {
+        let now = self.clock.now();
+        let second = now.saturating_duration_since(self.origin).as_secs();
+        if self.last_sample.load(Ordering::Relaxed) == second
+            || self.last_sample.swap(second, Ordering::Relaxed) == second
+        {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, counts()));
+        while matches!(samples.front(), Some((at, _)) if now.saturating_duration_since(*at) > self.window)
+        {
+            samples.pop_front();
+        }
+    }
+
+    /// Rates given the current `counts`
+    fn snapshot(&self, counts: OpCounts) -> OpRatesSnapshot {
+        let now = self.clock.now();
+        let samples = self.samples.lock().unwrap();
+        let (at, before) = samples
+            .iter()
+            .find(|(at, _)| now.saturating_duration_since(*at) <= self.window)
+            .copied()
+            .unwrap_or((now, counts));
+        let elapsed = now.saturating_duration_since(at).as_secs_f64();
+        let rate = |now: u64, before: u64| {
+            if elapsed > 0.0 {
+                now.saturating_sub(before) as f64 / elapsed
+            } else {
+                0.0
+            }
+        };
+        OpRatesSnapshot {
+            window: self.window,
+            insert: rate(counts.insert, before.insert),
+            update: rate(counts.update, before.update),
+            read: rate(counts.read, before.read),
+            delete: rate(counts.delete, before.delete),
+        }
+    }
+}
+
+/// Operations per second over at most the last `window`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OpRatesSnapshot {
+    pub window: Duration,
+    pub insert: f64,
+    pub update: f64,
+    pub read: f64,
+    pub delete: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BucketStatsSnapshot {
+    pub resizes: u64,
+    pub max_size: u64,
+    pub resize_us: u64,
+    pub new_file_us: u64,
+    pub flush_file_us: u64,
+    pub mmap_us: u64,
+    pub scratch_reuses: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OpStatsSnapshot {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+    pub max_trace_id: Option<TraceId>,
+}
+
+impl OpStatsSnapshot {
+    /// Combined stats of the operations of `self` and `other`
+    pub fn add(&self, other: &Self) -> Self {
+        Self {
+            count: self.count + other.count,
+            total_us: self.total_us + other.total_us,
+            max_us: self.max_us.max(other.max_us),
+            max_trace_id: if other.max_us >= self.max_us {
+                other.max_trace_id
+            } else {
+                self.max_trace_id
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BucketOpStatsSnapshot {
+    pub insert: OpStatsSnapshot,
+    pub update: OpStatsSnapshot,
+    pub read: OpStatsSnapshot,
+    pub delete: OpStatsSnapshot,
+    pub grow: OpStatsSnapshot,
+}
+
+/// point in time copy of BucketMapStats
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BucketMapStatsSnapshot {
+    pub index: BucketStatsSnapshot,
+    pub data: BucketStatsSnapshot,
+    pub insert: OpStatsSnapshot,
+    pub update: OpStatsSnapshot,
+    pub read: OpStatsSnapshot,
+    pub delete: OpStatsSnapshot,
+    pub grow: OpStatsSnapshot,
+    pub sync: OpStatsSnapshot,
+    pub priority: PriorityStatsSnapshot,
+    pub rates: OpRatesSnapshot,
+    /// number of entries in every bucket, filled in by BucketMap::stats_snapshot
+    pub bucket_entries: Vec<u64>,
+    /// operation counts of every bucket, whose sums are in the totals above
+    pub per_bucket: Vec<BucketOpStatsSnapshot>,
 }
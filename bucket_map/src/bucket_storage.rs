@@ -1,15 +1,23 @@
 use crate::bucket_stats::BucketStats;
+use crate::fail_points::{FailPointOp, FailPoints};
+use crate::layout::{CellLayout, HEADER_ALIGN, HEADER_BYTES};
+use crate::progress::{ProgressCallback, ProgressOperation, PROGRESS_INTERVAL_CELLS};
+use crate::scratch_pool::ScratchPool;
 use crate::MaxSearch;
 use memmap2::MmapMut;
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use solana_measure::measure::Measure;
-use std::fs::{remove_file, OpenOptions};
+use std::fs::{remove_file, File, OpenOptions};
+use std::io;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
-use std::path::PathBuf;
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 /*
 1	2
@@ -29,7 +37,7 @@ use std::sync::Arc;
 23  8,388,608
 24  16,777,216
 */
-const DEFAULT_CAPACITY_POW2: u8 = 5;
+pub(crate) const DEFAULT_CAPACITY_POW2: u8 = 5;
 
 /// A Header UID of 0 indicates that the header is unlocked
 pub(crate) const UID_UNLOCKED: Uid = 0;
@@ -56,15 +64,33 @@ impl Header {
     }
 }
 
+// the layout of files depends on these
+const _: [(); HEADER_BYTES as usize] = [(); std::mem::size_of::<Header>()];
+const _: [(); HEADER_ALIGN as usize] = [(); std::mem::align_of::<Header>()];
+
 pub struct BucketStorage {
     drives: Arc<Vec<PathBuf>>,
     path: PathBuf,
-    mmap: MmapMut,
+    // only dropped in Drop, which may hand it to the scratch pool
+    mmap: ManuallyDrop<MmapMut>,
     pub cell_size: u64,
+    layout: CellLayout,
+    num_elems: u64,
     pub capacity_pow2: u8,
     pub used: AtomicU64,
     pub stats: Arc<BucketStats>,
     pub max_search: MaxSearch,
+    /// one bit per cell, set for cells marked reachable during a gc pass, empty otherwise
+    gc_marks: Vec<AtomicU64>,
+    /// cells that were freed and can be handed out again without searching for an unused cell
+    free_list: Mutex<Vec<u64>>,
+    /// picks the drive and file name of every file this storage creates
+    rng: StdRng,
+    fail_points: Option<Arc<FailPoints>>,
+    /// where files this storage no longer needs go, and new ones come from
+    scratch: Option<Arc<ScratchPool>>,
+    /// leave the file on disk when dropped, see keep_file
+    keep_file: bool,
 }
 
 #[derive(Debug)]
@@ -74,52 +100,84 @@ pub enum BucketStorageError {
 
 impl Drop for BucketStorage {
     fn drop(&mut self) {
-        let _ = remove_file(&self.path);
+        // not used after this
+        let mmap = unsafe { ManuallyDrop::take(&mut self.mmap) };
+        let path = std::mem::take(&mut self.path);
+        if self.keep_file {
+            return;
+        }
+        if let Some((mmap, path)) = Self::retire(self.scratch.as_deref(), mmap, path) {
+            drop(mmap);
+            let _ = remove_file(path);
+        }
     }
 }
 
 impl BucketStorage {
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_capacity(
         drives: Arc<Vec<PathBuf>>,
+        layout: CellLayout,
         num_elems: u64,
-        elem_size: u64,
         capacity_pow2: u8,
         max_search: MaxSearch,
         mut stats: Arc<BucketStats>,
-    ) -> Self {
-        let cell_size = elem_size * num_elems + std::mem::size_of::<Header>() as u64;
-        let (mmap, path) = Self::new_map(&drives, cell_size as usize, capacity_pow2, &mut stats);
-        Self {
+        seed: u64,
+        fail_points: Option<Arc<FailPoints>>,
+        scratch: Option<Arc<ScratchPool>>,
+    ) -> io::Result<Self> {
+        let cell_size = layout.cell_bytes(num_elems);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (mmap, path) = Self::new_map(
+            &drives,
+            cell_size as usize,
+            capacity_pow2,
+            &mut stats,
+            &mut rng,
+            fail_points.as_deref(),
+            scratch.as_deref(),
+        )?;
+        Ok(Self {
             path,
-            mmap,
+            mmap: ManuallyDrop::new(mmap),
             drives,
             cell_size,
+            layout,
+            num_elems,
             used: AtomicU64::new(0),
             capacity_pow2,
             stats,
             max_search,
+            gc_marks: Vec::new(),
+            free_list: Mutex::default(),
+            rng,
+            fail_points,
+            scratch,
+            keep_file: false,
+        })
+    }
+
+    /// Hand a mapped file that is no longer used to `scratch`.
+    /// Returns it back if it wasn't taken, then it has to be removed.
+    fn retire(
+        scratch: Option<&ScratchPool>,
+        mmap: MmapMut,
+        path: PathBuf,
+    ) -> Option<(MmapMut, PathBuf)> {
+        match scratch {
+            Some(scratch) => scratch.put(mmap, path),
+            None => Some((mmap, path)),
         }
     }
 
-    pub fn max_search(&self) -> u64 {
-        self.max_search as u64
+    /// Return the smallest power of two number of cells of `cell_size` that can hold `bytes`
+    pub fn capacity_pow2_for_bytes(bytes: u64, cell_size: u64) -> u8 {
+        let cells = std::cmp::max(1, (bytes + cell_size - 1) / cell_size);
+        cells.next_power_of_two().trailing_zeros() as u8
     }
 
-    pub fn new(
-        drives: Arc<Vec<PathBuf>>,
-        num_elems: u64,
-        elem_size: u64,
-        max_search: MaxSearch,
-        stats: Arc<BucketStats>,
-    ) -> Self {
-        Self::new_with_capacity(
-            drives,
-            num_elems,
-            elem_size,
-            DEFAULT_CAPACITY_POW2,
-            max_search,
-            stats,
-        )
+    pub fn max_search(&self) -> u64 {
+        self.max_search as u64
     }
 
     pub fn uid(&self, ix: u64) -> Uid {
@@ -147,7 +205,8 @@ impl BucketStorage {
         let hdr_slice: &[u8] = &self.mmap[ix..ix + std::mem::size_of::<Header>()];
         unsafe {
             let hdr = hdr_slice.as_ptr() as *const Header;
-            if hdr.as_ref().unwrap().try_lock(uid) {
+            let hdr = hdr.as_ref().unwrap();
+            if hdr.try_lock(uid) {
                 e = Ok(());
                 self.used.fetch_add(1, Ordering::Relaxed);
             }
@@ -178,11 +237,82 @@ impl BucketStorage {
         }
     }
 
+    /// Remember that the cell at `ix` was freed so a later allocation can reuse it
+    pub fn recycle(&self, ix: u64) {
+        self.free_list.lock().unwrap().push(ix);
+    }
+
+    /// Allocate a previously freed cell for `uid`.
+    /// Returns None if there are no freed cells left to reuse.
+    pub fn allocate_recycled(&self, uid: Uid) -> Option<u64> {
+        let mut free_list = self.free_list.lock().unwrap();
+        while let Some(ix) = free_list.pop() {
+            // the cell may have been allocated by a search since it was freed
+            if self.allocate(ix, uid).is_ok() {
+                return Some(ix);
+            }
+        }
+        None
+    }
+
+    fn header(&self, ix: u64) -> &Header {
+        if ix >= self.capacity() {
+            panic!("bad index size");
+        }
+        let ix = (ix * self.cell_size) as usize;
+        let hdr_slice: &[u8] = &self.mmap[ix..ix + std::mem::size_of::<Header>()];
+        unsafe {
+            let hdr = hdr_slice.as_ptr() as *const Header;
+            hdr.as_ref().unwrap()
+        }
+    }
+
+    /// Mark the allocated cell at `ix` as reachable in the current gc pass
+    pub fn mark(&self, ix: u64) {
+        self.gc_marks[(ix / 64) as usize].fetch_or(1 << (ix % 64), Ordering::Relaxed);
+    }
+
+    /// Start a gc pass. Every allocated cell is unreachable until marked.
+    /// The marks are kept in memory, one bit per cell, until free_unmarked.
+    pub fn start_gc_generation(&mut self) {
+        self.gc_marks = (0..self.gc_mark_words())
+            .map(|_| AtomicU64::default())
+            .collect();
+    }
+
+    // the capacity is a power of two
+    fn gc_mark_words(&self) -> u64 {
+        std::cmp::max(1, self.capacity() / 64)
+    }
+
+    /// Free every allocated cell that was not marked reachable in the current gc pass, and end
+    /// the pass. Returns the number of bytes reclaimed.
+    pub fn free_unmarked(&mut self) -> u64 {
+        let marks = std::mem::take(&mut self.gc_marks);
+        assert_eq!(
+            marks.len() as u64,
+            self.gc_mark_words(),
+            "free_unmarked without start_gc_generation"
+        );
+        let mut reclaimed = 0;
+        for ix in 0..self.capacity() {
+            let marked = marks[(ix / 64) as usize].load(Ordering::Relaxed) & (1 << (ix % 64)) != 0;
+            let hdr = self.header(ix);
+            if hdr.uid() != UID_UNLOCKED && !marked {
+                hdr.unlock();
+                self.used.fetch_sub(1, Ordering::Relaxed);
+                self.recycle(ix);
+                reclaimed += self.cell_size;
+            }
+        }
+        reclaimed
+    }
+
     pub fn get<T: Sized>(&self, ix: u64) -> &T {
         if ix >= self.capacity() {
             panic!("bad index size");
         }
-        let start = (ix * self.cell_size) as usize + std::mem::size_of::<Header>();
+        let start = (ix * self.cell_size + self.layout.element_offset) as usize;
         let end = start + std::mem::size_of::<T>();
         let item_slice: &[u8] = &self.mmap[start..end];
         unsafe {
@@ -204,8 +334,7 @@ impl BucketStorage {
         if ix >= self.capacity() {
             panic!("bad index size");
         }
-        let ix = self.cell_size * ix;
-        let start = ix as usize + std::mem::size_of::<Header>();
+        let start = (self.cell_size * ix + self.layout.element_offset) as usize;
         let end = start + std::mem::size_of::<T>() * len as usize;
         //debug!("GET slice {} {}", start, end);
         let item_slice: &[u8] = &self.mmap[start..end];
@@ -215,12 +344,32 @@ impl BucketStorage {
         }
     }
 
+    /// The metadata in front of the elements of the cell at `ix`, see CellLayout::with_prefix
+    pub fn get_prefix<T: Sized>(&self, ix: u64) -> &T {
+        self.get_prefix_mut(ix)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn get_prefix_mut<T: Sized>(&self, ix: u64) -> &mut T {
+        if ix >= self.capacity() {
+            panic!("bad index size");
+        }
+        assert!(std::mem::size_of::<T>() as u64 <= self.layout.prefix_bytes);
+        let start = (ix * self.cell_size + HEADER_BYTES) as usize;
+        let end = start + std::mem::size_of::<T>();
+        let item_slice: &[u8] = &self.mmap[start..end];
+        unsafe {
+            let item = item_slice.as_ptr() as *mut T;
+            &mut *item
+        }
+    }
+
     #[allow(clippy::mut_from_ref)]
     pub fn get_mut<T: Sized>(&self, ix: u64) -> &mut T {
         if ix >= self.capacity() {
             panic!("bad index size");
         }
-        let start = (ix * self.cell_size) as usize + std::mem::size_of::<Header>();
+        let start = (ix * self.cell_size + self.layout.element_offset) as usize;
         let end = start + std::mem::size_of::<T>();
         let item_slice: &[u8] = &self.mmap[start..end];
         unsafe {
@@ -234,8 +383,7 @@ impl BucketStorage {
         if ix >= self.capacity() {
             panic!("bad index size");
         }
-        let ix = self.cell_size * ix;
-        let start = ix as usize + std::mem::size_of::<Header>();
+        let start = (self.cell_size * ix + self.layout.element_offset) as usize;
         let end = start + std::mem::size_of::<T>() * len as usize;
         //debug!("GET mut slice {} {}", start, end);
         let item_slice: &[u8] = &self.mmap[start..end];
@@ -250,42 +398,65 @@ impl BucketStorage {
         cell_size: usize,
         capacity_pow2: u8,
         stats: &mut Arc<BucketStats>,
-    ) -> (MmapMut, PathBuf) {
-        let mut measure_new_file = Measure::start("measure_new_file");
+        rng: &mut StdRng,
+        fail_points: Option<&FailPoints>,
+        scratch: Option<&ScratchPool>,
+    ) -> io::Result<(MmapMut, PathBuf)> {
+        let measure_new_file = Measure::start("measure_new_file");
         let capacity = 1u64 << capacity_pow2;
-        let r = thread_rng().gen_range(0, drives.len());
+        if let Some(region) = scratch.and_then(|scratch| scratch.take(capacity * cell_size as u64))
+        {
+            stats.scratch_reuses.fetch_add(1, Ordering::Relaxed);
+            return Ok(region);
+        }
+        let r = rng.gen_range(0, drives.len());
         let drive = &drives[r];
-        let pos = format!("{}", thread_rng().gen_range(0, u128::MAX),);
+        let pos = format!("{}", rng.gen_range(0, u128::MAX),);
         let file = drive.join(pos);
         let mut data = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(file.clone())
-            .map_err(|e| {
-                panic!(
-                    "Unable to create data file {} in current dir({:?}): {:?}",
-                    file.display(),
-                    std::env::current_dir(),
-                    e
-                );
-            })
-            .unwrap();
+            .open(file.clone())?;
+        match Self::map_file(
+            &mut data,
+            capacity * cell_size as u64,
+            measure_new_file,
+            stats,
+            fail_points,
+        ) {
+            Ok(mmap) => Ok((mmap, file)),
+            Err(err) => {
+                // don't leave a half created file behind
+                let _ = remove_file(&file);
+                Err(err)
+            }
+        }
+    }
 
+    /// Size the newly created `data` to `len` bytes and map it
+    fn map_file(
+        data: &mut File,
+        len: u64,
+        mut measure_new_file: Measure,
+        stats: &BucketStats,
+        fail_points: Option<&FailPoints>,
+    ) -> io::Result<MmapMut> {
         // Theoretical performance optimization: write a zero to the end of
         // the file so that we won't have to resize it later, which may be
         // expensive.
-        //debug!("GROWING file {}", capacity * cell_size as u64);
-        data.seek(SeekFrom::Start(capacity * cell_size as u64 - 1))
-            .unwrap();
-        data.write_all(&[0]).unwrap();
-        data.seek(SeekFrom::Start(0)).unwrap();
+        //debug!("GROWING file {}", len);
+        data.seek(SeekFrom::Start(len - 1))?;
+        FailPoints::check(fail_points, FailPointOp::Write)?;
+        data.write_all(&[0])?;
+        data.seek(SeekFrom::Start(0))?;
         measure_new_file.stop();
         let mut measure_flush = Measure::start("measure_flush");
-        data.flush().unwrap(); // can we skip this?
+        data.flush()?; // can we skip this?
         measure_flush.stop();
         let mut measure_mmap = Measure::start("measure_mmap");
-        let res = (unsafe { MmapMut::map_mut(&data).unwrap() }, file);
+        FailPoints::check(fail_points, FailPointOp::Mmap)?;
+        let mmap = unsafe { MmapMut::map_mut(&*data)? };
         measure_mmap.stop();
         stats
             .new_file_us
@@ -296,24 +467,39 @@ impl BucketStorage {
         stats
             .mmap_us
             .fetch_add(measure_mmap.as_us(), Ordering::Relaxed);
-        res
+        Ok(mmap)
     }
 
-    pub fn grow(&mut self) {
+    /// Grow to 2^`increment` times the current capacity.
+    /// On error the storage is left as it was.
+    pub fn grow(&mut self, increment: u8, progress: Option<&ProgressCallback>) -> io::Result<()> {
+        FailPoints::check(self.fail_points.as_deref(), FailPointOp::Grow)?;
         let mut m = Measure::start("grow");
         let old_cap = self.capacity();
         let old_map = &self.mmap;
         let old_file = self.path.clone();
 
-        let increment = 1;
         let index_grow = 1 << increment;
         let (new_map, new_file) = Self::new_map(
             &self.drives,
             self.cell_size as usize,
             self.capacity_pow2 + increment,
             &mut self.stats,
-        );
+            &mut self.rng,
+            self.fail_points.as_deref(),
+            self.scratch.as_deref(),
+        )?;
+        let total_bytes = old_cap * self.cell_size;
         (0..old_cap as usize).into_iter().for_each(|i| {
+            if i as u64 % PROGRESS_INTERVAL_CELLS == 0 {
+                if let Some(progress) = progress {
+                    progress.report(
+                        ProgressOperation::GrowData,
+                        i as u64 * self.cell_size,
+                        total_bytes,
+                    );
+                }
+            }
             let old_ix = i * self.cell_size as usize;
             let new_ix = old_ix * index_grow;
             let dst_slice: &[u8] = &new_map[new_ix..new_ix + self.cell_size as usize];
@@ -325,10 +511,23 @@ impl BucketStorage {
                 std::ptr::copy_nonoverlapping(src, dst, self.cell_size as usize);
             };
         });
-        self.mmap = new_map;
+        // freed cells are still free after the grow, they just moved along with everything else
+        self.free_list
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|ix| *ix *= index_grow as u64);
+        if let Some(progress) = progress {
+            progress.report(ProgressOperation::GrowData, total_bytes, total_bytes);
+        }
+        let old_map = std::mem::replace(&mut *self.mmap, new_map);
         self.path = new_file;
         self.capacity_pow2 += increment;
-        remove_file(old_file).unwrap();
+        if let Some((old_map, old_file)) = Self::retire(self.scratch.as_deref(), old_map, old_file)
+        {
+            drop(old_map);
+            remove_file(old_file).unwrap();
+        }
         m.stop();
         let sz = 1 << self.capacity_pow2;
         {
@@ -337,10 +536,147 @@ impl BucketStorage {
         }
         self.stats.resizes.fetch_add(1, Ordering::Relaxed);
         self.stats.resize_us.fetch_add(m.as_us(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Move the file to one of `drives`, where every file the storage creates after this goes.
+    /// On error the storage is left as it was.
+    pub fn move_to(&mut self, drives: Arc<Vec<PathBuf>>) -> io::Result<()> {
+        // a file from the scratch pool could be on any drive
+        let (mut new_map, new_file) = Self::new_map(
+            &drives,
+            self.cell_size as usize,
+            self.capacity_pow2,
+            &mut self.stats,
+            &mut self.rng,
+            self.fail_points.as_deref(),
+            None,
+        )?;
+        new_map.copy_from_slice(&self.mmap);
+        let old_map = std::mem::replace(&mut *self.mmap, new_map);
+        let old_file = std::mem::replace(&mut self.path, new_file);
+        self.drives = drives;
+        if let Some((old_map, old_file)) = Self::retire(self.scratch.as_deref(), old_map, old_file)
+        {
+            drop(old_map);
+            remove_file(old_file).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Create an empty storage with the same cell size and capacity as this one
+    pub fn new_like(&self, stats: Arc<BucketStats>, seed: u64) -> io::Result<Self> {
+        Self::new_with_capacity(
+            Arc::clone(&self.drives),
+            self.layout,
+            self.num_elems,
+            self.capacity_pow2,
+            self.max_search,
+            stats,
+            seed,
+            self.fail_points.clone(),
+            self.scratch.clone(),
+        )
+    }
+
+    /// True if `other` has the same cell size and capacity, so cells can be copied between them
+    pub fn same_shape(&self, other: &BucketStorage) -> bool {
+        self.cell_size == other.cell_size && self.capacity_pow2 == other.capacity_pow2
+    }
+
+    /// Overwrite every cell with the cells of `other`, which has to have the same shape
+    pub fn copy_from(&mut self, other: &BucketStorage) {
+        assert!(self.same_shape(other));
+        self.mmap.copy_from_slice(&other.mmap);
+        self.used
+            .store(other.used.load(Ordering::Relaxed), Ordering::Relaxed);
     }
 
     /// Return the number of cells currently allocated
     pub fn capacity(&self) -> u64 {
         1 << self.capacity_pow2
     }
+
+    /// msync the dirty pages of the file to disk
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.mmap.flush()
+    }
+
+    /// Leave the file on disk instead of removing it or retiring it to the scratch pool when
+    /// this storage is dropped. Returns the path of the file.
+    pub fn keep_file(&mut self) -> &Path {
+        self.keep_file = true;
+        &self.path
+    }
+
+    /// Hint to the kernel that the cells in `range` will be read soon, so their pages can be
+    /// paged in asynchronously instead of faulting them in one at a time.
+    pub fn prefetch(&self, range: Range<u64>) {
+        let end = std::cmp::min(range.end, self.capacity());
+        if range.start >= end {
+            return;
+        }
+        let start = (range.start * self.cell_size) as usize;
+        let end = (end * self.cell_size) as usize;
+        Self::advise_will_need(&self.mmap, start, end);
+    }
+
+    #[cfg(unix)]
+    fn advise_will_need(mmap: &MmapMut, start: usize, end: usize) {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let base = mmap.as_ptr() as usize;
+        // madvise requires a page aligned address. The mmap itself is page aligned.
+        let aligned_start = (base + start) & !(page_size - 1);
+        let len = base + end - aligned_start;
+        unsafe {
+            // this is only a hint, so failure is not interesting
+            libc::madvise(aligned_start as *mut libc::c_void, len, libc::MADV_WILLNEED);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn advise_will_need(_mmap: &MmapMut, _start: usize, _end: usize) {}
+
+    /// Size of the memory mapped file in bytes
+    pub fn mapped_bytes(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    /// Bytes of the file that are currently resident in memory
+    pub fn resident_bytes(&self) -> u64 {
+        Self::resident(&self.mmap)
+    }
+
+    /// Heap allocated by this storage outside of the mmap
+    pub fn heap_bytes(&self) -> u64 {
+        (self.free_list.lock().unwrap().capacity() * std::mem::size_of::<u64>()
+            + self.path.as_os_str().len()) as u64
+    }
+
+    #[cfg(unix)]
+    fn resident(mmap: &MmapMut) -> u64 {
+        if mmap.is_empty() {
+            return 0;
+        }
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let pages = (mmap.len() + page_size - 1) / page_size;
+        let mut residency = vec![0u8; pages];
+        let result = unsafe {
+            libc::mincore(
+                mmap.as_ptr() as *mut libc::c_void,
+                mmap.len(),
+                residency.as_mut_ptr() as *mut _,
+            )
+        };
+        if result != 0 {
+            return 0;
+        }
+        // the low bit of each byte is set if the page is resident
+        let resident_pages = residency.iter().filter(|page| *page & 1 == 1).count();
+        std::cmp::min(resident_pages * page_size, mmap.len()) as u64
+    }
+    #[cfg(not(unix))]
+    fn resident(_mmap: &MmapMut) -> u64 {
+        0
+    }
 }
@@ -0,0 +1,45 @@
+//! Cancellation of long running scans and maintenance.
+//! Tokens are checked between chunks of index cells, so a cancelled operation releases its
+//! bucket lock after at most one more chunk.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Number of index cells processed between checks of a CancelToken
+pub(crate) const CANCEL_CHECK_CELLS: u64 = 1024;
+
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+/// The operation was cancelled before it completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Err if `cancel` is set and cancelled, meant to be called once per chunk of cells
+    pub(crate) fn check(cancel: Option<&CancelToken>) -> Result<(), Cancelled> {
+        match cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Share an existing flag, such as the validator's exit flag
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        Self(flag)
+    }
+}
@@ -0,0 +1,92 @@
+//! Sizes from a previous run of a map.
+//! A map that is rebuilt with the same keys would otherwise rediscover the size of every
+//! bucket one grow at a time. Capture CapacityHints from the old map's stats_snapshot and hand
+//! them to BucketMapConfig::capacity_hints, and every bucket's index is created big enough.
+
+use crate::bucket_stats::BucketMapStatsSnapshot;
+
+/// An index is sized to be at most this full, so inserts rarely run out of free cells within
+/// max_search
+const MAX_LOAD_FACTOR_POW2: u8 = 1;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CapacityHints {
+    /// expected number of entries of every bucket, indexed by bucket
+    pub bucket_entries: Vec<u64>,
+}
+
+impl CapacityHints {
+    pub fn from_snapshot(snapshot: &BucketMapStatsSnapshot) -> Self {
+        Self {
+            bucket_entries: snapshot.bucket_entries.clone(),
+        }
+    }
+
+    /// Capacity of the index of bucket `ix`, as a power of two, that holds its expected entries.
+    /// Never smaller than `min_pow2`.
+    pub(crate) fn index_capacity_pow2(&self, ix: usize, min_pow2: u8) -> u8 {
+        let entries = self.bucket_entries[ix];
+        if entries == 0 {
+            return min_pow2;
+        }
+        let pow2 = (u64::BITS - (entries - 1).leading_zeros()) as u8 + MAX_LOAD_FACTOR_POW2;
+        std::cmp::max(pow2, min_pow2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_index_capacity_pow2() {
+        let hints = CapacityHints {
+            bucket_entries: vec![0, 1, 2, 3, 4, 1000, 1024, 1025],
+        };
+        let pow2s = (0..hints.bucket_entries.len())
+            .map(|ix| hints.index_capacity_pow2(ix, 0))
+            .collect::<Vec<_>>();
+        assert_eq!(pow2s, vec![0, 1, 2, 3, 3, 11, 11, 12]);
+        assert_eq!(hints.index_capacity_pow2(0, 5), 5);
+        assert_eq!(hints.index_capacity_pow2(5, 5), 11);
+    }
+
+    #[test]
+    fn test_capacity_hints() {
+        let config = BucketMapConfig::new(4);
+        let keys = (0..2000).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let map = BucketMap::new(config.clone());
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![0u64], 0)));
+        }
+        let snapshot = map.stats_snapshot();
+        assert!(snapshot.index.resizes > 0);
+        assert_eq!(snapshot.bucket_entries.iter().sum::<u64>(), 2000);
+        drop(map);
+
+        // a rebuild with the same keys creates every index at its final size
+        let map = BucketMap::new(BucketMapConfig {
+            capacity_hints: Some(CapacityHints::from_snapshot(&snapshot)),
+            ..config
+        });
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![0u64], 0)));
+        }
+        let rebuilt = map.stats_snapshot();
+        assert_eq!(rebuilt.index.resizes, 0);
+        assert_eq!(rebuilt.bucket_entries, snapshot.bucket_entries);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid BucketMapConfig: CapacityHintsMismatch")]
+    fn test_capacity_hints_wrong_buckets() {
+        BucketMap::<u64>::new(BucketMapConfig {
+            capacity_hints: Some(CapacityHints {
+                bucket_entries: vec![1, 2],
+            }),
+            ..BucketMapConfig::new(4)
+        });
+    }
+}
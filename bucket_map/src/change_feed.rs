@@ -0,0 +1,86 @@
+//! Notifications of writes to a map, so a follower can mirror the index without polling it.
+//! Hand the sending half of a channel to BucketMapConfig::change_feed and every write that
+//! changes an entry sends one Change. Changes are sent while the key's bucket is locked, so the
+//! changes of a bucket arrive in the order they were made. A full bounded channel holds up the
+//! writers of that bucket until the receiver catches up. Once the receiver is dropped, changes
+//! are discarded.
+
+use crate::trace::TraceId;
+use solana_sdk::pubkey::Pubkey;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// the key wasn't in the map before
+    Insert,
+    /// the value or ref count of a key that was in the map was written
+    Update,
+    /// the key was deleted
+    Delete,
+}
+
+/// The key written, how, and the trace id of the write if it was traced
+pub type Change = (Pubkey, ChangeKind, Option<TraceId>);
+
+impl ChangeKind {
+    /// Kind of a write that found a previous entry if `present`
+    pub(crate) fn of_write(present: bool) -> Self {
+        if present {
+            ChangeKind::Update
+        } else {
+            ChangeKind::Insert
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn test_change_feed() {
+        let (sender, receiver) = unbounded();
+        let map = BucketMap::<u64>::new(BucketMapConfig {
+            change_feed: Some(sender),
+            ..BucketMapConfig::new(4)
+        });
+        let key = Pubkey::new_unique();
+        let ix = map.bucket_ix(&key);
+        let missing = Pubkey::new_unique();
+
+        map.update(&key, |_| Some((vec![0], 0)));
+        map.update(&key, |_| Some((vec![1], 0)));
+        map.insert(ix, &key, (&[2], 1));
+        map.addref(&key);
+        map.unref_batch(&[key, missing]);
+        map.delete_key(&key);
+        map.try_insert(ix, &key, (&[], 0)).unwrap();
+        map.insert_batch(ix, &[(key, vec![], 0)]);
+        map.delete_keys(&[key, missing]);
+        // writes that don't change anything aren't sent
+        map.update(&missing, |_| None);
+        map.delete_key(&missing);
+        assert_eq!(map.addref(&missing), None);
+
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![
+                (key, ChangeKind::Insert, None),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Delete, None),
+                (key, ChangeKind::Insert, None),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Delete, None),
+            ]
+        );
+
+        // nobody listening anymore
+        drop(receiver);
+        map.update(&key, |_| Some((vec![0], 0)));
+        assert_eq!(map.read_value(&key), Some((vec![0], 0)));
+    }
+}
@@ -0,0 +1,109 @@
+//! A quick integrity check of a BucketMap, to decide whether an index restored from disk can be
+//! used as is or which buckets have to be repaired first.
+//! The check reads every header of each bucket's index, but follows only a sample of the entries
+//! into their data cells, so it pages in little more than the index files.
+
+/// Entries of each bucket followed into their data cells by BucketMap::quick_check
+pub const QUICK_CHECK_SAMPLES: u64 = 64;
+
+/// Something wrong with a bucket. `entry` is the index cell of the entry with the problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckProblem {
+    /// the number of used index cells doesn't match the bucket's count of entries
+    LenMismatch { counted: u64, recorded: u64 },
+    /// the header of the index cell doesn't belong to the key of the entry
+    IndexHeader { entry: u64 },
+    /// a lookup of the entry's key doesn't find the entry
+    Unreachable { entry: u64 },
+    /// the entry points at a data storage the bucket doesn't have
+    DataBucket { entry: u64 },
+    /// the entry points past the end of its data storage
+    DataOffset { entry: u64 },
+    /// the data cell the entry points at is free or belongs to another key
+    DataHeader { entry: u64 },
+    /// data cells are allocated that no entry points at
+    LeakedCells { cells: u64 },
+}
+
+impl CheckProblem {
+    /// Whether the index can't be trusted, rather than only wasting space
+    pub fn is_corruption(&self) -> bool {
+        !matches!(self, CheckProblem::LeakedCells { .. })
+    }
+}
+
+/// What to do about the problems of a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairAction {
+    /// Rebuild the index of the bucket, with BucketMap::rebuild_index if the map has
+    /// data_cell_keys, or else from wherever the entries came from
+    Rebuild(usize),
+    /// Free the leaked data cells of the bucket with BucketMap::gc_data
+    Compact(usize),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BucketCheck {
+    /// entries in the index
+    pub entries: u64,
+    /// entries followed into their data cells
+    pub sampled: u64,
+    pub problems: Vec<CheckProblem>,
+}
+
+impl BucketCheck {
+    pub(crate) fn action(&self, ix: usize) -> Option<RepairAction> {
+        if self.problems.iter().any(CheckProblem::is_corruption) {
+            Some(RepairAction::Rebuild(ix))
+        } else if self.problems.is_empty() {
+            None
+        } else {
+            Some(RepairAction::Compact(ix))
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CheckReport {
+    /// one per bucket, default for buckets that don't exist
+    pub buckets: Vec<BucketCheck>,
+    /// at most one per bucket, in bucket order. A rebuild also takes care of leaked cells.
+    pub actions: Vec<RepairAction>,
+}
+
+impl CheckReport {
+    /// true if no bucket needs a repair
+    pub fn is_ok(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_check_action() {
+        let check = |problems| BucketCheck {
+            entries: 10,
+            sampled: 10,
+            problems,
+        };
+        assert_eq!(check(vec![]).action(3), None);
+        let leaked = CheckProblem::LeakedCells { cells: 2 };
+        assert_eq!(
+            check(vec![leaked]).action(3),
+            Some(RepairAction::Compact(3))
+        );
+        assert_eq!(
+            check(vec![CheckProblem::DataOffset { entry: 1 }, leaked]).action(3),
+            Some(RepairAction::Rebuild(3))
+        );
+        assert!(!leaked.is_corruption());
+        assert!(CheckProblem::LenMismatch {
+            counted: 1,
+            recorded: 2
+        }
+        .is_corruption());
+    }
+}
@@ -0,0 +1,488 @@
+//! CoalescingIndex defers writes to a BucketMap.
+//! Writes land in an in-memory delta keyed by Pubkey and are folded into the map once the delta
+//! is older than the flush interval or larger than the byte budget, so repeated writes to a hot
+//! key only reach the mmap once.
+//! The delta is sharded like the map, one per bucket, so writers of different buckets don't
+//! contend and each shard is folded into its bucket on its own.
+
+use crate::bucket_item::BucketItem;
+use crate::bucket_map::{BucketMap, BucketMapError};
+use crate::bucket_stats::BucketMapStatsSnapshot;
+use crate::disk_index::DiskIndex;
+use crate::RefCount;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoalescingConfig {
+    /// fold the delta into the map once its oldest write is this old
+    pub flush_interval: Duration,
+    /// fold the delta into the map once it holds this many bytes
+    pub max_delta_bytes: usize,
+}
+
+/// pending writes of one bucket, None is a pending delete
+#[derive(Debug)]
+struct Delta<T> {
+    entries: HashMap<Pubkey, Option<(Vec<T>, RefCount)>>,
+    bytes: usize,
+    // time of the oldest write that is still pending
+    oldest_write: Option<Instant>,
+    // bumped by every write and fold, so a read of the map made without the lock can tell
+    // whether the delta changed meanwhile
+    version: u64,
+}
+
+impl<T> Default for Delta<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::default(),
+            bytes: 0,
+            oldest_write: None,
+            version: 0,
+        }
+    }
+}
+
+impl<T> Delta<T> {
+    fn entry_bytes(entry: &Option<(Vec<T>, RefCount)>) -> usize {
+        size_of::<Pubkey>()
+            + entry
+                .as_ref()
+                .map(|(value, _)| size_of::<RefCount>() + value.len() * size_of::<T>())
+                .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+pub struct CoalescingIndex<T: Clone + Copy + Debug> {
+    map: Arc<BucketMap<T>>,
+    config: CoalescingConfig,
+    // indexed by bucket ix
+    deltas: Vec<Mutex<Delta<T>>>,
+    // sum of the bytes of every delta
+    delta_bytes: AtomicUsize,
+    coalesced_writes: AtomicU64,
+}
+
+impl<T: Clone + Copy + Debug> CoalescingIndex<T> {
+    /// The flush interval is measured on the clock of `map`
+    pub fn new(map: Arc<BucketMap<T>>, config: CoalescingConfig) -> Self {
+        let mut deltas = Vec::with_capacity(map.num_buckets());
+        deltas.resize_with(map.num_buckets(), Mutex::default);
+        Self {
+            map,
+            config,
+            deltas,
+            delta_bytes: AtomicUsize::default(),
+            coalesced_writes: AtomicU64::default(),
+        }
+    }
+
+    pub fn map(&self) -> &Arc<BucketMap<T>> {
+        &self.map
+    }
+
+    /// Number of writes that replaced a pending write of the same key instead of reaching the map
+    pub fn coalesced_writes(&self) -> u64 {
+        self.coalesced_writes.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes waiting to be folded into the map
+    pub fn delta_bytes(&self) -> usize {
+        self.delta_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Fold every pending write into the map
+    pub fn flush(&self) {
+        for (ix, delta) in self.deltas.iter().enumerate() {
+            self.fold(ix, &mut delta.lock().unwrap());
+        }
+    }
+
+    /// Fold the pending writes of every bucket whose oldest pending write is at least the flush
+    /// interval old into the map
+    pub fn flush_if_due(&self) {
+        for (ix, delta) in self.deltas.iter().enumerate() {
+            let mut delta = delta.lock().unwrap();
+            if self.flush_due(&delta) {
+                self.fold(ix, &mut delta);
+            }
+        }
+    }
+
+    /// Call flush_if_due every flush interval until `exit` is set, then flush
+    pub fn spawn_flusher(index: Arc<Self>, exit: Arc<AtomicBool>) -> JoinHandle<()>
+    where
+        T: Send + Sync + 'static,
+    {
+        Builder::new()
+            .name("solana-bucket-map-coalesce".to_string())
+            .spawn(move || {
+                while !exit.load(Ordering::Relaxed) {
+                    sleep(index.config.flush_interval);
+                    index.flush_if_due();
+                }
+                index.flush();
+            })
+            .unwrap()
+    }
+
+    fn flush_due(&self, delta: &Delta<T>) -> bool {
+        let now = self.map.clock().now();
+        matches!(delta.oldest_write, Some(oldest_write)
+            if now.saturating_duration_since(oldest_write) >= self.config.flush_interval)
+    }
+
+    /// Apply the pending writes of bucket `ix` while holding its delta lock, so readers never
+    /// see the map without the writes that were just taken out of the delta
+    fn fold(&self, ix: usize, delta: &mut MutexGuard<Delta<T>>) {
+        if delta.entries.is_empty() {
+            return;
+        }
+        self.delta_bytes.fetch_sub(delta.bytes, Ordering::Relaxed);
+        delta.bytes = 0;
+        delta.oldest_write = None;
+        delta.version += 1;
+        let mut inserts = vec![];
+        for (key, entry) in delta.entries.drain() {
+            match entry {
+                Some((value, ref_count)) => inserts.push((key, value, ref_count)),
+                None => self.map.delete_key(&key),
+            }
+        }
+        if !inserts.is_empty() {
+            self.map.insert_batch(ix, &inserts);
+        }
+    }
+
+    /// Fold deltas, starting with that of bucket `ix`, until the byte budget is met.
+    /// Takes one delta lock at a time, so the caller must not hold any.
+    fn fold_over_budget(&self, ix: usize) {
+        let num_buckets = self.deltas.len();
+        for ix in (ix..num_buckets).chain(0..ix) {
+            if self.delta_bytes() <= self.config.max_delta_bytes {
+                break;
+            }
+            self.fold(ix, &mut self.deltas[ix].lock().unwrap());
+        }
+    }
+
+    fn write(&self, key: &Pubkey, entry: Option<(Vec<T>, RefCount)>) {
+        let ix = self.map.bucket_ix(key);
+        let mut delta = self.deltas[ix].lock().unwrap();
+        self.write_locked(ix, &mut delta, key, entry);
+        drop(delta);
+        self.fold_over_budget(ix);
+    }
+
+    fn write_locked(
+        &self,
+        ix: usize,
+        delta: &mut MutexGuard<Delta<T>>,
+        key: &Pubkey,
+        entry: Option<(Vec<T>, RefCount)>,
+    ) {
+        let bytes = Delta::entry_bytes(&entry);
+        delta.bytes += bytes;
+        self.delta_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(previous) = delta.entries.insert(*key, entry) {
+            let bytes = Delta::entry_bytes(&previous);
+            delta.bytes -= bytes;
+            self.delta_bytes.fetch_sub(bytes, Ordering::Relaxed);
+            self.coalesced_writes.fetch_add(1, Ordering::Relaxed);
+        }
+        delta.version += 1;
+        if delta.oldest_write.is_none() {
+            delta.oldest_write = Some(self.map.clock().now());
+        }
+        if self.flush_due(delta) {
+            self.fold(ix, delta);
+        }
+    }
+
+    /// Write what `f` returns for the current value of `key`, unless both are None, and return it.
+    /// A value that isn't in the delta is read from the map without holding the delta lock. If
+    /// the delta changed meanwhile, the value is read again.
+    fn read_modify_write<F>(
+        &self,
+        key: &Pubkey,
+        f: F,
+    ) -> Result<Option<(Vec<T>, RefCount)>, BucketMapError>
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Result<Option<(Vec<T>, RefCount)>, BucketMapError>,
+    {
+        let ix = self.map.bucket_ix(key);
+        loop {
+            let mut delta = self.deltas[ix].lock().unwrap();
+            let current = match delta.entries.get(key) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let version = delta.version;
+                    drop(delta);
+                    let current = self.map.read_value(key);
+                    delta = self.deltas[ix].lock().unwrap();
+                    if delta.version != version {
+                        continue;
+                    }
+                    current
+                }
+            };
+            let new = f(current
+                .as_ref()
+                .map(|(value, ref_count)| (&value[..], *ref_count)))?;
+            if new.is_some() || current.is_some() {
+                self.write_locked(ix, &mut delta, key, new.clone());
+            }
+            drop(delta);
+            self.fold_over_budget(ix);
+            return Ok(new);
+        }
+    }
+
+    fn update_ref_count<F>(&self, key: &Pubkey, f: F) -> Result<Option<RefCount>, BucketMapError>
+    where
+        F: Fn(RefCount) -> Result<RefCount, BucketMapError>,
+    {
+        let new = self.read_modify_write(key, |current| match current {
+            Some((value, ref_count)) => Ok(Some((value.to_vec(), f(ref_count)?))),
+            None => Ok(None),
+        })?;
+        Ok(new.map(|(_, ref_count)| ref_count))
+    }
+}
+
+impl<T: Clone + Copy + Debug> DiskIndex<T> for CoalescingIndex<T> {
+    fn num_buckets(&self) -> usize {
+        self.map.num_buckets()
+    }
+
+    fn bucket_ix(&self, key: &Pubkey) -> usize {
+        self.map.bucket_ix(key)
+    }
+
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        let delta = self.deltas[self.map.bucket_ix(key)].lock().unwrap();
+        match delta.entries.get(key) {
+            Some(entry) => entry.clone(),
+            None => {
+                drop(delta);
+                self.map.read_value(key)
+            }
+        }
+    }
+
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount)) {
+        self.write(key, Some((value.0.to_vec(), value.1)));
+    }
+
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        self.read_modify_write(key, |current| Ok(updatefn(current)))
+            .expect("updatefn can't fail");
+    }
+
+    fn delete_key(&self, key: &Pubkey) {
+        self.write(key, None);
+    }
+
+    /// Panics on overflow in RefCountMode::Strict, like BucketMap::addref
+    fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mode = self.map.ref_count_mode();
+        self.update_ref_count(key, |ref_count| mode.addref(ref_count))
+            .expect("Unable to addref")
+    }
+
+    /// Panics on underflow in RefCountMode::Strict, like BucketMap::unref
+    fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mode = self.map.ref_count_mode();
+        self.update_ref_count(key, |ref_count| mode.unref(ref_count))
+            .expect("Unable to unref")
+    }
+
+    /// Flushes the delta first
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        self.flush();
+        self.map.items_in_range(ix, range)
+    }
+
+    /// Flushes the delta first
+    fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        self.flush();
+        self.map.keys(ix)
+    }
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        self.map.stats_snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMapConfig, RefCountMode};
+    use crate::clock::ManualClock;
+
+    fn new_index(config: CoalescingConfig) -> CoalescingIndex<u64> {
+        CoalescingIndex::new(
+            Arc::new(BucketMap::new(BucketMapConfig::new(1 << 2))),
+            config,
+        )
+    }
+
+    #[test]
+    fn test_coalescing_hot_key() {
+        let index = new_index(CoalescingConfig {
+            flush_interval: Duration::from_secs(3600),
+            max_delta_bytes: 1 << 20,
+        });
+        let key = Pubkey::new_unique();
+        for i in 0..100 {
+            index.insert(&key, (&[i], 1));
+        }
+        assert_eq!(index.coalesced_writes(), 99);
+        assert_eq!(index.read_value(&key), Some((vec![99], 1)));
+        assert_eq!(index.addref(&key), Some(2));
+        assert_eq!(index.map().read_value(&key), None);
+        assert_eq!(index.map().stats_snapshot().insert.count, 0);
+
+        index.flush();
+        assert_eq!(index.delta_bytes(), 0);
+        assert_eq!(index.map().read_value(&key), Some((vec![99], 2)));
+        assert_eq!(index.map().stats_snapshot().insert.count, 1);
+
+        // a pending delete hides the value in the map until it is folded in
+        index.delete_key(&key);
+        assert_eq!(index.read_value(&key), None);
+        assert_eq!(index.map().read_value(&key), Some((vec![99], 2)));
+        index.flush();
+        assert_eq!(index.map().read_value(&key), None);
+    }
+
+    #[test]
+    fn test_coalescing_byte_budget() {
+        let index = new_index(CoalescingConfig {
+            flush_interval: Duration::from_secs(3600),
+            max_delta_bytes: 1024,
+        });
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            index.insert(key, (&[0], 0));
+            assert!(index.delta_bytes() <= 1024);
+        }
+        let in_map = keys
+            .iter()
+            .filter(|key| index.map().read_value(key).is_some())
+            .count();
+        assert!(in_map > 0 && in_map < keys.len());
+        index.flush();
+        assert!(keys.iter().all(|key| index.map().read_value(key).is_some()));
+    }
+
+    #[test]
+    fn test_coalescing_flusher() {
+        let index = Arc::new(new_index(CoalescingConfig {
+            flush_interval: Duration::from_millis(1),
+            max_delta_bytes: 1 << 20,
+        }));
+        let exit = Arc::new(AtomicBool::default());
+        let flusher = CoalescingIndex::spawn_flusher(Arc::clone(&index), Arc::clone(&exit));
+        let key = Pubkey::new_unique();
+        index.update(&key, |_| Some((vec![1], 1)));
+        let start = Instant::now();
+        while index.map().read_value(&key).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(1));
+        }
+        exit.store(true, Ordering::Relaxed);
+        flusher.join().unwrap();
+    }
+
+    #[test]
+    fn test_coalescing_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let map = BucketMap::new(BucketMapConfig {
+            clock: Some(clock.clone()),
+            ..BucketMapConfig::new(1 << 2)
+        });
+        let index = CoalescingIndex::new(
+            Arc::new(map),
+            CoalescingConfig {
+                flush_interval: Duration::from_secs(1),
+                max_delta_bytes: 1 << 20,
+            },
+        );
+        // the interval is measured from the oldest pending write, not from the last flush
+        clock.advance(Duration::from_secs(5));
+        let key = Pubkey::new_unique();
+        index.insert(&key, (&[1], 1));
+        index.flush_if_due();
+        assert_eq!(index.map().read_value(&key), None);
+
+        // each bucket's delta is due on its own
+        clock.advance(Duration::from_millis(500));
+        let other = std::iter::repeat_with(solana_sdk::pubkey::new_rand)
+            .find(|other| index.bucket_ix(other) != index.bucket_ix(&key))
+            .unwrap();
+        index.insert(&other, (&[2], 1));
+        clock.advance(Duration::from_millis(500));
+        index.flush_if_due();
+        assert_eq!(index.map().read_value(&key), Some((vec![1], 1)));
+        assert_eq!(index.map().read_value(&other), None);
+        assert_eq!(index.read_value(&other), Some((vec![2], 1)));
+        clock.advance(Duration::from_millis(500));
+        index.flush_if_due();
+        assert_eq!(index.map().read_value(&other), Some((vec![2], 1)));
+        assert_eq!(index.delta_bytes(), 0);
+    }
+
+    #[test]
+    fn test_coalescing_ref_count_mode() {
+        let index = CoalescingIndex::new(
+            Arc::new(BucketMap::<u64>::new(BucketMapConfig {
+                ref_count_mode: RefCountMode::Saturating,
+                ..BucketMapConfig::new(1 << 2)
+            })),
+            CoalescingConfig {
+                flush_interval: Duration::from_secs(3600),
+                max_delta_bytes: 1 << 20,
+            },
+        );
+        let key = Pubkey::new_unique();
+        index.map().insert(index.bucket_ix(&key), &key, (&[1], 0));
+        assert_eq!(index.unref(&key), Some(0));
+        index.insert(&key, (&[1], RefCount::MAX));
+        assert_eq!(index.addref(&key), Some(RefCount::MAX));
+        assert_eq!(index.addref(&Pubkey::new_unique()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to unref")]
+    fn test_coalescing_ref_count_mode_strict_panics() {
+        let index = CoalescingIndex::new(
+            Arc::new(BucketMap::<u64>::new(BucketMapConfig {
+                ref_count_mode: RefCountMode::Strict,
+                ..BucketMapConfig::new(1 << 2)
+            })),
+            CoalescingConfig {
+                flush_interval: Duration::from_secs(3600),
+                max_delta_bytes: 1 << 20,
+            },
+        );
+        let key = Pubkey::new_unique();
+        index.insert(&key, (&[1], 0));
+        index.unref(&key);
+    }
+}
@@ -0,0 +1,54 @@
+//! Text dumps of a BucketMap, one line per entry, for debugging and for diffing two indexes
+
+use crate::RefCount;
+use solana_sdk::pubkey::Pubkey;
+use std::io::{Result, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one JSON object per line: {"pubkey":"..","ref_count":N,"value":"<hex>"}.
+    /// The value is dumped as stored in its data cell, after the map's codec and encryption.
+    JsonLines,
+    /// a `pubkey,ref_count,value` header followed by one row per entry
+    Csv,
+}
+
+pub(crate) fn write_header<W: Write>(writer: &mut W, format: ExportFormat) -> Result<()> {
+    match format {
+        ExportFormat::JsonLines => Ok(()),
+        ExportFormat::Csv => writeln!(writer, "pubkey,ref_count,value"),
+    }
+}
+
+pub(crate) fn write_entry<W: Write, T>(
+    writer: &mut W,
+    format: ExportFormat,
+    pubkey: &Pubkey,
+    value: &[T],
+    ref_count: RefCount,
+) -> Result<()> {
+    let value = to_hex(value);
+    match format {
+        ExportFormat::JsonLines => writeln!(
+            writer,
+            r#"{{"pubkey":"{}","ref_count":{},"value":"{}"}}"#,
+            pubkey, ref_count, value
+        ),
+        ExportFormat::Csv => writeln!(writer, "{},{},{}", pubkey, ref_count, value),
+    }
+}
+
+/// hex encode the raw bytes of `value`, which has to point into a data cell of the mmap: every
+/// byte there is initialized, while an owned `T` may have uninitialized padding
+fn to_hex<T>(value: &[T]) -> String {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(value.as_ptr() as *const u8, std::mem::size_of_val(value))
+    };
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(DIGITS[(byte >> 4) as usize] as char);
+        hex.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
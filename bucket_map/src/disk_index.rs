@@ -0,0 +1,554 @@
+//! DiskIndex is the surface an accounts index needs from its disk backed storage.
+//! BucketMap is the production implementation. InMemoryIndex is a simple reference
+//! implementation that other backends and the embedding accounts index can be tested against.
+//! BackendIndex picks an implementation at runtime from BucketMapConfig::backend.
+
+use crate::bucket_item::BucketItem;
+use crate::bucket_map::{read_be_u64, BucketMap, BucketMapConfig, ConfigError, RefCountMode};
+use crate::bucket_stats::BucketMapStatsSnapshot;
+#[cfg(feature = "rocksdb")]
+use crate::kv_index::KvIndex;
+use crate::value_codec::ValueCodec;
+use crate::RefCount;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock};
+
+pub trait DiskIndex<T: Clone + Copy + Debug> {
+    fn num_buckets(&self) -> usize;
+
+    /// Get the bucket index for Pubkey `key`
+    fn bucket_ix(&self, key: &Pubkey) -> usize;
+
+    /// Get the values for Pubkey `key`
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)>;
+
+    /// Update Pubkey `key`'s value with 'value'
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount));
+
+    /// Update Pubkey `key`'s value with function `updatefn`.
+    /// Returning None from `updatefn` deletes `key`.
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>;
+
+    /// Delete the Pubkey `key`
+    fn delete_key(&self, key: &Pubkey);
+
+    /// Increment the refcount for Pubkey `key`
+    fn addref(&self, key: &Pubkey) -> Option<RefCount>;
+
+    /// Decrement the refcount for Pubkey `key`
+    fn unref(&self, key: &Pubkey) -> Option<RefCount>;
+
+    /// Get the items for bucket `ix` in `range`
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>;
+
+    /// Get the Pubkeys for bucket `ix`
+    fn keys(&self, ix: usize) -> Vec<Pubkey>;
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot;
+}
+
+impl<T: Clone + Copy + Debug> DiskIndex<T> for BucketMap<T> {
+    fn num_buckets(&self) -> usize {
+        BucketMap::num_buckets(self)
+    }
+
+    fn bucket_ix(&self, key: &Pubkey) -> usize {
+        BucketMap::bucket_ix(self, key)
+    }
+
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        BucketMap::read_value(self, key)
+    }
+
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount)) {
+        BucketMap::insert(self, BucketMap::bucket_ix(self, key), key, value)
+    }
+
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        BucketMap::update(self, key, updatefn)
+    }
+
+    fn delete_key(&self, key: &Pubkey) {
+        BucketMap::delete_key(self, key)
+    }
+
+    fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        BucketMap::addref(self, key)
+    }
+
+    fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        BucketMap::unref(self, key)
+    }
+
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        BucketMap::items_in_range(self, ix, range)
+    }
+
+    fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        BucketMap::keys(self, ix)
+    }
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        BucketMap::stats_snapshot(self)
+    }
+}
+
+/// Which DiskIndex implementation BackendIndex::new creates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskIndexBackend {
+    /// BucketMap, memory mapped files in BucketMapConfig::drives
+    Mmap,
+    /// KvIndex, a RocksDB instance in the first of BucketMapConfig::drives
+    #[cfg(feature = "rocksdb")]
+    Kv,
+}
+
+impl Default for DiskIndexBackend {
+    fn default() -> Self {
+        DiskIndexBackend::Mmap
+    }
+}
+
+#[derive(Debug)]
+// there is one of these per index, boxing the map would only add an indirection to every call
+#[allow(clippy::large_enum_variant)]
+pub enum BackendIndex<T: Clone + Copy + Debug> {
+    Mmap(BucketMap<T>),
+    #[cfg(feature = "rocksdb")]
+    Kv(KvIndex<T>),
+}
+
+impl<T: Clone + Copy + Debug> BackendIndex<T> {
+    /// Panics on a config try_new rejects
+    pub fn new(config: BucketMapConfig) -> Self {
+        Self::try_new(config).unwrap_or_else(|err| panic!("Invalid BucketMapConfig: {:?}", err))
+    }
+
+    /// The index `config.backend` selects, or an error for a config it can't honor
+    pub fn try_new(config: BucketMapConfig) -> Result<Self, ConfigError> {
+        match config.backend {
+            DiskIndexBackend::Mmap => BucketMap::try_new(config).map(BackendIndex::Mmap),
+            #[cfg(feature = "rocksdb")]
+            DiskIndexBackend::Kv => KvIndex::try_new(config).map(BackendIndex::Kv),
+        }
+    }
+
+    /// Same as try_new, storing every value as `codec` encodes it. Only the Mmap backend
+    /// supports a codec.
+    pub fn try_new_with_codec(
+        config: BucketMapConfig,
+        codec: Arc<dyn ValueCodec<T>>,
+    ) -> Result<Self, ConfigError> {
+        match config.backend {
+            DiskIndexBackend::Mmap => {
+                BucketMap::try_new_with_codec(config, codec).map(BackendIndex::Mmap)
+            }
+            #[cfg(feature = "rocksdb")]
+            DiskIndexBackend::Kv => Err(ConfigError::UnsupportedByKvBackend("codec")),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $index:ident => $call:expr) => {
+        match $self {
+            BackendIndex::Mmap($index) => $call,
+            #[cfg(feature = "rocksdb")]
+            BackendIndex::Kv($index) => $call,
+        }
+    };
+}
+
+impl<T: Clone + Copy + Debug> DiskIndex<T> for BackendIndex<T> {
+    fn num_buckets(&self) -> usize {
+        dispatch!(self, index => DiskIndex::num_buckets(index))
+    }
+
+    fn bucket_ix(&self, key: &Pubkey) -> usize {
+        dispatch!(self, index => DiskIndex::bucket_ix(index, key))
+    }
+
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        dispatch!(self, index => DiskIndex::read_value(index, key))
+    }
+
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount)) {
+        dispatch!(self, index => DiskIndex::insert(index, key, value))
+    }
+
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        dispatch!(self, index => DiskIndex::update(index, key, updatefn))
+    }
+
+    fn delete_key(&self, key: &Pubkey) {
+        dispatch!(self, index => DiskIndex::delete_key(index, key))
+    }
+
+    fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        dispatch!(self, index => DiskIndex::addref(index, key))
+    }
+
+    fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        dispatch!(self, index => DiskIndex::unref(index, key))
+    }
+
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        dispatch!(self, index => DiskIndex::items_in_range(index, ix, range))
+    }
+
+    fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        dispatch!(self, index => DiskIndex::keys(index, ix))
+    }
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        dispatch!(self, index => DiskIndex::stats_snapshot(index))
+    }
+}
+
+type InMemoryBucket<T> = HashMap<Pubkey, (Vec<T>, RefCount)>;
+
+/// A DiskIndex kept entirely in memory, with the same Pubkey prefix bucket assignment as a
+/// default configured BucketMap
+#[derive(Debug)]
+pub struct InMemoryIndex<T> {
+    buckets: Vec<RwLock<InMemoryBucket<T>>>,
+    max_buckets_pow2: u32,
+    ref_count_mode: RefCountMode,
+}
+
+impl<T: Clone + Copy + Debug> InMemoryIndex<T> {
+    pub fn new(max_buckets: usize) -> Self {
+        Self::new_with_ref_count_mode(max_buckets, RefCountMode::default())
+    }
+
+    /// An index whose addref and unref follow `ref_count_mode`, like those of a BucketMap
+    pub fn new_with_ref_count_mode(max_buckets: usize, ref_count_mode: RefCountMode) -> Self {
+        assert!(
+            max_buckets.is_power_of_two(),
+            "Max number of buckets must be a power of two"
+        );
+        let mut buckets = Vec::with_capacity(max_buckets);
+        buckets.resize_with(max_buckets, RwLock::default);
+        Self {
+            buckets,
+            max_buckets_pow2: max_buckets.trailing_zeros(),
+            ref_count_mode,
+        }
+    }
+}
+
+impl<T: Clone + Copy + Debug> DiskIndex<T> for InMemoryIndex<T> {
+    fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn bucket_ix(&self, key: &Pubkey) -> usize {
+        if self.max_buckets_pow2 > 0 {
+            (read_be_u64(key.as_ref()) >> (u64::BITS - self.max_buckets_pow2)) as usize
+        } else {
+            0
+        }
+    }
+
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        self.buckets[self.bucket_ix(key)]
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+    }
+
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount)) {
+        self.buckets[self.bucket_ix(key)]
+            .write()
+            .unwrap()
+            .insert(*key, (value.0.to_vec(), value.1));
+    }
+
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        let mut bucket = self.buckets[self.bucket_ix(key)].write().unwrap();
+        let current = bucket
+            .get(key)
+            .map(|(value, ref_count)| (&value[..], *ref_count));
+        match updatefn(current) {
+            Some(new) => bucket.insert(*key, new),
+            None => bucket.remove(key),
+        };
+    }
+
+    fn delete_key(&self, key: &Pubkey) {
+        self.buckets[self.bucket_ix(key)]
+            .write()
+            .unwrap()
+            .remove(key);
+    }
+
+    /// Panics on overflow in RefCountMode::Strict, like BucketMap::addref
+    fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mut bucket = self.buckets[self.bucket_ix(key)].write().unwrap();
+        let (_, ref_count) = bucket.get_mut(key)?;
+        *ref_count = self
+            .ref_count_mode
+            .addref(*ref_count)
+            .expect("Unable to addref");
+        Some(*ref_count)
+    }
+
+    /// Panics on underflow in RefCountMode::Strict, like BucketMap::unref
+    fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mut bucket = self.buckets[self.bucket_ix(key)].write().unwrap();
+        let (_, ref_count) = bucket.get_mut(key)?;
+        *ref_count = self
+            .ref_count_mode
+            .unref(*ref_count)
+            .expect("Unable to unref");
+        Some(*ref_count)
+    }
+
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        self.buckets[ix]
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| range.map(|r| r.contains(key)).unwrap_or(true))
+            .map(|(key, (slot_list, ref_count))| BucketItem {
+                pubkey: *key,
+                ref_count: *ref_count,
+                slot_list: slot_list.clone(),
+            })
+            .collect()
+    }
+
+    fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        self.buckets[ix].read().unwrap().keys().cloned().collect()
+    }
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        BucketMapStatsSnapshot::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::BucketMapConfig;
+
+    type Entry = (Pubkey, Vec<u64>, RefCount);
+
+    fn sorted_items<I: DiskIndex<u64>>(
+        index: &I,
+        range: Option<&std::ops::RangeFrom<Pubkey>>,
+    ) -> Vec<Entry> {
+        let mut items = (0..index.num_buckets())
+            .flat_map(|ix| index.items_in_range(ix, &range))
+            .map(|item| (item.pubkey, item.slot_list, item.ref_count))
+            .collect::<Vec<_>>();
+        items.sort_by_key(|(pubkey, _, _)| *pubkey);
+        items
+    }
+
+    fn exercise<I: DiskIndex<u64>>(index: &I, keys: &[Pubkey]) {
+        for (i, key) in keys.iter().enumerate() {
+            let i = i as u64;
+            if i % 2 == 0 {
+                index.insert(key, (&[i], i));
+            } else {
+                index.update(key, |current| {
+                    assert!(current.is_none());
+                    Some((vec![i; i as usize % 5], i))
+                });
+            }
+        }
+        for key in keys.iter().step_by(3) {
+            assert!(index.addref(key).is_some());
+        }
+        for key in keys.iter().step_by(7) {
+            assert!(index.unref(key).is_some());
+        }
+        for key in keys.iter().step_by(11) {
+            index.delete_key(key);
+        }
+        index.update(&keys[1], |_| None);
+        assert_eq!(index.addref(&keys[1]), None);
+    }
+
+    fn assert_matches_in_memory_index<I: DiskIndex<u64>>(index: &I) {
+        let keys = (0..200)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        let reference = InMemoryIndex::new(index.num_buckets());
+        exercise(&reference, &keys);
+        exercise(index, &keys);
+
+        for key in keys.iter() {
+            assert_eq!(reference.bucket_ix(key), index.bucket_ix(key));
+            assert_eq!(reference.read_value(key), index.read_value(key));
+        }
+        assert_eq!(sorted_items(&reference, None), sorted_items(index, None));
+        let range = keys[5]..;
+        assert_eq!(
+            sorted_items(&reference, Some(&range)),
+            sorted_items(index, Some(&range))
+        );
+        for ix in 0..reference.num_buckets() {
+            let mut expected = reference.keys(ix);
+            let mut keys = index.keys(ix);
+            expected.sort();
+            keys.sort();
+            assert_eq!(expected, keys);
+        }
+    }
+
+    #[test]
+    fn test_in_memory_index_ref_count_mode() {
+        let key = Pubkey::new_unique();
+        let index = InMemoryIndex::new_with_ref_count_mode(1, RefCountMode::Wrapping);
+        index.insert(&key, (&[1], 0));
+        assert_eq!(index.unref(&key), Some(u64::MAX));
+        assert_eq!(index.addref(&key), Some(0));
+        let index = InMemoryIndex::new_with_ref_count_mode(1, RefCountMode::Saturating);
+        index.insert(&key, (&[1], 0));
+        assert_eq!(index.unref(&key), Some(0));
+        index.insert(&key, (&[1], u64::MAX));
+        assert_eq!(index.addref(&key), Some(u64::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to unref")]
+    fn test_in_memory_index_ref_count_mode_strict_panics() {
+        let key = Pubkey::new_unique();
+        let index = InMemoryIndex::new_with_ref_count_mode(1, RefCountMode::Strict);
+        index.insert(&key, (&[1], 0));
+        index.unref(&key);
+    }
+
+    #[test]
+    fn test_bucket_map_matches_in_memory_index() {
+        assert_matches_in_memory_index(&BucketMap::new(BucketMapConfig::new(1 << 2)));
+        assert_matches_in_memory_index(&BackendIndex::new(BucketMapConfig::new(1)));
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_kv_index_matches_in_memory_index() {
+        let config = BucketMapConfig {
+            backend: DiskIndexBackend::Kv,
+            ..BucketMapConfig::new(1 << 3)
+        };
+        let index = BackendIndex::new(config);
+        assert!(matches!(index, BackendIndex::Kv(_)));
+        assert_matches_in_memory_index(&index);
+        assert!(index.stats_snapshot().update.count > 0);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_kv_index_config_errors() {
+        use crate::bucket_map::BucketHashKey;
+        use crate::value_codec::IdentityCodec;
+
+        let drive = tempfile::TempDir::new().unwrap();
+        let marker = drive.path().join("marker");
+        std::fs::write(&marker, b"").unwrap();
+        let config = || BucketMapConfig {
+            backend: DiskIndexBackend::Kv,
+            drives: Some(vec![drive.path().to_path_buf()]),
+            ..BucketMapConfig::new(1 << 2)
+        };
+        let try_new = |config| BackendIndex::<u64>::try_new(config).unwrap_err();
+        assert_eq!(
+            try_new(BucketMapConfig {
+                max_buckets: 3,
+                ..config()
+            }),
+            ConfigError::MaxBucketsNotPowerOfTwo(3)
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                bucket_hash_key: Some(BucketHashKey([1, 2])),
+                ..config()
+            }),
+            ConfigError::UnsupportedByKvBackend("bucket_hash_key")
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                change_feed: Some(crossbeam_channel::unbounded().0),
+                ..config()
+            }),
+            ConfigError::UnsupportedByKvBackend("change_feed")
+        );
+        assert_eq!(
+            try_new(BucketMapConfig {
+                max_value_len: Some(1),
+                ..config()
+            }),
+            ConfigError::UnsupportedByKvBackend("max_value_len")
+        );
+        assert_eq!(
+            BackendIndex::<u64>::try_new_with_codec(config(), Arc::new(IdentityCodec)).unwrap_err(),
+            ConfigError::UnsupportedByKvBackend("codec")
+        );
+        #[cfg(feature = "encryption")]
+        assert_eq!(
+            try_new(BucketMapConfig {
+                encryption_key: Some(crate::encryption::EncryptionKey::new_rand()),
+                ..config()
+            }),
+            ConfigError::UnsupportedByKvBackend("encryption_key")
+        );
+        // nothing was erased
+        assert!(marker.exists());
+        assert!(BackendIndex::<u64>::try_new(config()).is_ok());
+        assert!(!marker.exists());
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_kv_index_rates() {
+        use crate::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let index = KvIndex::<u64>::new(BucketMapConfig {
+            clock: Some(clock.clone()),
+            rate_window: Some(Duration::from_secs(10)),
+            ..BucketMapConfig::new(1 << 1)
+        });
+        assert_eq!(index.stats_snapshot().rates.window, Duration::from_secs(10));
+        let key = Pubkey::new_unique();
+        for _ in 0..5 {
+            for _ in 0..10 {
+                index.update(&key, |_| Some((vec![1], 1)));
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        let rates = index.stats_snapshot().rates;
+        assert_eq!(rates.update, 10.0);
+        assert_eq!(rates.read, 0.0);
+    }
+}
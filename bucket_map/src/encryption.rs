@@ -0,0 +1,338 @@
+//! Encryption at rest of the files of a map, for drives that other machines can read.
+//! Values are sealed with XChaCha20-Poly1305, bound to their Pubkey, under a random nonce per
+//! write. Pubkeys are stored in the index under a keyed permutation, a 4 round Feistel network
+//! with XChaCha20 as the round function, so equal Pubkeys still find each other without the
+//! index exposing them.
+//! What stays visible: the number of entries per bucket, ref counts, the length of every value
+//! and, with BucketAssignment::Prefix and no bucket_hash_key, the bucket a Pubkey falls in.
+//! Because of the random nonces, a seeded map doesn't reproduce its files byte for byte.
+
+use chacha20::cipher::{NewCipher, StreamCipher};
+use chacha20::XChaCha20;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::{thread_rng, Rng};
+use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
+use std::mem::size_of;
+
+const NONCE_BYTES: usize = 24;
+const TAG_BYTES: usize = 16;
+/// bytes a sealed value takes on top of the value itself
+const OVERHEAD_BYTES: usize = NONCE_BYTES + TAG_BYTES;
+const FEISTEL_ROUNDS: usize = 4;
+const HALF_KEY_BYTES: usize = 16;
+// domain separation of the subkeys derived from an EncryptionKey
+const SUBKEY_NONCE: &[u8; NONCE_BYTES] = b"solana-bucket-map-subkey";
+
+/// Key every file of a map is encrypted with. A map that is reopened has to use the same key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+impl EncryptionKey {
+    pub fn new_rand() -> Self {
+        Self(thread_rng().gen())
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+pub(crate) struct Encryption {
+    aead: XChaCha20Poly1305,
+    round_keys: [Key; FEISTEL_ROUNDS],
+}
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Encryption(..)")
+    }
+}
+
+impl Encryption {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        // subkeys are the keystream of the key, so the permutation and the AEAD never share a key
+        let mut subkeys = [[0u8; 32]; FEISTEL_ROUNDS + 1];
+        let mut stream = XChaCha20::new(&Key::from(key.0), &XNonce::from(*SUBKEY_NONCE));
+        subkeys
+            .iter_mut()
+            .for_each(|subkey| stream.apply_keystream(subkey));
+        let mut round_keys = [Key::default(); FEISTEL_ROUNDS];
+        round_keys
+            .iter_mut()
+            .zip(subkeys[1..].iter())
+            .for_each(|(round_key, subkey)| *round_key = Key::from(*subkey));
+        Self {
+            aead: XChaCha20Poly1305::new(&Key::from(subkeys[0])),
+            round_keys,
+        }
+    }
+
+    fn round(&self, round: usize, half: &[u8; HALF_KEY_BYTES]) -> [u8; HALF_KEY_BYTES] {
+        let mut nonce = [0u8; NONCE_BYTES];
+        nonce[..HALF_KEY_BYTES].copy_from_slice(half);
+        let mut output = [0u8; HALF_KEY_BYTES];
+        XChaCha20::new(&self.round_keys[round], &XNonce::from(nonce)).apply_keystream(&mut output);
+        output
+    }
+
+    fn split(key: &Pubkey) -> ([u8; HALF_KEY_BYTES], [u8; HALF_KEY_BYTES]) {
+        let mut left = [0u8; HALF_KEY_BYTES];
+        let mut right = [0u8; HALF_KEY_BYTES];
+        left.copy_from_slice(&key.as_ref()[..HALF_KEY_BYTES]);
+        right.copy_from_slice(&key.as_ref()[HALF_KEY_BYTES..]);
+        (left, right)
+    }
+
+    fn join(left: &[u8; HALF_KEY_BYTES], right: &[u8; HALF_KEY_BYTES]) -> Pubkey {
+        let mut key = [0u8; 32];
+        key[..HALF_KEY_BYTES].copy_from_slice(left);
+        key[HALF_KEY_BYTES..].copy_from_slice(right);
+        Pubkey::new_from_array(key)
+    }
+
+    fn xor(mut half: [u8; HALF_KEY_BYTES], other: &[u8; HALF_KEY_BYTES]) -> [u8; HALF_KEY_BYTES] {
+        half.iter_mut().zip(other.iter()).for_each(|(a, b)| *a ^= b);
+        half
+    }
+
+    /// What the index stores for `key`
+    pub(crate) fn encrypt_key(&self, key: &Pubkey) -> Pubkey {
+        let (mut left, mut right) = Self::split(key);
+        for round in 0..FEISTEL_ROUNDS {
+            let next = Self::xor(left, &self.round(round, &right));
+            left = right;
+            right = next;
+        }
+        Self::join(&left, &right)
+    }
+
+    /// The Pubkey `stored`, a result of encrypt_key, was encrypted from
+    pub(crate) fn decrypt_key(&self, stored: &Pubkey) -> Pubkey {
+        let (mut left, mut right) = Self::split(stored);
+        for round in (0..FEISTEL_ROUNDS).rev() {
+            let previous = Self::xor(right, &self.round(round, &left));
+            right = left;
+            left = previous;
+        }
+        Self::join(&left, &right)
+    }
+}
+
+fn as_bytes<T>(values: &[T]) -> &[u8] {
+    // values are plain old data, the storages write them to disk byte for byte as well
+    unsafe {
+        std::slice::from_raw_parts(values.as_ptr() as *const u8, std::mem::size_of_val(values))
+    }
+}
+
+/// The fewest elements that hold `bytes`, zero padded at the end
+fn from_bytes<T: Copy>(bytes: &[u8]) -> Vec<T> {
+    let len = (bytes.len() + size_of::<T>() - 1) / size_of::<T>();
+    let mut values = Vec::<T>::with_capacity(len);
+    unsafe {
+        let dst = values.as_mut_ptr() as *mut u8;
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+        std::ptr::write_bytes(dst.add(bytes.len()), 0, len * size_of::<T>() - bytes.len());
+        values.set_len(len);
+    }
+    values
+}
+
+impl Encryption {
+    /// What to store for the value `values` of `key`
+    pub(crate) fn encode<'a, T: Copy>(&self, key: &Pubkey, values: &'a [T]) -> Cow<'a, [T]> {
+        // empty values have no data cell to encrypt
+        if values.is_empty() {
+            return Cow::Borrowed(values);
+        }
+        let nonce: [u8; NONCE_BYTES] = thread_rng().gen();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.aead
+                .encrypt(
+                    &XNonce::from(nonce),
+                    Payload {
+                        msg: as_bytes(values),
+                        aad: key.as_ref(),
+                    },
+                )
+                .expect("Unable to encrypt value"),
+        );
+        Cow::Owned(from_bytes(&sealed))
+    }
+
+    /// The value of `key` that `stored`, a result of encode, was encoded from.
+    /// None if `stored` is too short or doesn't authenticate under this key.
+    pub(crate) fn decode<'a, T: Copy>(
+        &self,
+        key: &Pubkey,
+        stored: &'a [T],
+    ) -> Option<Cow<'a, [T]>> {
+        if stored.is_empty() {
+            return Some(Cow::Borrowed(stored));
+        }
+        // the padding after the tag is shorter than an element
+        let len = std::mem::size_of_val(stored).checked_sub(OVERHEAD_BYTES)? / size_of::<T>();
+        let sealed = &as_bytes(stored)[..OVERHEAD_BYTES + len * size_of::<T>()];
+        let mut nonce = [0u8; NONCE_BYTES];
+        nonce.copy_from_slice(&sealed[..NONCE_BYTES]);
+        let values = self
+            .aead
+            .decrypt(
+                &XNonce::from(nonce),
+                Payload {
+                    msg: &sealed[NONCE_BYTES..],
+                    aad: key.as_ref(),
+                },
+            )
+            .ok()?;
+        Some(Cow::Owned(from_bytes(&values)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_mode::{AssertMode, InvariantViolation};
+    use crate::bucket_map::{BucketMap, BucketMapConfig, BucketMapError};
+    use solana_sdk::pubkey::new_rand;
+    use std::fs;
+
+    #[test]
+    fn test_encrypt_key() {
+        let encryption = Encryption::new(&EncryptionKey::new_rand());
+        let other = Encryption::new(&EncryptionKey::new_rand());
+        for _ in 0..100 {
+            let key = Pubkey::new_unique();
+            let stored = encryption.encrypt_key(&key);
+            assert_ne!(stored, key);
+            assert_eq!(encryption.encrypt_key(&key), stored);
+            assert_ne!(other.encrypt_key(&key), stored);
+            assert_eq!(encryption.decrypt_key(&stored), key);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_value() {
+        let encryption = Encryption::new(&EncryptionKey::new_rand());
+        let key = Pubkey::new_unique();
+        for len in 0..10 {
+            let values = (0..len as u8).collect::<Vec<_>>();
+            let stored = encryption.encode(&key, &values);
+            assert_eq!(
+                stored.len(),
+                if len == 0 { 0 } else { len + OVERHEAD_BYTES }
+            );
+            // fresh nonce for every write
+            assert!(len == 0 || encryption.encode(&key, &values) != stored);
+            assert_eq!(encryption.decode(&key, &stored).unwrap(), values);
+
+            let values = (0..len as u64).collect::<Vec<_>>();
+            let stored = encryption.encode(&key, &values);
+            assert_eq!(
+                stored.len(),
+                if len == 0 {
+                    0
+                } else {
+                    len + OVERHEAD_BYTES / 8
+                }
+            );
+            assert_eq!(encryption.decode(&key, &stored).unwrap(), values);
+        }
+    }
+
+    #[test]
+    fn test_encrypt_value_undecryptable() {
+        let encryption = Encryption::new(&EncryptionKey::new_rand());
+        let key = Pubkey::new_unique();
+        let stored = encryption.encode(&key, &[1u64]);
+        assert!(encryption.decode(&Pubkey::new_unique(), &stored).is_none());
+        let other = Encryption::new(&EncryptionKey::new_rand());
+        assert!(other.decode(&key, &stored).is_none());
+        // shorter than the nonce and tag
+        assert!(encryption.decode(&key, &stored[..2]).is_none());
+        assert!(encryption.decode(&key, &[1u8]).is_none());
+    }
+
+    #[test]
+    fn test_encrypted_map_corrupt_value() {
+        let map = BucketMap::new(BucketMapConfig {
+            encryption_key: Some(EncryptionKey::new_rand()),
+            assert_mode: AssertMode::Resilient,
+            ..BucketMapConfig::new(1)
+        });
+        let keys = (0..2).map(|_| new_rand()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![1u64, 2], 1)));
+        }
+        {
+            let bucket = map.buckets[0].read().unwrap();
+            let bucket = bucket.as_ref().unwrap();
+            let (value, _) = bucket
+                .find_entry(&keys[0])
+                .unwrap()
+                .0
+                .read_value(bucket)
+                .unwrap();
+            // flip a bit of the tag
+            let last = value.len() - 1;
+            unsafe { *(value.as_ptr().add(last) as *mut u64) ^= 1 };
+        }
+        assert_eq!(map.read_value(&keys[0]), None);
+        assert!(matches!(
+            map.try_read_value(&keys[0]),
+            Err(BucketMapError::InvariantViolation(
+                InvariantViolation::UndecryptableValue
+            ))
+        ));
+        assert_eq!(map.read_value(&keys[1]), Some((vec![1, 2], 1)));
+        // scans skip it
+        let items = map.items_in_range(0, &None::<&std::ops::RangeFull>);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].pubkey, keys[1]);
+        // the next write replaces it
+        map.update(&keys[0], |_| Some((vec![3], 2)));
+        assert_eq!(map.read_value(&keys[0]), Some((vec![3], 2)));
+    }
+
+    #[test]
+    fn test_encrypted_map() {
+        let map = BucketMap::new(BucketMapConfig {
+            encryption_key: Some(EncryptionKey::new_rand()),
+            ..BucketMapConfig::new(1)
+        });
+        // unique keys are mostly zeros, which a file can contain by chance
+        let mut keys = (0..100).map(|_| new_rand()).collect::<Vec<_>>();
+        keys.sort();
+        let marker = 0x5eed_5eed_5eed_5eedu64;
+        for (i, key) in keys.iter().enumerate() {
+            map.update(key, |_| Some((vec![marker; i % 3], i as u64)));
+        }
+        map.flush().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.read_value(key), Some((vec![marker; i % 3], i as u64)));
+        }
+        let mut found = map.keys(0);
+        found.sort();
+        assert_eq!(found, keys);
+        let range = keys[10]..=keys[20];
+        let items = map.items_in_range(0, &Some(&range));
+        assert_eq!(items.len(), 11);
+        assert!(items.iter().all(|item| range.contains(&item.pubkey)));
+        assert!(items
+            .iter()
+            .all(|item| item.slot_list == vec![marker; item.ref_count as usize % 3]));
+
+        // neither the keys nor the values are in the files
+        for file in fs::read_dir(map.temp_dir.as_ref().unwrap().path()).unwrap() {
+            let contents = fs::read(file.unwrap().path()).unwrap();
+            let contains = |needle: &[u8]| contents.windows(needle.len()).any(|w| w == needle);
+            assert!(!contains(&marker.to_le_bytes()));
+            assert!(keys.iter().all(|key| !contains(key.as_ref())));
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Fault injection for the IO done by bucket storages, so the handling of disk full and EIO
+//! can be exercised without faulty hardware.
+//! Hand a FailPoints to BucketMapConfig::fail_points and arm the operations that should fail.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailPointOp {
+    /// mapping a newly created file into memory
+    Mmap,
+    /// sizing a newly created file by writing its last byte
+    Write,
+    /// growing an index or data storage, before anything is allocated
+    Grow,
+}
+
+#[derive(Debug, Default)]
+struct FailPoint {
+    // times the operation has been attempted
+    hits: u64,
+    // (hit that fails, errno)
+    armed: Option<(u64, i32)>,
+}
+
+#[derive(Debug, Default)]
+pub struct FailPoints {
+    points: Mutex<HashMap<FailPointOp, FailPoint>>,
+}
+
+impl FailPoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail the `nth` attempt of `op` from now on, counting from 1, with `errno`.
+    /// Attempts after that succeed again.
+    pub fn fail_nth(&self, op: FailPointOp, nth: u64, errno: i32) {
+        assert_ne!(nth, 0, "attempts are counted from 1");
+        let mut points = self.points.lock().unwrap();
+        let point = points.entry(op).or_default();
+        point.armed = Some((point.hits + nth, errno));
+    }
+
+    /// Disarm `op` without failing it
+    pub fn clear(&self, op: FailPointOp) {
+        if let Some(point) = self.points.lock().unwrap().get_mut(&op) {
+            point.armed = None;
+        }
+    }
+
+    /// Number of times `op` has been attempted, failed attempts included
+    pub fn hits(&self, op: FailPointOp) -> u64 {
+        self.points
+            .lock()
+            .unwrap()
+            .get(&op)
+            .map(|point| point.hits)
+            .unwrap_or_default()
+    }
+
+    /// Count an attempt of `op`, Err if it is the one `fail_points` is armed to fail
+    pub(crate) fn check(fail_points: Option<&FailPoints>, op: FailPointOp) -> io::Result<()> {
+        let fail_points = match fail_points {
+            Some(fail_points) => fail_points,
+            None => return Ok(()),
+        };
+        let mut points = fail_points.points.lock().unwrap();
+        let point = points.entry(op).or_default();
+        point.hits += 1;
+        match point.armed {
+            Some((nth, errno)) if nth == point.hits => {
+                point.armed = None;
+                Err(io::Error::from_raw_os_error(errno))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "fail-points"))]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig, BucketMapError};
+    use solana_sdk::pubkey::Pubkey;
+    use std::fs;
+    use std::sync::Arc;
+
+    fn new_map(fail_points: &Arc<FailPoints>) -> BucketMap<u64> {
+        BucketMap::new(BucketMapConfig {
+            fail_points: Some(Arc::clone(fail_points)),
+            ..BucketMapConfig::new(1)
+        })
+    }
+
+    fn num_files(map: &BucketMap<u64>) -> usize {
+        fs::read_dir(map.temp_dir.as_ref().unwrap().path())
+            .unwrap()
+            .count()
+    }
+
+    fn errno(err: BucketMapError) -> Option<i32> {
+        match err {
+            BucketMapError::Io(err) => err.raw_os_error(),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_fail_points_create() {
+        let fail_points = Arc::new(FailPoints::new());
+        let map = new_map(&fail_points);
+        let key = Pubkey::new_unique();
+
+        // creating the bucket's index runs out of space
+        fail_points.fail_nth(FailPointOp::Write, 1, libc::ENOSPC);
+        let err = map.try_insert(0, &key, (&[1], 1)).unwrap_err();
+        assert_eq!(errno(err), Some(libc::ENOSPC));
+        assert_eq!(num_files(&map), 0);
+
+        // the bucket has no data storage yet, creating it fails to map
+        let err = map.try_insert(0, &key, (&[1], 1)).unwrap_err();
+        assert!(matches!(err, BucketMapError::DataNoSpace(_)));
+        fail_points.fail_nth(FailPointOp::Mmap, 1, libc::EIO);
+        let err = map.try_grow(0, err).unwrap_err();
+        assert_eq!(errno(err), Some(libc::EIO));
+        assert_eq!(num_files(&map), 1);
+
+        let err = map.try_insert(0, &key, (&[1], 1)).unwrap_err();
+        map.try_grow(0, err).unwrap();
+        map.try_insert(0, &key, (&[1], 1)).unwrap();
+        assert_eq!(map.read_value(&key), Some((vec![1], 1)));
+        assert_eq!(fail_points.hits(FailPointOp::Write), 4);
+        assert_eq!(fail_points.hits(FailPointOp::Mmap), 3);
+    }
+
+    #[test]
+    fn test_fail_points_grow() {
+        let fail_points = Arc::new(FailPoints::new());
+        let map = new_map(&fail_points);
+        fail_points.fail_nth(FailPointOp::Grow, 2, libc::EIO);
+        let mut keys = vec![];
+        let mut failed = 0;
+        while keys.len() < 100 {
+            let key = Pubkey::new_unique();
+            match map.try_insert(0, &key, (&[keys.len() as u64], 0)) {
+                Ok(()) => keys.push(key),
+                Err(err) => {
+                    if let Err(err) = map.try_grow(0, err) {
+                        assert_eq!(errno(err), Some(libc::EIO));
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(failed, 1);
+        assert!(fail_points.hits(FailPointOp::Grow) > 2);
+        // a failed grow leaves the bucket as it was
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.read_value(key), Some((vec![i as u64], 0)));
+        }
+
+        fail_points.fail_nth(FailPointOp::Grow, 1, libc::EIO);
+        fail_points.clear(FailPointOp::Grow);
+        map.reserve_index(0, 1 << 10);
+    }
+}
@@ -0,0 +1,181 @@
+//! Worker threads BucketMap::grow_async hands grows to, so a caller that hit NoSpace in several
+//! buckets, e.g. during startup, can grow them in parallel instead of one after another.
+//! Grows of different buckets run concurrently, up to BucketMapConfig::grow_workers at once.
+//! Grows of the same bucket take turns on its write lock, and a grow that another one already
+//! did is a no-op.
+
+use crate::bucket_map::BucketMapError;
+use crossbeam_channel::{bounded, Receiver};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::{Arc, Mutex};
+
+/// The pending result of a grow started with BucketMap::grow_async
+pub struct GrowHandle {
+    ix: usize,
+    receiver: Receiver<Result<(), BucketMapError>>,
+    result: Option<Result<(), BucketMapError>>,
+}
+
+impl GrowHandle {
+    /// The bucket being grown
+    pub fn ix(&self) -> usize {
+        self.ix
+    }
+
+    /// true once the grow finished, without blocking
+    pub fn is_done(&mut self) -> bool {
+        if self.result.is_none() {
+            self.result = self.receiver.try_recv().ok();
+        }
+        self.result.is_some()
+    }
+
+    /// Block until the grow finished and return its result
+    pub fn wait(self) -> Result<(), BucketMapError> {
+        match self.result {
+            Some(result) => result,
+            None => self
+                .receiver
+                .recv()
+                .unwrap_or_else(|_| panic!("grow of bucket {} panicked", self.ix)),
+        }
+    }
+}
+
+pub(crate) struct GrowPool {
+    workers: Option<usize>,
+    // created by the first grow
+    pool: Mutex<Option<Arc<ThreadPool>>>,
+}
+
+impl GrowPool {
+    /// `workers` None starts one per core
+    pub(crate) fn new(workers: Option<usize>) -> Self {
+        if let Some(workers) = workers {
+            assert_ne!(workers, 0, "grow_workers must be non-zero");
+        }
+        Self {
+            workers,
+            pool: Mutex::default(),
+        }
+    }
+
+    /// Run `grow` of bucket `ix` on one of the workers
+    pub(crate) fn spawn<F>(&self, ix: usize, grow: F) -> GrowHandle
+    where
+        F: FnOnce() -> Result<(), BucketMapError> + Send + 'static,
+    {
+        let (sender, receiver) = bounded(1);
+        self.pool().spawn(move || {
+            let _ = sender.send(grow());
+        });
+        GrowHandle {
+            ix,
+            receiver,
+            result: None,
+        }
+    }
+
+    fn pool(&self) -> Arc<ThreadPool> {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.is_none() {
+            *pool = Some(Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(self.workers.unwrap_or(0))
+                    .thread_name(|i| format!("solana-bucket-map-grow-{}", i))
+                    // the handle of a grow that panicked reports it from wait
+                    .panic_handler(|_| ())
+                    .build()
+                    .unwrap(),
+            ));
+        }
+        pool.as_ref().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use solana_sdk::pubkey::Pubkey;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_grow_pool_runs_concurrently() {
+        let pool = GrowPool::new(Some(2));
+        // only returns once both grows run at the same time
+        let barrier = Arc::new(Barrier::new(2));
+        let handles = (0..2)
+            .map(|ix| {
+                let barrier = barrier.clone();
+                pool.spawn(ix, move || {
+                    barrier.wait();
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for (ix, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.ix(), ix);
+            assert!(handle.wait().is_ok());
+        }
+        let mut handle = pool.spawn(0, || Err(BucketMapError::IndexNoSpace(3)));
+        while !handle.is_done() {
+            std::thread::yield_now();
+        }
+        assert!(matches!(
+            handle.wait(),
+            Err(BucketMapError::IndexNoSpace(3))
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "grow of bucket 1 panicked")]
+    fn test_grow_pool_panic() {
+        let pool = GrowPool::new(Some(1));
+        let _ = pool.spawn(1, || panic!("grow failed")).wait();
+    }
+
+    #[test]
+    fn test_grow_async() {
+        let map = Arc::new(BucketMap::<u64>::new(BucketMapConfig {
+            grow_workers: Some(2),
+            ..BucketMapConfig::new(1 << 2)
+        }));
+        let capacity = |ix: usize| {
+            map.buckets[ix]
+                .read()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .index_capacity()
+                .trailing_zeros() as u8
+        };
+        let before = (0..4)
+            .map(|ix| {
+                let key = std::iter::repeat_with(Pubkey::new_unique)
+                    .find(|key| map.bucket_ix(key) == ix)
+                    .unwrap();
+                map.insert(ix, &key, (&[ix as u64], 1));
+                capacity(ix)
+            })
+            .collect::<Vec<_>>();
+        let handles = before
+            .iter()
+            .enumerate()
+            .map(|(ix, sz)| BucketMap::grow_async(&map, ix, BucketMapError::IndexNoSpace(*sz)))
+            .collect::<Vec<_>>();
+        // the same grow again is a no-op
+        let again = BucketMap::grow_async(&map, 0, BucketMapError::IndexNoSpace(before[0]));
+        for handle in handles.into_iter().chain(std::iter::once(again)) {
+            handle.wait().unwrap();
+        }
+        for (ix, sz) in before.into_iter().enumerate() {
+            assert_eq!(capacity(ix), sz + 1);
+        }
+        assert!(
+            BucketMap::grow_async(&map, 1, BucketMapError::RefCountOverflow)
+                .wait()
+                .is_err()
+        );
+    }
+}
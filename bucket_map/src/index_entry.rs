@@ -1,3 +1,4 @@
+use crate::assert_mode::InvariantViolation;
 use crate::bucket::Bucket;
 use crate::bucket_storage::{BucketStorage, Uid};
 use crate::RefCount;
@@ -17,9 +18,26 @@ pub struct IndexEntry {
     pub storage_offset: u64, // smaller? since these are variably sized, this could get tricky. well, actually accountinfo is not variable sized...
     // if the bucket doubled, the index can be recomputed using create_bucket_capacity_pow2
     pub storage_capacity_when_created_pow2: u8, // see data_location
+    pub reserved: u8, // 1 while the entry is claimed by Bucket::try_reserve but not written yet. Fits in what was padding.
     pub num_slots: Slot, // can this be smaller? epoch size should ~ be the max len. this is the num elements in the slot list
 }
 
+// the layout of index files depends on this
+const _: [(); 64] = [(); std::mem::size_of::<IndexEntry>()];
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+// what Bucket::rebuild_index needs to recreate the index entry of a data cell
+// stored in front of the value when BucketMapConfig::data_cell_keys is set
+pub struct DataCellPrefix {
+    pub key: Pubkey, // as stored in the index
+    pub ref_count: RefCount,
+    pub num_slots: Slot,
+}
+
+// the layout of data files depends on this
+const _: [(); 48] = [(); std::mem::size_of::<DataCellPrefix>()];
+
 impl IndexEntry {
     pub fn data_bucket_from_num_slots(num_slots: Slot) -> u64 {
         (num_slots as f64).log2().ceil() as u64 // use int log here?
@@ -33,26 +51,42 @@ impl IndexEntry {
         self.ref_count
     }
 
+    /// Reserved entries are hidden from readers until they are written
+    pub fn is_reserved(&self) -> bool {
+        self.reserved != 0
+    }
+
     // This function maps the original data location into an index in the current bucket storage.
     // This is coupled with how we resize bucket storages.
     pub fn data_loc(&self, storage: &BucketStorage) -> u64 {
         self.storage_offset << (storage.capacity_pow2 - self.storage_capacity_when_created_pow2)
     }
 
-    pub fn read_value<'a, T>(&self, bucket: &'a Bucket<T>) -> Option<(&'a [T], RefCount)> {
+    pub fn read_value<'a, T: Clone + Copy>(
+        &self,
+        bucket: &'a Bucket<T>,
+    ) -> Option<(&'a [T], RefCount)> {
+        self.try_read_value(bucket).ok()
+    }
+
+    /// Same as read_value, but returns the violation in AssertMode::Resilient when the data cell
+    /// belongs to another key
+    pub fn try_read_value<'a, T: Clone + Copy>(
+        &self,
+        bucket: &'a Bucket<T>,
+    ) -> Result<(&'a [T], RefCount), InvariantViolation> {
         let data_bucket_ix = self.data_bucket_ix();
         let data_bucket = &bucket.data[data_bucket_ix as usize];
         let slice = if self.num_slots > 0 {
             let loc = self.data_loc(data_bucket);
-            let uid = Self::key_uid(&self.key);
-            assert_eq!(uid, bucket.data[data_bucket_ix as usize].uid(loc));
+            bucket.check_data_cell(self, Self::key_uid(&self.key))?;
             bucket.data[data_bucket_ix as usize].get_cell_slice(loc, self.num_slots)
         } else {
             // num_slots is 0. This means we don't have an actual allocation.
             // can we trust that the data_bucket is even safe?
             bucket.data[data_bucket_ix as usize].get_empty_cell_slice()
         };
-        Some((slice, self.ref_count))
+        Ok((slice, self.ref_count))
     }
     pub fn key_uid(key: &Pubkey) -> Uid {
         let mut s = DefaultHasher::new();
@@ -0,0 +1,286 @@
+//! KvIndex is a DiskIndex stored in RocksDB instead of memory mapped files.
+//! It is meant for drives that behave poorly under mmap, such as network filesystems.
+//! Values are stored as the little endian ref count followed by the raw bytes of the slot list.
+
+use crate::bucket_item::BucketItem;
+use crate::bucket_map::{
+    read_be_u64, BucketAssignment, BucketMapConfig, BucketMapError, ConfigError, RefCountMode,
+};
+use crate::bucket_stats::{BucketMapStats, BucketMapStatsSnapshot, DEFAULT_RATE_WINDOW};
+use crate::clock::SystemClock;
+use crate::disk_index::DiskIndex;
+use crate::RefCount;
+use rocksdb::{Direction, IteratorMode, Options, DB};
+use solana_measure::measure::Measure;
+use solana_sdk::pubkey::Pubkey;
+use std::fmt::Debug;
+use std::fs;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+pub struct KvIndex<T> {
+    // declared before temp_dir so the db is closed before its directory is removed
+    db: DB,
+    // serializes read-modify-write operations within a bucket
+    locks: Vec<Mutex<()>>,
+    max_buckets_pow2: u32,
+    ref_count_mode: RefCountMode,
+    pub stats: BucketMapStats,
+    pub temp_dir: Option<TempDir>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> std::fmt::Debug for KvIndex<T> {
+    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Ok(())
+    }
+}
+
+impl<T: Clone + Copy + Debug> KvIndex<T> {
+    /// Open a KvIndex in the first of `config.drives`, erasing whatever was there.
+    /// Panics on a config try_new rejects.
+    pub fn new(config: BucketMapConfig) -> Self {
+        Self::try_new(config).unwrap_or_else(|err| panic!("Invalid BucketMapConfig: {:?}", err))
+    }
+
+    /// Same as new, but returns an error for a config the index can't honor, before the drive
+    /// is erased. Only BucketAssignment::Prefix without a bucket_hash_key is supported, since
+    /// buckets are read back as contiguous ranges of Pubkeys, and none of the settings that
+    /// change what the map does beyond storing entries: change_feed, throttle, max_value_len,
+    /// replicas, tiering, data_cell_keys and encryption_key. The settings that size and tune the
+    /// memory mapped files, such as max_search and index_capacity_pow2, have no effect.
+    pub fn try_new(config: BucketMapConfig) -> Result<Self, ConfigError> {
+        Self::check_config(&config)?;
+        let mut temp_dir = None;
+        let path = match config.drives.as_ref().and_then(|drives| drives.first()) {
+            Some(path) => {
+                let _ = fs::remove_dir_all(path);
+                let _ = fs::create_dir_all(path);
+                path.clone()
+            }
+            None => {
+                temp_dir = Some(TempDir::new().unwrap());
+                temp_dir.as_ref().unwrap().path().to_path_buf()
+            }
+        };
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        let db = DB::open(&options, &path).unwrap();
+        let mut locks = Vec::with_capacity(config.max_buckets);
+        locks.resize_with(config.max_buckets, Mutex::default);
+        Ok(Self {
+            db,
+            locks,
+            max_buckets_pow2: config.max_buckets.trailing_zeros(),
+            ref_count_mode: config.ref_count_mode,
+            // operations are counted in the shared counters, there is no shard per bucket
+            stats: BucketMapStats::new_with_clock(
+                0,
+                config.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+                config.rate_window.unwrap_or(DEFAULT_RATE_WINDOW),
+            ),
+            temp_dir,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn check_config(config: &BucketMapConfig) -> Result<(), ConfigError> {
+        if config.max_buckets == 0 {
+            return Err(ConfigError::NoBuckets);
+        }
+        if !config.max_buckets.is_power_of_two() {
+            return Err(ConfigError::MaxBucketsNotPowerOfTwo(config.max_buckets));
+        }
+        let unsupported = [
+            (
+                config.bucket_assignment != BucketAssignment::Prefix,
+                "bucket_assignment",
+            ),
+            (config.bucket_hash_key.is_some(), "bucket_hash_key"),
+            (config.change_feed.is_some(), "change_feed"),
+            (config.throttle.is_some(), "throttle"),
+            (config.max_value_len.is_some(), "max_value_len"),
+            (config.replicas.is_some(), "replicas"),
+            (config.tiering.is_some(), "tiering"),
+            (config.data_cell_keys, "data_cell_keys"),
+        ];
+        // values would go into the db in plaintext
+        #[cfg(feature = "encryption")]
+        if config.encryption_key.is_some() {
+            return Err(ConfigError::UnsupportedByKvBackend("encryption_key"));
+        }
+        match unsupported.iter().find(|(set, _)| *set) {
+            Some((_, setting)) => Err(ConfigError::UnsupportedByKvBackend(setting)),
+            None => Ok(()),
+        }
+    }
+
+    fn encode(value: &[T], ref_count: RefCount) -> Vec<u8> {
+        let value_bytes = unsafe {
+            std::slice::from_raw_parts(value.as_ptr() as *const u8, std::mem::size_of_val(value))
+        };
+        let mut bytes = Vec::with_capacity(size_of::<RefCount>() + value_bytes.len());
+        bytes.extend_from_slice(&ref_count.to_le_bytes());
+        bytes.extend_from_slice(value_bytes);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> (Vec<T>, RefCount) {
+        let (ref_count, value_bytes) = bytes.split_at(size_of::<RefCount>());
+        let mut ref_count_bytes = [0u8; size_of::<RefCount>()];
+        ref_count_bytes.copy_from_slice(ref_count);
+        let len = value_bytes.len() / size_of::<T>().max(1);
+        let ptr = value_bytes.as_ptr() as *const T;
+        let value = (0..len)
+            .map(|i| unsafe { std::ptr::read_unaligned(ptr.add(i)) })
+            .collect();
+        (value, RefCount::from_le_bytes(ref_count_bytes))
+    }
+
+    fn get(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        self.db
+            .get(key.as_ref())
+            .unwrap()
+            .map(|bytes| Self::decode(&bytes))
+    }
+
+    fn put(&self, key: &Pubkey, value: &[T], ref_count: RefCount) {
+        self.db
+            .put(key.as_ref(), Self::encode(value, ref_count))
+            .unwrap();
+    }
+
+    /// Apply `f` to the entries of bucket `ix`, in Pubkey order
+    fn scan_bucket<F: FnMut(Pubkey, &[u8])>(&self, ix: usize, mut f: F) {
+        let mut start = [0u8; 32];
+        if self.max_buckets_pow2 > 0 {
+            let prefix = (ix as u64) << (u64::BITS - self.max_buckets_pow2);
+            start[..size_of::<u64>()].copy_from_slice(&prefix.to_be_bytes());
+        }
+        for (key, value) in self
+            .db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+        {
+            let pubkey = Pubkey::new(&key);
+            if self.bucket_ix(&pubkey) != ix {
+                break;
+            }
+            f(pubkey, &value);
+        }
+    }
+
+    /// Panics if `f` fails, like BucketMap::addref and unref
+    fn update_ref_count<F: Fn(RefCount) -> Result<RefCount, BucketMapError>>(
+        &self,
+        key: &Pubkey,
+        f: F,
+    ) -> Option<RefCount> {
+        let _lock = self.locks[self.bucket_ix(key)].lock().unwrap();
+        let (value, ref_count) = self.get(key)?;
+        let ref_count = f(ref_count).expect("Unable to update ref count");
+        self.put(key, &value, ref_count);
+        Some(ref_count)
+    }
+}
+
+impl<T: Clone + Copy + Debug> DiskIndex<T> for KvIndex<T> {
+    fn num_buckets(&self) -> usize {
+        self.locks.len()
+    }
+
+    fn bucket_ix(&self, key: &Pubkey) -> usize {
+        if self.max_buckets_pow2 > 0 {
+            (read_be_u64(key.as_ref()) >> (u64::BITS - self.max_buckets_pow2)) as usize
+        } else {
+            0
+        }
+    }
+
+    fn read_value(&self, key: &Pubkey) -> Option<(Vec<T>, RefCount)> {
+        let mut m = Measure::start("read");
+        let result = self.get(key);
+        m.stop();
+        self.stats.record(&self.stats.read, m.as_us(), None);
+        result
+    }
+
+    fn insert(&self, key: &Pubkey, value: (&[T], RefCount)) {
+        let mut m = Measure::start("insert");
+        let lock = self.locks[self.bucket_ix(key)].lock().unwrap();
+        self.put(key, value.0, value.1);
+        drop(lock);
+        m.stop();
+        self.stats.record(&self.stats.insert, m.as_us(), None);
+    }
+
+    fn update<F>(&self, key: &Pubkey, updatefn: F)
+    where
+        F: Fn(Option<(&[T], RefCount)>) -> Option<(Vec<T>, RefCount)>,
+    {
+        let mut m = Measure::start("update");
+        let lock = self.locks[self.bucket_ix(key)].lock().unwrap();
+        let current = self.get(key);
+        let new = updatefn(
+            current
+                .as_ref()
+                .map(|(value, ref_count)| (&value[..], *ref_count)),
+        );
+        match new {
+            Some((value, ref_count)) => self.put(key, &value, ref_count),
+            None => self.db.delete(key.as_ref()).unwrap(),
+        }
+        drop(lock);
+        m.stop();
+        self.stats.record(&self.stats.update, m.as_us(), None);
+    }
+
+    fn delete_key(&self, key: &Pubkey) {
+        let mut m = Measure::start("delete");
+        let lock = self.locks[self.bucket_ix(key)].lock().unwrap();
+        self.db.delete(key.as_ref()).unwrap();
+        drop(lock);
+        m.stop();
+        self.stats.record(&self.stats.delete, m.as_us(), None);
+    }
+
+    fn addref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mode = self.ref_count_mode;
+        self.update_ref_count(key, |ref_count| mode.addref(ref_count))
+    }
+
+    fn unref(&self, key: &Pubkey) -> Option<RefCount> {
+        let mode = self.ref_count_mode;
+        self.update_ref_count(key, |ref_count| mode.unref(ref_count))
+    }
+
+    fn items_in_range<R>(&self, ix: usize, range: &Option<&R>) -> Vec<BucketItem<T>>
+    where
+        R: RangeBounds<Pubkey>,
+    {
+        let mut items = vec![];
+        self.scan_bucket(ix, |pubkey, bytes| {
+            if range.map(|r| r.contains(&pubkey)).unwrap_or(true) {
+                let (slot_list, ref_count) = Self::decode(bytes);
+                items.push(BucketItem {
+                    pubkey,
+                    ref_count,
+                    slot_list,
+                });
+            }
+        });
+        items
+    }
+
+    fn keys(&self, ix: usize) -> Vec<Pubkey> {
+        let mut keys = vec![];
+        self.scan_bucket(ix, |pubkey, _| keys.push(pubkey));
+        keys
+    }
+
+    fn stats_snapshot(&self) -> BucketMapStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
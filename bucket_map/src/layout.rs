@@ -0,0 +1,166 @@
+//! Layout of the cells in a storage file.
+//! Every cell is a header followed by the cell's elements. The elements start at the header
+//! size rounded up to the element alignment, and cells are padded to a multiple of the element
+//! alignment so the header and elements of every cell stay aligned. For 8 byte values this
+//! means no padding at all: a cell of n u64s is 8 + 8n bytes.
+
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+
+/// Size of the header in front of every cell, see bucket_storage::Header
+pub const HEADER_BYTES: u64 = 8;
+/// Alignment of the header
+pub(crate) const HEADER_ALIGN: u64 = 8;
+/// Largest element alignment a storage can provide, as files are only mapped at page boundaries
+pub const MAX_ELEMENT_ALIGN: u64 = 4096;
+/// Largest storage file, as cells are read through slices of the mapped file
+pub const MAX_STORAGE_BYTES: u64 = isize::MAX as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellLayout {
+    /// offset of the first element in a cell
+    pub element_offset: u64,
+    /// size of one element
+    pub element_bytes: u64,
+    /// alignment of every cell and element
+    pub align: u64,
+    /// bytes of per cell metadata between the header and the elements, see with_prefix
+    pub prefix_bytes: u64,
+}
+
+impl CellLayout {
+    /// Layout for elements of type `T`, aligned to `align` instead of T's own alignment if set.
+    /// Panics if `align` isn't a power of two between align_of::<T>() and MAX_ELEMENT_ALIGN.
+    pub fn new<T>(align: Option<u64>) -> Self {
+        ElementLayout::<T>::assert_valid();
+        let element_align = align_of::<T>() as u64;
+        let align = align.unwrap_or(element_align);
+        assert!(
+            Self::valid_align::<T>(align),
+            "Element alignment {} has to be a power of two between {} and {}",
+            align,
+            element_align,
+            MAX_ELEMENT_ALIGN
+        );
+        let align = std::cmp::max(align, HEADER_ALIGN);
+        Self {
+            element_offset: round_up(HEADER_BYTES, align),
+            element_bytes: size_of::<T>() as u64,
+            align,
+            prefix_bytes: 0,
+        }
+    }
+
+    /// Whether `align` is an alignment CellLayout::new accepts for elements of type `T`
+    pub fn valid_align<T>(align: u64) -> bool {
+        align.is_power_of_two() && align >= align_of::<T>() as u64 && align <= MAX_ELEMENT_ALIGN
+    }
+
+    /// This layout with `prefix_bytes` of metadata in front of the elements of every cell
+    pub fn with_prefix(self, prefix_bytes: u64) -> Self {
+        Self {
+            element_offset: round_up(HEADER_BYTES + prefix_bytes, self.align),
+            prefix_bytes,
+            ..self
+        }
+    }
+
+    /// Size of a cell holding `num_elems` elements
+    pub fn cell_bytes(&self, num_elems: u64) -> u64 {
+        round_up(
+            self.element_offset + self.element_bytes * num_elems,
+            self.align,
+        )
+    }
+
+    /// cell_bytes, or None if the cell would be larger than MAX_STORAGE_BYTES
+    pub fn checked_cell_bytes(&self, num_elems: u64) -> Option<u64> {
+        self.element_bytes
+            .checked_mul(num_elems)?
+            .checked_add(self.element_offset + self.align - 1)
+            .map(|bytes| bytes & !(self.align - 1))
+            .filter(|bytes| *bytes <= MAX_STORAGE_BYTES)
+    }
+}
+
+/// Compile time checks of an element type.
+/// An element type has to:
+/// - be larger than 0 bytes, as value lengths are derived from byte lengths, e.g. by encryption
+/// - have a size that is a multiple of its alignment, so every element of a cell is aligned
+/// - be aligned to at most MAX_ELEMENT_ALIGN
+///
+/// The alignment a map is configured with is checked at runtime, by CellLayout::new.
+pub struct ElementLayout<T>(PhantomData<T>);
+
+impl<T> ElementLayout<T> {
+    // indexing out of bounds fails the build for any T this is evaluated for
+    const VALID: () = [()][(size_of::<T>() == 0
+        || size_of::<T>() % align_of::<T>() != 0
+        || align_of::<T>() as u64 > MAX_ELEMENT_ALIGN) as usize];
+
+    /// Fails to compile for element types that can't be stored in a storage
+    pub fn assert_valid() {
+        Self::VALID
+    }
+}
+
+fn round_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_layout() {
+        // no padding for 8 byte values
+        let layout = CellLayout::new::<u64>(None);
+        assert_eq!(layout.element_offset, HEADER_BYTES);
+        for num_elems in 0..8 {
+            assert_eq!(layout.cell_bytes(num_elems), HEADER_BYTES + 8 * num_elems);
+        }
+
+        // cells stay a multiple of the header's alignment
+        let layout = CellLayout::new::<u32>(None);
+        assert_eq!(layout.cell_bytes(1), 16);
+        assert_eq!(layout.cell_bytes(3), 24);
+
+        #[repr(align(32))]
+        struct Aligned {
+            _bytes: [u8; 32],
+        }
+        let layout = CellLayout::new::<Aligned>(None);
+        assert_eq!(layout.element_offset, 32);
+        assert_eq!(layout.cell_bytes(1), 64);
+
+        let layout = CellLayout::new::<u64>(Some(64));
+        assert_eq!(layout.element_offset, 64);
+        assert_eq!(layout.cell_bytes(1), 128);
+        assert_eq!(layout.cell_bytes(8), 128);
+
+        // the prefix comes after the header, the elements stay aligned
+        let layout = CellLayout::new::<u64>(None).with_prefix(48);
+        assert_eq!(layout.element_offset, HEADER_BYTES + 48);
+        assert_eq!(layout.cell_bytes(1), HEADER_BYTES + 56);
+        let layout = CellLayout::new::<u64>(Some(64)).with_prefix(48);
+        assert_eq!(layout.element_offset, 64);
+        let layout = CellLayout::new::<u64>(Some(64)).with_prefix(57);
+        assert_eq!(layout.element_offset, 128);
+
+        for num_elems in [0, 1, 7, 100] {
+            assert_eq!(
+                layout.checked_cell_bytes(num_elems),
+                Some(layout.cell_bytes(num_elems))
+            );
+        }
+        assert_eq!(layout.checked_cell_bytes(MAX_STORAGE_BYTES / 8), None);
+        assert_eq!(layout.checked_cell_bytes(u64::MAX), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Element alignment")]
+    fn test_cell_layout_underaligned() {
+        CellLayout::new::<u64>(Some(4));
+    }
+}
@@ -1,10 +1,46 @@
 #![allow(clippy::integer_arithmetic)]
+pub mod assert_mode;
 mod bucket;
-mod bucket_item;
+pub mod bucket_item;
 pub mod bucket_map;
-mod bucket_stats;
+pub mod bucket_stats;
 mod bucket_storage;
+pub mod cancel;
+pub mod capacity_hints;
+pub mod change_feed;
+pub mod check;
+pub mod clock;
+pub mod coalescing;
+pub mod debug_export;
+pub mod disk_index;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "fail-points")]
+pub mod fail_points;
+// always compiled so storages don't need a cfg at every IO call, but only reachable with the feature
+#[cfg(not(feature = "fail-points"))]
+#[allow(dead_code)]
+mod fail_points;
+pub mod grow_pool;
 mod index_entry;
+#[cfg(feature = "rocksdb")]
+pub mod kv_index;
+pub mod layout;
+pub mod membership;
+pub mod memory_usage;
+pub mod metadata;
+pub mod multimap;
+pub mod prefetch_iter;
+mod priority;
+pub mod progress;
+pub mod replica;
+mod scratch_pool;
+pub mod staged_writes;
+pub mod throttle;
+pub mod tiering;
+pub mod trace;
+pub mod value_codec;
+pub mod value_report;
 
 pub type MaxSearch = u8;
 pub type RefCount = u64;
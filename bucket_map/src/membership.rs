@@ -0,0 +1,181 @@
+//! Compact set of the keys of a map, for "definitely not in the index" checks that don't touch
+//! the map. This is a xor filter with 8 bit fingerprints: about 10 bits per key and a false
+//! positive rate of about 1 in 256, with no false negatives.
+//! See Graf and Lemire, "Xor Filters: Faster and Smaller Than Bloom and Cuckoo Filters".
+
+use rand::{thread_rng, Rng};
+use siphasher::sip::SipHasher24;
+use solana_sdk::pubkey::Pubkey;
+use std::hash::Hasher;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketMembership {
+    seed: u64,
+    block_length: u32,
+    fingerprints: Vec<u8>,
+}
+
+impl BucketMembership {
+    /// Filter of `keys`. Repeated keys are counted once.
+    pub fn new(keys: &[Pubkey]) -> Self {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.is_empty() {
+            return Self {
+                seed: 0,
+                block_length: 0,
+                fingerprints: vec![],
+            };
+        }
+        let capacity = 32 + (keys.len() as f64 * 1.23).ceil() as usize;
+        let block_length = (capacity / 3) as u32;
+        let len = block_length as usize * 3;
+        let mut rng = thread_rng();
+        loop {
+            let seed = rng.gen();
+            let hashes = keys
+                .iter()
+                .map(|key| Self::hash(seed, key))
+                .collect::<Vec<_>>();
+            if let Some(order) = Self::peel(&hashes, block_length, len) {
+                let mut fingerprints = vec![0u8; len];
+                for (slot, hash) in order.into_iter().rev() {
+                    // the slot itself is still 0
+                    fingerprints[slot] = Self::slots(hash, block_length)
+                        .iter()
+                        .fold(Self::fingerprint(hash), |fp, slot| fp ^ fingerprints[*slot]);
+                }
+                return Self {
+                    seed,
+                    block_length,
+                    fingerprints,
+                };
+            }
+            // a cycle in the hypergraph, retry with another seed
+        }
+    }
+
+    /// Find an order in which every hash has a slot no later hash uses.
+    /// Returns None if there is none under this seed.
+    fn peel(hashes: &[u64], block_length: u32, len: usize) -> Option<Vec<(usize, u64)>> {
+        let mut count = vec![0u32; len];
+        let mut xor = vec![0u64; len];
+        for hash in hashes {
+            for slot in Self::slots(*hash, block_length).iter() {
+                count[*slot] += 1;
+                xor[*slot] ^= hash;
+            }
+        }
+        let mut queue = (0..len)
+            .filter(|slot| count[*slot] == 1)
+            .collect::<Vec<_>>();
+        let mut order = Vec::with_capacity(hashes.len());
+        while let Some(slot) = queue.pop() {
+            if count[slot] != 1 {
+                continue;
+            }
+            // the only hash left in this slot
+            let hash = xor[slot];
+            order.push((slot, hash));
+            for other in Self::slots(hash, block_length).iter() {
+                count[*other] -= 1;
+                xor[*other] ^= hash;
+                if count[*other] == 1 {
+                    queue.push(*other);
+                }
+            }
+        }
+        if order.len() == hashes.len() {
+            Some(order)
+        } else {
+            None
+        }
+    }
+
+    /// False if `key` is definitely not in the set, true if it probably is
+    pub fn may_contain(&self, key: &Pubkey) -> bool {
+        if self.fingerprints.is_empty() {
+            return false;
+        }
+        let hash = Self::hash(self.seed, key);
+        Self::slots(hash, self.block_length)
+            .iter()
+            .fold(Self::fingerprint(hash), |fp, slot| {
+                fp ^ self.fingerprints[*slot]
+            })
+            == 0
+    }
+
+    /// Bytes of memory the filter takes
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.fingerprints.len()
+    }
+
+    fn hash(seed: u64, key: &Pubkey) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(seed, 0);
+        hasher.write(key.as_ref());
+        hasher.finish()
+    }
+
+    /// One slot in each of the three blocks
+    fn slots(hash: u64, block_length: u32) -> [usize; 3] {
+        let reduce = |hash: u64| ((hash as u32 as u64 * block_length as u64) >> 32) as usize;
+        let block_length = block_length as usize;
+        [
+            reduce(hash),
+            reduce(hash.rotate_left(21)) + block_length,
+            reduce(hash.rotate_left(42)) + 2 * block_length,
+        ]
+    }
+
+    fn fingerprint(hash: u64) -> u8 {
+        (hash ^ (hash >> 32)) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+
+    #[test]
+    fn test_membership() {
+        let empty = BucketMembership::new(&[]);
+        assert!(!empty.may_contain(&Pubkey::new_unique()));
+
+        for len in [1, 10, 1000, 20_000].iter() {
+            let keys = (0..*len).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+            let membership = BucketMembership::new(&keys);
+            assert!(keys.iter().all(|key| membership.may_contain(key)));
+            let false_positives = (0..10_000)
+                .filter(|_| membership.may_contain(&Pubkey::new_unique()))
+                .count();
+            assert!(false_positives < 100, "{}", false_positives);
+            // about 10 bits per key, on top of 32 spare fingerprints
+            assert!(membership.size_bytes() <= 100 + *len * 5 / 4);
+        }
+
+        // repeated keys
+        let key = Pubkey::new_unique();
+        assert!(BucketMembership::new(&[key, key, key]).may_contain(&key));
+    }
+
+    #[test]
+    fn test_export_membership() {
+        let map = BucketMap::<u64>::new(BucketMapConfig::new(4));
+        assert!(!map.export_membership().may_contain(&Pubkey::new_unique()));
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![], 0)));
+        }
+        map.delete_key(&keys[0]);
+        let membership = map.export_membership();
+        assert!(keys[1..].iter().all(|key| membership.may_contain(key)));
+        // same size as a filter of just the keys left
+        assert_eq!(
+            membership.size_bytes(),
+            BucketMembership::new(&keys[1..]).size_bytes()
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Memory accounting for a BucketMap, to tell resident index mmaps apart from heap growth when
+//! the validator's RSS climbs.
+
+/// Memory used by one bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BucketMemoryUsage {
+    /// size of the bucket's memory mapped files
+    pub mapped_bytes: u64,
+    /// part of mapped_bytes currently resident, as reported by mincore. Always 0 on platforms
+    /// without mincore.
+    pub resident_bytes: u64,
+    /// estimate of the bucket's heap allocations
+    pub heap_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// one entry per bucket, all zero for buckets that have not been created yet
+    pub buckets: Vec<BucketMemoryUsage>,
+    /// heap used by the map itself, outside of any bucket
+    pub map_heap_bytes: u64,
+}
+
+impl MemoryReport {
+    pub fn mapped_bytes(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.mapped_bytes).sum()
+    }
+
+    pub fn resident_bytes(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.resident_bytes)
+            .sum()
+    }
+
+    /// heap of the map and every bucket
+    pub fn heap_bytes(&self) -> u64 {
+        self.map_heap_bytes
+            + self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.heap_bytes)
+                .sum::<u64>()
+    }
+}
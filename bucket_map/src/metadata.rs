@@ -0,0 +1,213 @@
+//! What a process taking over the files of BucketMap::into_persistent needs to find keys in them:
+//! how Pubkeys are assigned to buckets, the key they are hashed with and the random offset each
+//! bucket's index is hashed with. into_persistent writes it to METADATA_FILE on every drive, next
+//! to the bucket files.
+//!
+//! The format is plain text, one item per line, with tab separated fields:
+//! ```text
+//! version      1
+//! max_buckets  <n>
+//! assignment   prefix | jump_consistent_hash
+//! hash_key     none | <hex> <hex>
+//! bucket       <ix> <hex random> <index file> <data file>...
+//! ```
+
+use crate::bucket_map::{BucketAssignment, BucketHashKey};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the metadata file on each drive
+pub const METADATA_FILE: &str = "bucket_map.meta";
+
+const VERSION: u64 = 1;
+
+/// The files of one bucket and the offset its index is hashed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketMetadata {
+    pub random: u64,
+    pub index_file: PathBuf,
+    pub data_files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapMetadata {
+    pub max_buckets: usize,
+    pub bucket_assignment: BucketAssignment,
+    pub bucket_hash_key: Option<BucketHashKey>,
+    /// None for buckets that were never allocated
+    pub buckets: Vec<Option<BucketMetadata>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    match path.to_str() {
+        Some(path) if !path.contains(&['\t', '\n'][..]) => Ok(path),
+        _ => Err(invalid(format!("unsupported path {:?}", path))),
+    }
+}
+
+fn parse_hex(field: Option<&str>) -> io::Result<u64> {
+    let field = field.ok_or_else(|| invalid("missing field".to_string()))?;
+    u64::from_str_radix(field, 16).map_err(|err| invalid(format!("{}: {}", field, err)))
+}
+
+impl MapMetadata {
+    /// Write the metadata to `path`, replacing any previous file
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = String::new();
+        out += &format!("version\t{}\n", VERSION);
+        out += &format!("max_buckets\t{}\n", self.max_buckets);
+        out += match self.bucket_assignment {
+            BucketAssignment::Prefix => "assignment\tprefix\n",
+            BucketAssignment::JumpConsistentHash => "assignment\tjump_consistent_hash\n",
+        };
+        match self.bucket_hash_key {
+            Some(BucketHashKey([k0, k1])) => out += &format!("hash_key\t{:x}\t{:x}\n", k0, k1),
+            None => out += "hash_key\tnone\n",
+        }
+        for (ix, bucket) in self.buckets.iter().enumerate() {
+            if let Some(bucket) = bucket {
+                out += &format!(
+                    "bucket\t{}\t{:x}\t{}",
+                    ix,
+                    bucket.random,
+                    path_str(&bucket.index_file)?
+                );
+                for file in bucket.data_files.iter() {
+                    out += "\t";
+                    out += path_str(file)?;
+                }
+                out += "\n";
+            }
+        }
+        let mut file = File::create(path)?;
+        file.write_all(out.as_bytes())?;
+        file.sync_all()
+    }
+
+    /// Read metadata written by `write`
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut version = None;
+        let mut max_buckets = None;
+        let mut bucket_assignment = None;
+        let mut bucket_hash_key = None;
+        let mut buckets = vec![];
+        for line in contents.lines() {
+            let mut fields = line.split('\t');
+            match fields.next() {
+                Some("version") => {
+                    version = fields.next().and_then(|field| field.parse::<u64>().ok());
+                }
+                Some("max_buckets") => {
+                    max_buckets = fields.next().and_then(|field| field.parse::<usize>().ok());
+                }
+                Some("assignment") => {
+                    bucket_assignment = match fields.next() {
+                        Some("prefix") => Some(BucketAssignment::Prefix),
+                        Some("jump_consistent_hash") => Some(BucketAssignment::JumpConsistentHash),
+                        other => return Err(invalid(format!("unknown assignment {:?}", other))),
+                    };
+                }
+                Some("hash_key") => {
+                    bucket_hash_key = Some(match fields.next() {
+                        Some("none") => None,
+                        k0 => Some(BucketHashKey([parse_hex(k0)?, parse_hex(fields.next())?])),
+                    });
+                }
+                Some("bucket") => {
+                    let ix = fields
+                        .next()
+                        .and_then(|field| field.parse::<usize>().ok())
+                        .ok_or_else(|| invalid(format!("bad bucket line {:?}", line)))?;
+                    let random = parse_hex(fields.next())?;
+                    let index_file = fields
+                        .next()
+                        .map(PathBuf::from)
+                        .ok_or_else(|| invalid(format!("bad bucket line {:?}", line)))?;
+                    if buckets.len() <= ix {
+                        buckets.resize(ix + 1, None);
+                    }
+                    buckets[ix] = Some(BucketMetadata {
+                        random,
+                        index_file,
+                        data_files: fields.map(PathBuf::from).collect(),
+                    });
+                }
+                _ => return Err(invalid(format!("unknown line {:?}", line))),
+            }
+        }
+        if version != Some(VERSION) {
+            return Err(invalid(format!("unsupported version {:?}", version)));
+        }
+        let max_buckets = max_buckets.ok_or_else(|| invalid("missing max_buckets".to_string()))?;
+        if buckets.len() > max_buckets {
+            return Err(invalid(format!(
+                "bucket {} out of range of {} buckets",
+                buckets.len() - 1,
+                max_buckets
+            )));
+        }
+        buckets.resize(max_buckets, None);
+        Ok(Self {
+            max_buckets,
+            bucket_assignment: bucket_assignment
+                .ok_or_else(|| invalid("missing assignment".to_string()))?,
+            bucket_hash_key: bucket_hash_key
+                .ok_or_else(|| invalid("missing hash_key".to_string()))?,
+            buckets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(METADATA_FILE);
+        let mut metadata = MapMetadata {
+            max_buckets: 3,
+            bucket_assignment: BucketAssignment::JumpConsistentHash,
+            bucket_hash_key: Some(BucketHashKey([1, u64::MAX])),
+            buckets: vec![
+                Some(BucketMetadata {
+                    random: u64::MAX - 1,
+                    index_file: PathBuf::from("/drive/0.index"),
+                    data_files: vec![PathBuf::from("/drive/0.data"), PathBuf::from("/d/1")],
+                }),
+                None,
+                Some(BucketMetadata {
+                    random: 0,
+                    index_file: PathBuf::from("/drive/2.index"),
+                    data_files: vec![],
+                }),
+            ],
+        };
+        metadata.write(&path).unwrap();
+        assert_eq!(MapMetadata::read(&path).unwrap(), metadata);
+
+        metadata.bucket_assignment = BucketAssignment::Prefix;
+        metadata.bucket_hash_key = None;
+        metadata.buckets.pop();
+        metadata.buckets.push(None);
+        metadata.write(&path).unwrap();
+        assert_eq!(MapMetadata::read(&path).unwrap(), metadata);
+
+        fs::write(&path, "version\t2\n").unwrap();
+        assert!(MapMetadata::read(&path).is_err());
+        metadata.buckets[1] = Some(BucketMetadata {
+            random: 0,
+            index_file: PathBuf::from("tab\tin name"),
+            data_files: vec![],
+        });
+        assert!(metadata.write(&path).is_err());
+    }
+}
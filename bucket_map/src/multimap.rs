@@ -0,0 +1,215 @@
+//! BucketMultiMap keys entries by a Pubkey and a u64 discriminant, such as a slot, and answers
+//! range queries over the discriminants of a Pubkey.
+//! Each entry is stored in its own BucketMap under a key derived from the Pubkey and the
+//! discriminant, so writing one entry doesn't rewrite the others of the same Pubkey. A second
+//! BucketMap keeps the sorted discriminants of every Pubkey.
+//! Entries are written and deleted from within the update of the Pubkey's discriminants, under
+//! the lock of its bucket in the second map, so writes of the same Pubkey can't interleave and
+//! a discriminant is never left without its entry. A concurrent range query reads the
+//! discriminants before the entries, so it may miss an entry that is being inserted or deleted,
+//! but only finds a discriminant without its entry while that entry is being deleted.
+//! The entries map is only ever locked while holding a lock of the discriminants map, never
+//! the other way around.
+
+use crate::bucket_map::{BucketMap, BucketMapConfig};
+use crate::RefCount;
+use solana_sdk::hash::hashv;
+use solana_sdk::pubkey::Pubkey;
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::ops::RangeBounds;
+
+pub struct BucketMultiMap<T: Clone + Copy + Debug> {
+    entries: BucketMap<T>,
+    discriminants: BucketMap<u64>,
+}
+
+impl<T: Clone + Copy + Debug> BucketMultiMap<T> {
+    /// Both maps use `config`, with their files in "entries" and "discriminants" directories of
+    /// each drive. Capacity hints only apply to the entries. Change feeds aren't supported.
+    pub fn new(config: BucketMapConfig) -> Self {
+        assert!(
+            config.change_feed.is_none(),
+            "BucketMultiMap doesn't support change feeds"
+        );
+        let with_drives = |dir: &str| BucketMapConfig {
+            drives: config
+                .drives
+                .as_ref()
+                .map(|drives| drives.iter().map(|drive| drive.join(dir)).collect()),
+            ..config.clone()
+        };
+        Self {
+            entries: BucketMap::new(with_drives("entries")),
+            discriminants: BucketMap::new(BucketMapConfig {
+                capacity_hints: None,
+                ..with_drives("discriminants")
+            }),
+        }
+    }
+
+    /// Key the entry of `key` and `discriminant` is stored under
+    fn entry_key(key: &Pubkey, discriminant: u64) -> Pubkey {
+        Pubkey::new_from_array(hashv(&[key.as_ref(), &discriminant.to_le_bytes()]).to_bytes())
+    }
+
+    /// Set the value of `key` and `discriminant`
+    pub fn insert(&self, key: &Pubkey, discriminant: u64, value: (&[T], RefCount)) {
+        let entry_key = Self::entry_key(key, discriminant);
+        self.discriminants.update(key, |current| {
+            self.entries
+                .insert(self.entries.bucket_ix(&entry_key), &entry_key, value);
+            let mut discriminants = current.map_or_else(Vec::new, |(d, _)| d.to_vec());
+            if let Err(pos) = discriminants.binary_search(&discriminant) {
+                discriminants.insert(pos, discriminant);
+            }
+            Some((discriminants, 0))
+        });
+    }
+
+    pub fn read_value(&self, key: &Pubkey, discriminant: u64) -> Option<(Vec<T>, RefCount)> {
+        self.entries.read_value(&Self::entry_key(key, discriminant))
+    }
+
+    /// Returns true if the entry of `key` and `discriminant` was present
+    pub fn delete(&self, key: &Pubkey, discriminant: u64) -> bool {
+        self.delete_range(key, &(discriminant..=discriminant)) == 1
+    }
+
+    /// Delete the entries of `key` with a discriminant in `range`.
+    /// Returns the number of entries deleted.
+    pub fn delete_range<R: RangeBounds<u64>>(&self, key: &Pubkey, range: &R) -> usize {
+        let deleted = Cell::new(0);
+        self.discriminants.update(key, |current| {
+            let (discriminants, _) = current?;
+            let (removed, kept): (Vec<u64>, Vec<u64>) = discriminants
+                .iter()
+                .partition(|discriminant| range.contains(discriminant));
+            let entry_keys = removed
+                .into_iter()
+                .map(|discriminant| Self::entry_key(key, discriminant))
+                .collect::<Vec<_>>();
+            deleted.set(
+                self.entries
+                    .delete_keys(&entry_keys)
+                    .into_iter()
+                    .filter(|deleted| *deleted)
+                    .count(),
+            );
+            if kept.is_empty() {
+                None
+            } else {
+                Some((kept, 0))
+            }
+        });
+        deleted.get()
+    }
+
+    /// Discriminants of `key` in `range`, in ascending order
+    pub fn discriminants<R: RangeBounds<u64>>(&self, key: &Pubkey, range: &R) -> Vec<u64> {
+        self.discriminants
+            .read_value(key)
+            .map(|(discriminants, _)| {
+                discriminants
+                    .into_iter()
+                    .filter(|discriminant| range.contains(discriminant))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Entries of `key` with a discriminant in `range`, in ascending order of discriminant
+    pub fn range<R: RangeBounds<u64>>(
+        &self,
+        key: &Pubkey,
+        range: &R,
+    ) -> Vec<(u64, Vec<T>, RefCount)> {
+        self.discriminants(key, range)
+            .into_iter()
+            .filter_map(|discriminant| {
+                self.read_value(key, discriminant)
+                    .map(|(value, ref_count)| (discriminant, value, ref_count))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_multimap() {
+        let map = BucketMultiMap::<u64>::new(BucketMapConfig::new(4));
+        let key = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        assert!(map.range(&key, &..).is_empty());
+        for slot in [5, 1, 3, 9, 7].iter() {
+            map.insert(&key, *slot, (&[*slot; 2], 1));
+            map.insert(&other, *slot + 1, (&[*slot], 2));
+        }
+        // overwriting keeps a single discriminant
+        map.insert(&key, 3, (&[30], 3));
+        assert_eq!(map.read_value(&key, 3), Some((vec![30], 3)));
+        assert_eq!(map.read_value(&key, 2), None);
+
+        assert_eq!(map.discriminants(&key, &..), vec![1, 3, 5, 7, 9]);
+        assert_eq!(map.discriminants(&other, &(3..8)), vec![4, 6]);
+        assert_eq!(
+            map.range(&key, &(2..=7)),
+            vec![(3, vec![30], 3), (5, vec![5, 5], 1), (7, vec![7, 7], 1)]
+        );
+        assert!(map.range(&key, &(10..)).is_empty());
+
+        assert!(map.delete(&key, 5));
+        assert!(!map.delete(&key, 5));
+        assert_eq!(map.read_value(&key, 5), None);
+        assert_eq!(map.delete_range(&key, &(..8)), 3);
+        assert_eq!(map.discriminants(&key, &..), vec![9]);
+        assert_eq!(map.delete_range(&key, &..), 1);
+        assert!(map.range(&key, &..).is_empty());
+        assert_eq!(map.discriminants.read_value(&key), None);
+        assert_eq!(map.delete_range(&key, &..), 0);
+        // other keys are untouched
+        assert_eq!(map.discriminants(&other, &..), vec![2, 4, 6, 8, 10]);
+    }
+
+    #[test]
+    fn test_multimap_concurrent_insert_delete() {
+        let map = Arc::new(BucketMultiMap::<u64>::new(BucketMapConfig::new(4)));
+        let key = Pubkey::new_unique();
+        let inserter = {
+            let map = Arc::clone(&map);
+            std::thread::spawn(move || {
+                for slot in 0..500 {
+                    map.insert(&key, slot % 10, (&[slot], 1));
+                }
+            })
+        };
+        for _ in 0..500 {
+            map.delete_range(&key, &..);
+        }
+        inserter.join().unwrap();
+        // every discriminant left has its entry
+        for discriminant in map.discriminants(&key, &..) {
+            assert!(map.read_value(&key, discriminant).is_some());
+        }
+    }
+
+    #[test]
+    fn test_multimap_drives() {
+        let drive = TempDir::new().unwrap();
+        let map = BucketMultiMap::<u64>::new(BucketMapConfig {
+            drives: Some(vec![drive.path().to_path_buf()]),
+            ..BucketMapConfig::new(1)
+        });
+        let key = Pubkey::new_unique();
+        map.insert(&key, 1, (&[1], 0));
+        for dir in ["entries", "discriminants"].iter() {
+            assert!(drive.path().join(dir).read_dir().unwrap().next().is_some());
+        }
+        assert_eq!(map.range(&key, &..), vec![(1, vec![1], 0)]);
+    }
+}
@@ -0,0 +1,152 @@
+//! PrefetchIter walks every bucket of a BucketMap a chunk of index cells at a time.
+//! While the caller processes the current chunk, the data cells of the next chunk and the index
+//! cells of the chunk after it are already being read in, which overlaps IO with processing
+//! during full-map scans like hash calculation.
+//! SnapshotIter does the same and returns every chunk with the counts of its bucket, taken under
+//! the read lock the bucket's chunks are read with, so they are exactly what the iterator
+//! returns for that bucket.
+//! Both hold the read lock of the bucket they are in between calls to next, so a caller that
+//! writes to that bucket deadlocks. The other buckets stay unlocked.
+
+use crate::bucket::Bucket;
+use crate::bucket_item::BucketItem;
+use crate::bucket_map::BucketMap;
+use std::fmt::Debug;
+use std::sync::RwLockReadGuard;
+
+/// Data cells closer than this are prefetched with a single madvise
+pub const PREFETCH_MERGE_BYTES: u64 = 4096;
+
+/// Holds the read lock of the bucket it is in between calls to next
+pub struct PrefetchIter<'a, T: Clone + Copy + Debug> {
+    map: &'a BucketMap<T>,
+    chunk_size: u64,
+    bucket_ix: usize,
+    cell: u64,
+    // the read lock is held for the whole bucket so the bucket can't grow out from under us
+    guard: Option<RwLockReadGuard<'a, Option<Bucket<T>>>>,
+    // counted when a bucket is locked, for SnapshotIter
+    count_entries: bool,
+    bucket_stats: IterStats,
+}
+
+impl<'a, T: Clone + Copy + Debug> PrefetchIter<'a, T> {
+    pub(crate) fn new(map: &'a BucketMap<T>, chunk_size: usize) -> Self {
+        assert_ne!(chunk_size, 0, "chunk size must be non-zero");
+        Self {
+            map,
+            chunk_size: chunk_size as u64,
+            bucket_ix: 0,
+            cell: 0,
+            guard: None,
+            count_entries: false,
+            bucket_stats: IterStats::default(),
+        }
+    }
+}
+
+impl<'a, T: Clone + Copy + Debug> Iterator for PrefetchIter<'a, T> {
+    type Item = Vec<BucketItem<T>>;
+
+    /// Returns the next non-empty chunk of items. Chunks never span buckets.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.guard.is_none() {
+                if self.bucket_ix >= self.map.num_buckets() {
+                    return None;
+                }
+                let guard = self.map.buckets[self.bucket_ix].read().unwrap();
+                if self.count_entries {
+                    let (entries, bytes) =
+                        guard.as_ref().map_or((0, 0), |bucket| bucket.entry_stats());
+                    self.bucket_stats = IterStats { entries, bytes };
+                }
+                start_prefetch(guard.as_ref(), self.chunk_size);
+                self.guard = Some(guard);
+                self.cell = 0;
+            }
+            let bucket = self.guard.as_ref().unwrap().as_ref();
+            match next_chunk(bucket, &mut self.cell, self.chunk_size) {
+                Some(items) => return Some(items),
+                None => {
+                    self.guard = None;
+                    self.bucket_ix += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Prefetch the first chunk of `bucket` and the index cells of the second
+fn start_prefetch<T: Clone + Copy>(bucket: Option<&Bucket<T>>, chunk_size: u64) {
+    if let Some(bucket) = bucket {
+        bucket.prefetch_index(0..2 * chunk_size);
+        bucket.prefetch_data(0..chunk_size);
+    }
+}
+
+/// The next non-empty chunk of `bucket` starting at index cell `cell`, None once the bucket is
+/// done. The chunk's data cells were prefetched by the previous call, or start_prefetch.
+fn next_chunk<T: Clone + Copy>(
+    bucket: Option<&Bucket<T>>,
+    cell: &mut u64,
+    chunk_size: u64,
+) -> Option<Vec<BucketItem<T>>> {
+    let bucket = bucket?;
+    let capacity = bucket.index_capacity();
+    while *cell < capacity {
+        let end = std::cmp::min(*cell + chunk_size, capacity);
+        // the next chunk's index cells were prefetched a chunk ago
+        bucket.prefetch_data(end..end + chunk_size);
+        bucket.prefetch_index(end + chunk_size..end + 2 * chunk_size);
+        let items = bucket.items_in_cells(*cell..end);
+        *cell = end;
+        if !items.is_empty() {
+            return Some(items);
+        }
+    }
+    None
+}
+
+/// Totals of the items of a bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IterStats {
+    pub entries: u64,
+    /// bytes of the values as stored. Unless the map has a ValueCodec or encryption, this is
+    /// what the lengths of the returned values add up to.
+    pub bytes: u64,
+}
+
+/// A chunk of a SnapshotIter
+#[derive(Debug)]
+pub struct SnapshotChunk<T> {
+    pub bucket_ix: usize,
+    pub items: Vec<BucketItem<T>>,
+    /// every entry of the bucket, counted under the read lock its chunks are read with
+    pub bucket_stats: IterStats,
+}
+
+/// Holds the read lock of the bucket it is in between calls to next, like PrefetchIter
+pub struct SnapshotIter<'a, T: Clone + Copy + Debug>(PrefetchIter<'a, T>);
+
+impl<'a, T: Clone + Copy + Debug> SnapshotIter<'a, T> {
+    pub(crate) fn new(map: &'a BucketMap<T>, chunk_size: usize) -> Self {
+        let mut iter = PrefetchIter::new(map, chunk_size);
+        iter.count_entries = true;
+        Self(iter)
+    }
+}
+
+impl<'a, T: Clone + Copy + Debug> Iterator for SnapshotIter<'a, T> {
+    type Item = SnapshotChunk<T>;
+
+    /// Returns the next non-empty chunk of items. Chunks never span buckets.
+    fn next(&mut self) -> Option<Self::Item> {
+        let items = self.0.next()?;
+        Some(SnapshotChunk {
+            bucket_ix: self.0.bucket_ix,
+            items,
+            bucket_stats: self.0.bucket_stats,
+        })
+    }
+}
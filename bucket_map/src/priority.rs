@@ -0,0 +1,88 @@
+//! A priority lane for consensus critical reads, such as those of the vote and leader pipelines.
+//! A critical read announces itself on its bucket's gate before it takes the read lock. Writers
+//! and background scans check the gate before they lock the bucket, and while critical reads are
+//! pending they wait for them, for at most MAX_YIELD so a steady stream of critical reads can't
+//! starve them. Locks that are already held or queued are not preempted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Longest a writer or scan waits for the critical reads of its bucket
+pub(crate) const MAX_YIELD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Default)]
+pub(crate) struct PriorityGate {
+    // critical reads that haven't got the bucket lock yet
+    pending: AtomicU64,
+    // guards waiting for pending to drop to 0
+    lock: Mutex<()>,
+    cleared: Condvar,
+}
+
+impl PriorityGate {
+    /// Announce a critical read until the returned guard is dropped, once it holds the lock
+    pub(crate) fn enter(&self) -> CriticalRead<'_> {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        CriticalRead { gate: self }
+    }
+
+    /// Wait until no critical read is pending, or MAX_YIELD passed.
+    /// Returns whether there was a critical read to wait for.
+    pub(crate) fn yield_to_critical(&self) -> bool {
+        if self.pending.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+        let lock = self.lock.lock().unwrap();
+        let _ = self
+            .cleared
+            .wait_timeout_while(lock, MAX_YIELD, |_| {
+                self.pending.load(Ordering::Acquire) > 0
+            })
+            .unwrap();
+        true
+    }
+}
+
+pub(crate) struct CriticalRead<'a> {
+    gate: &'a PriorityGate,
+}
+
+impl Drop for CriticalRead<'_> {
+    fn drop(&mut self) {
+        if self.gate.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // taking the lock orders this after any waiter's check of pending
+            let _lock = self.gate.lock.lock().unwrap();
+            self.gate.cleared.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_priority_gate() {
+        let gate = Arc::new(PriorityGate::default());
+        assert!(!gate.yield_to_critical());
+
+        let critical = gate.enter();
+        let waiter = {
+            let gate = gate.clone();
+            std::thread::spawn(move || gate.yield_to_critical())
+        };
+        // the waiter returns once the critical read is done, whether it saw it or not
+        drop(critical);
+        waiter.join().unwrap();
+        assert!(!gate.yield_to_critical());
+
+        // a critical read that doesn't finish only holds others up for MAX_YIELD
+        let _critical = gate.enter();
+        let start = Instant::now();
+        assert!(gate.yield_to_critical());
+        assert!(start.elapsed() >= MAX_YIELD);
+    }
+}
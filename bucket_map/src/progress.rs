@@ -0,0 +1,59 @@
+//! Progress reporting for long running operations, so callers can show progress and watchdogs
+//! can tell a slow grow from a hung one.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Number of cells copied or scanned between progress reports
+pub(crate) const PROGRESS_INTERVAL_CELLS: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressOperation {
+    /// rehashing a bucket's index into a larger file
+    GrowIndex,
+    /// copying a data storage into a larger file
+    GrowData,
+    /// BucketMap::gc_data
+    Compaction,
+    /// BucketMap::export_debug
+    Export,
+    /// BucketMap::quick_check
+    Verify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub operation: ProgressOperation,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Called with the progress of an operation every PROGRESS_INTERVAL_CELLS cells, and once more
+/// when the operation completes with processed_bytes == total_bytes
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F: Fn(Progress) + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn report(
+        &self,
+        operation: ProgressOperation,
+        processed_bytes: u64,
+        total_bytes: u64,
+    ) {
+        (self.0)(Progress {
+            operation,
+            processed_bytes,
+            total_bytes,
+        })
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
@@ -0,0 +1,162 @@
+//! Read only replicas of hot buckets.
+//! A replica is a copy of a bucket's files that is refreshed periodically. The `_stale` scans of
+//! BucketMap, e.g. items_in_range_stale, read the replica of a replicated bucket, so they never
+//! hold the bucket's lock and never hold up writers, at the cost of seeing the bucket as of the
+//! last refresh. Every other scan reads the bucket itself.
+
+use crate::bucket::Bucket;
+use rand::rngs::StdRng;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaConfig {
+    /// indexes of the buckets to keep replicas of
+    pub buckets: Vec<usize>,
+    /// how often BucketMap::spawn_replica_refresher refreshes every replica
+    pub refresh_interval: Duration,
+}
+
+/// Double buffered copy of one bucket: scans read `current` while the next refresh is copied
+/// into the previous copy, once no scan is using it anymore
+pub(crate) struct Replica<T> {
+    current: RwLock<Option<Arc<Bucket<T>>>>,
+    // (previous copy, rng for the names of new files). Held for the whole refresh.
+    spare: Mutex<(Option<Bucket<T>>, StdRng)>,
+}
+
+impl<T: Clone + Copy> Replica<T> {
+    pub(crate) fn new(rng: StdRng) -> Self {
+        Self {
+            current: RwLock::default(),
+            spare: Mutex::new((None, rng)),
+        }
+    }
+
+    /// The most recent copy, None until the first refresh
+    pub(crate) fn current(&self) -> Option<Arc<Bucket<T>>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the current copy with a copy of `bucket`
+    pub(crate) fn refresh(&self, bucket: &Bucket<T>) -> io::Result<()> {
+        let mut spare = self.spare.lock().unwrap();
+        let (previous, rng) = &mut *spare;
+        let copy = bucket.copy_to(previous.take(), rng)?;
+        let replaced = self.current.write().unwrap().replace(Arc::new(copy));
+        // a scan that still holds the replaced copy keeps it alive, it is dropped after the scan
+        *previous = replaced.and_then(|replaced| Arc::try_unwrap(replaced).ok());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use solana_sdk::pubkey::Pubkey;
+    use std::fs;
+    use std::ops::RangeInclusive;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Instant;
+
+    fn new_map(refresh_interval: Duration) -> BucketMap<u64> {
+        BucketMap::new(BucketMapConfig {
+            replicas: Some(ReplicaConfig {
+                buckets: vec![0],
+                refresh_interval,
+            }),
+            ..BucketMapConfig::new(1)
+        })
+    }
+
+    fn scan(map: &BucketMap<u64>) -> Vec<Pubkey> {
+        let mut keys = map
+            .items_in_range_stale(0, &None::<&RangeInclusive<Pubkey>>, None)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.pubkey)
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys.len(), map.keys_stale(0, None).unwrap().len());
+        assert_eq!(keys.len(), map.scan_bucket_stale(0).iter().count());
+        let mut items = vec![];
+        map.copy_bucket_stale(0, &mut items);
+        assert_eq!(keys.len(), items.len());
+        keys
+    }
+
+    fn num_files(map: &BucketMap<u64>) -> usize {
+        fs::read_dir(map.temp_dir.as_ref().unwrap().path())
+            .unwrap()
+            .count()
+    }
+
+    #[test]
+    fn test_replica_refresh() {
+        let map = new_map(Duration::from_secs(3600));
+        let mut keys = (0..10).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        keys.sort();
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![1], 1)));
+        }
+        // scans read the bucket until the first refresh
+        assert_eq!(scan(&map), keys);
+        map.refresh_replicas().unwrap();
+        let files = num_files(&map);
+
+        // enough to grow the bucket, which the replica doesn't see until it is refreshed
+        let more_keys = (0..1000).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for key in more_keys.iter() {
+            map.update(key, |_| Some((vec![2], 2)));
+        }
+        assert_eq!(scan(&map), keys);
+        assert_eq!(map.read_value(&more_keys[0]), Some((vec![2], 2)));
+        // scans that aren't stale read the bucket itself
+        let num_keys = keys.len() + more_keys.len();
+        assert_eq!(map.keys(0).len(), num_keys);
+        assert_eq!(
+            map.items_in_range(0, &None::<&RangeInclusive<Pubkey>>)
+                .len(),
+            num_keys
+        );
+        assert_eq!(map.scan_bucket(0).iter().count(), num_keys);
+
+        map.refresh_replicas().unwrap();
+        keys.extend(more_keys);
+        keys.sort();
+        assert_eq!(scan(&map), keys);
+
+        // the previous copy is reused once it has the right shape again
+        map.refresh_replicas().unwrap();
+        let files_after_grow = num_files(&map);
+        assert!(files_after_grow > files);
+        map.refresh_replicas().unwrap();
+        assert_eq!(num_files(&map), files_after_grow);
+    }
+
+    #[test]
+    fn test_replica_refresher() {
+        assert!(BucketMap::<u64>::spawn_replica_refresher(
+            Arc::new(BucketMap::new(BucketMapConfig::new(1))),
+            Arc::default()
+        )
+        .is_none());
+
+        let map = Arc::new(new_map(Duration::from_millis(1)));
+        let exit = Arc::new(AtomicBool::default());
+        let refresher =
+            BucketMap::spawn_replica_refresher(Arc::clone(&map), Arc::clone(&exit)).unwrap();
+        let key = Pubkey::new_unique();
+        map.update(&key, |_| Some((vec![1], 1)));
+        let start = Instant::now();
+        while scan(&map) != [key] {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(1));
+        }
+        exit.store(true, Ordering::Relaxed);
+        refresher.join().unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Mapped files of storages that grew or were dropped, kept for the next storage of the same
+//! size instead of creating, sizing and mapping a new file.
+//! Buckets grow through the same sequence of sizes, so during the grows right after startup the
+//! file one bucket leaves behind is often the size another bucket needs next. A reused file is
+//! zeroed before it is handed out, which is cheaper than the syscalls it replaces.
+
+use memmap2::MmapMut;
+use std::collections::HashMap;
+use std::fs::remove_file;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Regions {
+    bytes: u64,
+    // mapped files by their length
+    by_len: HashMap<u64, Vec<(MmapMut, PathBuf)>>,
+}
+
+pub(crate) struct ScratchPool {
+    max_bytes: u64,
+    regions: Mutex<Regions>,
+}
+
+impl std::fmt::Debug for ScratchPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regions = self.regions.lock().unwrap();
+        f.debug_struct("ScratchPool")
+            .field("max_bytes", &self.max_bytes)
+            .field("bytes", &regions.bytes)
+            .finish()
+    }
+}
+
+impl ScratchPool {
+    /// Pool that holds at most `max_bytes` of files
+    pub(crate) fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            regions: Mutex::default(),
+        }
+    }
+
+    /// Keep the mapped file at `path` for reuse.
+    /// Returns it back if the pool is full, then the caller has to remove the file.
+    pub(crate) fn put(&self, mmap: MmapMut, path: PathBuf) -> Option<(MmapMut, PathBuf)> {
+        let len = mmap.len() as u64;
+        let mut regions = self.regions.lock().unwrap();
+        if regions.bytes + len > self.max_bytes {
+            return Some((mmap, path));
+        }
+        regions.bytes += len;
+        regions.by_len.entry(len).or_default().push((mmap, path));
+        None
+    }
+
+    /// A zeroed mapped file of `len` bytes, if the pool has one
+    pub(crate) fn take(&self, len: u64) -> Option<(MmapMut, PathBuf)> {
+        let (mut mmap, path) = {
+            let mut regions = self.regions.lock().unwrap();
+            let region = regions.by_len.get_mut(&len)?.pop()?;
+            regions.bytes -= len;
+            region
+        };
+        mmap.fill(0);
+        Some((mmap, path))
+    }
+}
+
+impl Drop for ScratchPool {
+    fn drop(&mut self) {
+        let regions = std::mem::take(&mut *self.regions.lock().unwrap());
+        for (mmap, path) in regions.by_len.into_values().flatten() {
+            drop(mmap);
+            let _ = remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use solana_sdk::pubkey::Pubkey;
+    use std::fs::OpenOptions;
+    use tempfile::TempDir;
+
+    fn new_region(dir: &TempDir, name: &str, len: u64) -> (MmapMut, PathBuf) {
+        let path = dir.path().join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(len).unwrap();
+        let mut mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
+        mmap.fill(1);
+        (mmap, path)
+    }
+
+    #[test]
+    fn test_scratch_pool() {
+        let dir = TempDir::new().unwrap();
+        let pool = ScratchPool::new(3 << 12);
+        let (mmap, path) = new_region(&dir, "a", 1 << 12);
+        assert!(pool.put(mmap, path).is_none());
+        let (mmap, path) = new_region(&dir, "b", 2 << 12);
+        assert!(pool.put(mmap, path).is_none());
+        // full
+        let (mmap, path) = new_region(&dir, "c", 1 << 12);
+        let (c_mmap, c_path) = pool.put(mmap, path).unwrap();
+
+        // only exact sizes are handed out
+        assert!(pool.take(4 << 12).is_none());
+        let (mmap, path) = pool.take(2 << 12).unwrap();
+        assert_eq!(path, dir.path().join("b"));
+        assert!(mmap.iter().all(|byte| *byte == 0));
+        assert!(pool.take(2 << 12).is_none());
+        // taking made room
+        assert!(pool.put(c_mmap, c_path).is_none());
+
+        // files still in the pool are removed with it
+        drop(pool);
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("c").exists());
+        assert!(path.exists());
+        drop(mmap);
+    }
+
+    #[test]
+    fn test_scratch_pool_map() {
+        let map = BucketMap::<u64>::new(BucketMapConfig {
+            scratch_pool_bytes: Some(1 << 20),
+            ..BucketMapConfig::new(2)
+        });
+        let keys = |ix| {
+            std::iter::repeat_with(Pubkey::new_unique)
+                .filter(|key| map.bucket_ix(key) == ix)
+                .take(500)
+                .collect::<Vec<_>>()
+        };
+        // bucket 1 grows through the sizes bucket 0 grew through and left in the pool
+        let keys = [keys(0), keys(1)];
+        for (ix, keys) in keys.iter().enumerate() {
+            for (i, key) in keys.iter().enumerate() {
+                map.update(key, |_| Some((vec![i as u64], 0)));
+            }
+            let stats = map.stats_snapshot();
+            assert_eq!(
+                stats.index.scratch_reuses + stats.data.scratch_reuses > 0,
+                ix == 1
+            );
+        }
+        for keys in keys.iter() {
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(map.read_value(key), Some((vec![i as u64], 0)));
+            }
+        }
+    }
+}
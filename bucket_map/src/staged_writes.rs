@@ -0,0 +1,185 @@
+//! Staged writes for phases where many threads insert into the same buckets at once, such as
+//! index generation. Each writer thread appends to its own StagingBuffer without taking any
+//! bucket lock. Full buffers are handed to a merger thread, which groups them by bucket and
+//! applies each group under a single bucket lock.
+
+use crate::bucket_map::BucketMap;
+use crate::RefCount;
+use solana_sdk::pubkey::Pubkey;
+use std::fmt::Debug;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+
+type StagedItem<T> = (Pubkey, Vec<T>, RefCount);
+
+enum Message<T> {
+    Batch(Vec<StagedItem<T>>),
+    /// acked once every batch sent before it has been applied
+    Flush(Sender<()>),
+}
+
+pub struct StagedWrites<T: Clone + Copy + Debug> {
+    sender: Option<Sender<Message<T>>>,
+    merger: Option<JoinHandle<()>>,
+    batch_size: usize,
+}
+
+impl<T: Clone + Copy + Debug + Send + Sync + 'static> StagedWrites<T> {
+    /// Start a merger thread applying staged writes to `map`.
+    /// Writers hand off their buffers every `batch_size` items.
+    pub fn new(map: Arc<BucketMap<T>>, batch_size: usize) -> Self {
+        assert_ne!(batch_size, 0, "batch_size must be non-zero");
+        let (sender, receiver) = channel();
+        let merger = Builder::new()
+            .name("solana-bucket-map-merge".to_string())
+            .spawn(move || Self::merge(&map, receiver))
+            .unwrap();
+        Self {
+            sender: Some(sender),
+            merger: Some(merger),
+            batch_size,
+        }
+    }
+
+    /// Get a buffer for the calling thread to stage writes in
+    pub fn writer(&self) -> StagingBuffer<T> {
+        StagingBuffer {
+            sender: self.sender.as_ref().unwrap().clone(),
+            items: Vec::with_capacity(self.batch_size),
+            batch_size: self.batch_size,
+        }
+    }
+
+    /// Block until everything writers have handed off so far has been applied to the map.
+    /// Writes still sitting in a StagingBuffer are not included, see StagingBuffer::flush.
+    pub fn flush(&self) {
+        let (ack_sender, ack_receiver) = channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Message::Flush(ack_sender))
+            .unwrap();
+        ack_receiver.recv().unwrap();
+    }
+
+    fn merge(map: &BucketMap<T>, receiver: Receiver<Message<T>>) {
+        for message in receiver.iter() {
+            match message {
+                Message::Batch(mut items) => {
+                    items.sort_by_cached_key(|(key, _, _)| map.bucket_ix(key));
+                    let mut start = 0;
+                    while start < items.len() {
+                        let ix = map.bucket_ix(&items[start].0);
+                        let len = items[start..]
+                            .iter()
+                            .position(|(key, _, _)| map.bucket_ix(key) != ix)
+                            .unwrap_or(items.len() - start);
+                        map.insert_batch(ix, &items[start..start + len]);
+                        start += len;
+                    }
+                }
+                Message::Flush(ack) => {
+                    let _ = ack.send(());
+                }
+            }
+        }
+    }
+}
+
+impl<T: Clone + Copy + Debug> Drop for StagedWrites<T> {
+    /// Apply everything that has been handed off, then stop the merger thread.
+    /// The merger keeps running until every StagingBuffer has been dropped too.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(merger) = self.merger.take() {
+            merger.join().unwrap();
+        }
+    }
+}
+
+/// A writer thread's buffer of staged writes.
+/// Writes are not visible in the map until the buffer has been handed off and merged.
+pub struct StagingBuffer<T> {
+    sender: Sender<Message<T>>,
+    items: Vec<StagedItem<T>>,
+    batch_size: usize,
+}
+
+impl<T> StagingBuffer<T> {
+    /// Stage an update of Pubkey `key`'s value to 'value'.
+    /// Later writes of the same key from this buffer win.
+    pub fn insert(&mut self, key: &Pubkey, value: (&[T], RefCount))
+    where
+        T: Clone,
+    {
+        self.items.push((*key, value.0.to_vec(), value.1));
+        if self.items.len() >= self.batch_size {
+            self.flush();
+        }
+    }
+
+    /// Hand off the staged writes to the merger thread
+    pub fn flush(&mut self) {
+        if !self.items.is_empty() {
+            let items = std::mem::replace(&mut self.items, Vec::with_capacity(self.batch_size));
+            self.sender.send(Message::Batch(items)).unwrap();
+        }
+    }
+}
+
+impl<T> Drop for StagingBuffer<T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::BucketMapConfig;
+
+    #[test]
+    fn test_staged_writes() {
+        let map = Arc::new(BucketMap::new(BucketMapConfig::new(1 << 2)));
+        let staged = StagedWrites::new(Arc::clone(&map), 7);
+        let keys = (0..8)
+            .map(|_| {
+                (0..100)
+                    .map(|_| solana_sdk::pubkey::new_rand())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let handles = keys
+            .iter()
+            .cloned()
+            .map(|keys| {
+                let mut writer = staged.writer();
+                std::thread::spawn(move || {
+                    for (i, key) in keys.iter().enumerate() {
+                        writer.insert(key, (&[i as u64], 0));
+                        writer.insert(key, (&[i as u64, 1], 2));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        staged.flush();
+
+        for keys in keys.iter() {
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(map.read_value(key), Some((vec![i as u64, 1], 2)));
+            }
+        }
+
+        // unflushed writes are applied when the buffer is dropped
+        let key = Pubkey::new_unique();
+        let mut writer = staged.writer();
+        writer.insert(&key, (&[3], 1));
+        staged.flush();
+        assert_eq!(map.read_value(&key), None);
+        drop(writer);
+        drop(staged);
+        assert_eq!(map.read_value(&key), Some((vec![3], 1)));
+    }
+}
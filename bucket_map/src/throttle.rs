@@ -0,0 +1,181 @@
+//! Per bucket write throttling.
+//! Writers are never blocked by the bucket map itself. Instead, background flushers can ask
+//! `would_block` and hold off on buckets that are over budget or already busy growing.
+
+use crate::clock::{Clock, SystemClock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Sustained rates a single bucket may be written at before `would_block` reports back pressure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    pub bytes_per_sec: u64,
+    pub grows_per_sec: u64,
+}
+
+/// Tokens accrue at `rate` per second, up to one second worth of burst.
+/// Consuming more than is available puts the bucket into debt, which has to be paid off by time.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    fn consume(&mut self, tokens: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= tokens as f64;
+    }
+
+    fn has(&mut self, tokens: u64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= tokens as f64
+    }
+}
+
+#[derive(Debug)]
+struct Budgets {
+    bytes: TokenBucket,
+    grows: TokenBucket,
+}
+
+#[derive(Debug)]
+pub struct WriteThrottle {
+    budgets: Mutex<Budgets>,
+    // true while the bucket is growing or being compacted
+    busy: AtomicBool,
+    clock: Arc<dyn Clock>,
+}
+
+impl WriteThrottle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Budgets accrue as `clock` advances
+    pub fn with_clock(config: ThrottleConfig, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        Self {
+            budgets: Mutex::new(Budgets {
+                bytes: TokenBucket::new(config.bytes_per_sec, now),
+                grows: TokenBucket::new(config.grows_per_sec, now),
+            }),
+            busy: AtomicBool::default(),
+            clock,
+        }
+    }
+
+    pub fn record_write(&self, bytes: u64) {
+        self.budgets
+            .lock()
+            .unwrap()
+            .bytes
+            .consume(bytes, self.clock.now());
+    }
+
+    pub fn record_grow(&self) {
+        self.budgets
+            .lock()
+            .unwrap()
+            .grows
+            .consume(1, self.clock.now());
+    }
+
+    pub fn set_busy(&self, busy: bool) {
+        self.busy.store(busy, Ordering::Relaxed);
+    }
+
+    /// Returns true if writing `bytes` now would exceed the byte budget, if the bucket has used up
+    /// its grow budget, or if it is currently growing or compacting
+    pub fn would_block(&self, bytes: u64) -> bool {
+        if self.busy.load(Ordering::Relaxed) {
+            return true;
+        }
+        let now = self.clock.now();
+        let mut budgets = self.budgets.lock().unwrap();
+        !budgets.bytes.has(bytes, now) || !budgets.grows.has(1, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, start);
+        assert!(bucket.has(100, start));
+        assert!(!bucket.has(101, start));
+        bucket.consume(150, start);
+        assert!(!bucket.has(1, start));
+        // half a second pays off the debt of 50 and earns 0 more
+        assert!(!bucket.has(1, start + Duration::from_millis(490)));
+        assert!(bucket.has(0, start + Duration::from_millis(500)));
+        // never accrues more than one second of burst
+        assert!(bucket.has(100, start + Duration::from_secs(10)));
+        assert!(!bucket.has(101, start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn test_write_throttle() {
+        let throttle = WriteThrottle::new(ThrottleConfig {
+            bytes_per_sec: 1 << 20,
+            grows_per_sec: 1,
+        });
+        assert!(!throttle.would_block(1024));
+        throttle.set_busy(true);
+        assert!(throttle.would_block(0));
+        throttle.set_busy(false);
+        throttle.record_write(1 << 20);
+        assert!(throttle.would_block(1024));
+
+        let throttle = WriteThrottle::new(ThrottleConfig {
+            bytes_per_sec: 1 << 20,
+            grows_per_sec: 1,
+        });
+        throttle.record_grow();
+        assert!(throttle.would_block(0));
+    }
+
+    #[test]
+    fn test_write_throttle_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let throttle = WriteThrottle::with_clock(
+            ThrottleConfig {
+                bytes_per_sec: 1000,
+                grows_per_sec: 1,
+            },
+            clock.clone(),
+        );
+        throttle.record_write(1500);
+        assert!(throttle.would_block(0));
+        clock.advance(Duration::from_millis(499));
+        assert!(throttle.would_block(0));
+        clock.advance(Duration::from_millis(1));
+        assert!(!throttle.would_block(0));
+        assert!(throttle.would_block(1));
+        clock.advance(Duration::from_secs(1));
+        assert!(!throttle.would_block(1000));
+    }
+}
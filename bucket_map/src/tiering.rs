@@ -0,0 +1,270 @@
+//! Warm/cold tiering of buckets across a fast and a slow set of drives.
+//! Buckets are created on the slow drives, BucketMapConfig::drives. Every pass of
+//! BucketMap::rebalance_tiers moves the buckets that were read the most since the previous pass to
+//! the fast drives, and moves buckets that dropped out of that set back, copying their files while
+//! the bucket is write locked. Grows of a bucket create their files on the bucket's current tier.
+//! Files reused from the scratch pool stay on the drive they were created on.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TieringConfig {
+    /// drives of the fast tier
+    pub fast_drives: Vec<PathBuf>,
+    /// most buckets kept on the fast tier at once
+    pub max_fast_buckets: usize,
+    /// how often BucketMap::spawn_tier_migrator rebalances the tiers
+    pub migrate_interval: Duration,
+}
+
+struct TierState {
+    // read counts of every bucket at the previous pass
+    reads: Vec<u64>,
+    // read counts of every bucket at the pass before, to roll back to when a move fails
+    previous_reads: Vec<u64>,
+    fast: Vec<bool>,
+}
+
+pub(crate) struct Tiering {
+    pub(crate) fast_drives: Arc<Vec<PathBuf>>,
+    max_fast_buckets: usize,
+    pub(crate) migrate_interval: Duration,
+    state: Mutex<TierState>,
+}
+
+impl Tiering {
+    pub(crate) fn new(config: TieringConfig, num_buckets: usize) -> Self {
+        assert!(
+            !config.fast_drives.is_empty(),
+            "The fast tier needs at least one drive"
+        );
+        Self {
+            fast_drives: Arc::new(config.fast_drives),
+            max_fast_buckets: config.max_fast_buckets,
+            migrate_interval: config.migrate_interval,
+            state: Mutex::new(TierState {
+                reads: vec![0; num_buckets],
+                previous_reads: vec![0; num_buckets],
+                fast: vec![false; num_buckets],
+            }),
+        }
+    }
+
+    pub(crate) fn is_fast(&self, ix: usize) -> bool {
+        self.state.lock().unwrap().fast[ix]
+    }
+
+    /// Record that bucket `ix` moved to the fast tier, or back to the slow one
+    pub(crate) fn set_fast(&self, ix: usize, fast: bool) {
+        self.state.lock().unwrap().fast[ix] = fast;
+    }
+
+    /// Record that bucket `ix` failed to make the move it was planned for, so its reads since
+    /// the call to plan before the last one count again and the next plan makes the move again
+    /// if the bucket is still as hot or as cold
+    pub(crate) fn move_failed(&self, ix: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.reads[ix] = state.previous_reads[ix];
+    }
+
+    /// Buckets that have to change tiers given the total read count of every bucket, as
+    /// (bucket, whether it goes to the fast tier). Demotions come first, so the fast tier never
+    /// holds more than max_fast_buckets. Buckets that weren't read since the previous call are
+    /// never promoted.
+    pub(crate) fn plan(&self, reads: &[u64]) -> Vec<(usize, bool)> {
+        let mut state = self.state.lock().unwrap();
+        let mut hot = reads
+            .iter()
+            .zip(state.reads.iter())
+            .map(|(now, before)| now.saturating_sub(*before))
+            .enumerate()
+            .filter(|(_, recent)| *recent > 0)
+            .collect::<Vec<_>>();
+        state.previous_reads = std::mem::replace(&mut state.reads, reads.to_vec());
+        // most read first, ties to the lower bucket
+        hot.sort_by(|(a_ix, a), (b_ix, b)| b.cmp(a).then(a_ix.cmp(b_ix)));
+        let mut want_fast = vec![false; reads.len()];
+        for (ix, _) in hot.into_iter().take(self.max_fast_buckets) {
+            want_fast[ix] = true;
+        }
+        let moves = |to_fast: bool| {
+            state
+                .fast
+                .iter()
+                .zip(want_fast.iter())
+                .enumerate()
+                .filter(move |(_, (fast, want))| **fast != to_fast && **want == to_fast)
+                .map(move |(ix, _)| (ix, to_fast))
+        };
+        moves(false).chain(moves(true)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use solana_sdk::pubkey::Pubkey;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn new_tiering(max_fast_buckets: usize) -> Tiering {
+        Tiering::new(
+            TieringConfig {
+                fast_drives: vec![PathBuf::from("fast")],
+                max_fast_buckets,
+                migrate_interval: Duration::from_secs(1),
+            },
+            4,
+        )
+    }
+
+    #[test]
+    fn test_tiering_plan() {
+        let tiering = new_tiering(2);
+        assert!(tiering.plan(&[0, 0, 0, 0]).is_empty());
+        assert_eq!(tiering.plan(&[5, 0, 7, 1]), vec![(0, true), (2, true)]);
+        tiering.set_fast(0, true);
+        tiering.set_fast(2, true);
+        // counts are since the previous pass
+        assert!(tiering.plan(&[10, 0, 10, 1]).is_empty());
+        assert_eq!(tiering.plan(&[10, 0, 11, 9]), vec![(0, false), (3, true)]);
+        tiering.set_fast(0, false);
+        tiering.set_fast(3, true);
+        // buckets that weren't read since are demoted
+        assert_eq!(tiering.plan(&[10, 0, 11, 9]), vec![(2, false), (3, false)]);
+        assert!(tiering.is_fast(2));
+        assert!(!tiering.is_fast(0));
+        assert!(new_tiering(0).plan(&[1, 2, 3, 4]).is_empty());
+
+        // a failed move is planned again, with the reads it was planned for
+        let tiering = new_tiering(1);
+        assert_eq!(tiering.plan(&[0, 5, 0, 0]), vec![(1, true)]);
+        tiering.move_failed(1);
+        assert_eq!(tiering.plan(&[0, 5, 0, 0]), vec![(1, true)]);
+        tiering.set_fast(1, true);
+        assert!(tiering.plan(&[0, 6, 0, 0]).is_empty());
+    }
+
+    fn num_files(dir: &TempDir) -> usize {
+        fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[test]
+    fn test_rebalance_tiers() {
+        let slow = TempDir::new().unwrap();
+        let fast = TempDir::new().unwrap();
+        let map = BucketMap::<u64>::new(BucketMapConfig {
+            drives: Some(vec![slow.path().to_path_buf()]),
+            tiering: Some(TieringConfig {
+                fast_drives: vec![fast.path().to_path_buf()],
+                max_fast_buckets: 1,
+                migrate_interval: Duration::from_secs(1),
+            }),
+            ..BucketMapConfig::new(4)
+        });
+        let keys = (0..4)
+            .map(|ix| {
+                std::iter::repeat_with(Pubkey::new_unique)
+                    .filter(|key| map.bucket_ix(key) == ix)
+                    .take(10)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        for (i, key) in keys.iter().flatten().enumerate() {
+            map.update(key, |_| Some((vec![i as u64], 1)));
+        }
+        let files = num_files(&slow);
+        assert_eq!(num_files(&fast), 0);
+
+        let read = |ix: usize, times| {
+            for _ in 0..times {
+                keys[ix].iter().for_each(|key| {
+                    map.read_value(key).unwrap();
+                });
+            }
+        };
+        read(2, 3);
+        read(1, 1);
+        assert_eq!(map.rebalance_tiers().unwrap(), 1);
+        assert!(map.bucket_on_fast_tier(2));
+        assert!(!map.bucket_on_fast_tier(1));
+        // an index and a data file
+        assert_eq!(num_files(&fast), 2);
+        assert_eq!(num_files(&slow), files - 2);
+
+        // grows stay on the fast tier
+        let more = std::iter::repeat_with(Pubkey::new_unique)
+            .filter(|key| map.bucket_ix(key) == 2)
+            .take(100)
+            .collect::<Vec<_>>();
+        for key in more.iter() {
+            map.update(key, |_| Some((vec![0, 1], 1)));
+        }
+        assert_eq!(num_files(&slow), files - 2);
+
+        read(1, 2);
+        assert_eq!(map.rebalance_tiers().unwrap(), 2);
+        assert!(map.bucket_on_fast_tier(1));
+        assert!(!map.bucket_on_fast_tier(2));
+        assert_eq!(num_files(&fast), 2);
+        // nothing was read since
+        assert_eq!(map.rebalance_tiers().unwrap(), 1);
+        assert_eq!(num_files(&fast), 0);
+
+        for (i, key) in keys.iter().flatten().enumerate() {
+            assert_eq!(map.read_value(key), Some((vec![i as u64], 1)));
+        }
+        for key in more.iter() {
+            assert_eq!(map.read_value(key), Some((vec![0, 1], 1)));
+        }
+    }
+
+    #[cfg(feature = "fail-points")]
+    #[test]
+    fn test_rebalance_tiers_failed_move() {
+        use crate::fail_points::{FailPointOp, FailPoints};
+
+        let fast = TempDir::new().unwrap();
+        let fail_points = Arc::new(FailPoints::new());
+        let map = BucketMap::<u64>::new(BucketMapConfig {
+            tiering: Some(TieringConfig {
+                fast_drives: vec![fast.path().to_path_buf()],
+                max_fast_buckets: 2,
+                migrate_interval: Duration::from_secs(1),
+            }),
+            fail_points: Some(Arc::clone(&fail_points)),
+            ..BucketMapConfig::new(4)
+        });
+        let keys = (0..4)
+            .map(|ix| {
+                std::iter::repeat_with(Pubkey::new_unique)
+                    .find(|key| map.bucket_ix(key) == ix)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            map.update(key, |_| Some((vec![1], 1)));
+        }
+        map.read_value(&keys[1]);
+        map.read_value(&keys[2]);
+
+        // the move of bucket 1 fails, bucket 2 still moves
+        fail_points.fail_nth(FailPointOp::Mmap, 1, libc::EIO);
+        let err = map.rebalance_tiers().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(!map.bucket_on_fast_tier(1));
+        assert!(map.bucket_on_fast_tier(2));
+
+        // bucket 1 moves on the next pass, without being read again
+        map.read_value(&keys[2]);
+        assert_eq!(map.rebalance_tiers().unwrap(), 1);
+        assert!(map.bucket_on_fast_tier(1));
+        assert!(map.bucket_on_fast_tier(2));
+        for key in keys.iter() {
+            assert_eq!(map.read_value(key), Some((vec![1], 1)));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Opaque ids a caller attaches to writes, such as the slot of the bank that issued them, so a
+//! write can be followed across components. The id of a traced write is sent with its Change,
+//! kept in the stats if the write is the slowest of its kind so far, and returned with its error.
+
+use crate::bucket_map::BucketMapError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u64);
+
+/// The error of a traced write, and the id of the write
+#[derive(Debug)]
+pub struct TracedError {
+    pub trace_id: TraceId,
+    pub error: BucketMapError,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use crate::bucket_stats::OpStats;
+    use crate::change_feed::ChangeKind;
+    use crossbeam_channel::unbounded;
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_traced_writes() {
+        let (sender, receiver) = unbounded();
+        let map = BucketMap::<u64>::new(BucketMapConfig {
+            change_feed: Some(sender),
+            ..BucketMapConfig::new(1)
+        });
+        let key = Pubkey::new_unique();
+        let error = map.try_insert_traced(0, &key, (&[1], 1), TraceId(1));
+        assert!(matches!(
+            error,
+            Err(TracedError {
+                trace_id: TraceId(1),
+                error: BucketMapError::DataNoSpace(_),
+            })
+        ));
+        assert_eq!(map.insert_traced(0, &key, (&[1], 1), TraceId(2)), None);
+        map.update_traced(&key, |_| Some((vec![2], 1)), TraceId(3));
+        map.insert(0, &key, (&[3], 1));
+        map.delete_key_traced(&key, TraceId(4));
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![
+                (key, ChangeKind::Insert, Some(TraceId(2))),
+                (key, ChangeKind::Update, Some(TraceId(3))),
+                (key, ChangeKind::Update, None),
+                (key, ChangeKind::Delete, Some(TraceId(4))),
+            ]
+        );
+        let stats = map.stats_snapshot();
+        assert_eq!(stats.update.max_trace_id, Some(TraceId(3)));
+        assert_eq!(stats.delete.max_trace_id, Some(TraceId(4)));
+    }
+
+    #[test]
+    fn test_op_stats_max_trace_id() {
+        let stats = OpStats::default();
+        stats.update_traced(5, Some(TraceId(1)));
+        stats.update(3);
+        assert_eq!(stats.snapshot().max_trace_id, Some(TraceId(1)));
+        let other = OpStats::default();
+        other.update_traced(7, Some(TraceId(2)));
+        assert_eq!(
+            stats.snapshot().add(&other.snapshot()).max_trace_id,
+            Some(TraceId(2))
+        );
+        stats.update(9);
+        assert_eq!(stats.snapshot().max_trace_id, None);
+        assert_eq!(stats.snapshot().add(&other.snapshot()).max_trace_id, None);
+    }
+}
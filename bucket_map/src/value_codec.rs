@@ -0,0 +1,117 @@
+//! Transformation of values between what callers see and what is stored in data cells.
+//! A codec is applied to every value written to a map's data storages and to every value read
+//! back, so encryption at rest or compression can be layered on without changing the storages.
+//! Index entries, ref counts and keys are stored as they are.
+
+use solana_sdk::pubkey::Pubkey;
+use std::borrow::Cow;
+
+pub trait ValueCodec<T: Clone>: Send + Sync {
+    /// What to store for the value `values` of `key`. May be longer or shorter than `values`.
+    /// The result may only depend on `key` and `values`: stored values are moved between cells
+    /// by grows and gc_data and copied into replicas without being re-encoded.
+    fn encode<'a>(&self, key: &Pubkey, values: &'a [T]) -> Cow<'a, [T]>;
+
+    /// The value of `key` that `stored`, a result of encode, was encoded from
+    fn decode<'a>(&self, key: &Pubkey, stored: &'a [T]) -> Cow<'a, [T]>;
+}
+
+/// Stores values as they are. Reads borrow straight from the mmap.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdentityCodec;
+
+impl<T: Clone> ValueCodec<T> for IdentityCodec {
+    fn encode<'a>(&self, _key: &Pubkey, values: &'a [T]) -> Cow<'a, [T]> {
+        Cow::Borrowed(values)
+    }
+
+    fn decode<'a>(&self, _key: &Pubkey, stored: &'a [T]) -> Cow<'a, [T]> {
+        Cow::Borrowed(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+    use crate::debug_export::ExportFormat;
+    use std::convert::TryInto;
+    use std::ops::RangeInclusive;
+    use std::sync::Arc;
+
+    /// Run length encodes values as (count, value) pairs and mixes the key into what is stored
+    struct RleCodec;
+
+    impl RleCodec {
+        fn mask(key: &Pubkey) -> u64 {
+            u64::from_le_bytes(key.as_ref()[..8].try_into().unwrap())
+        }
+    }
+
+    impl ValueCodec<u64> for RleCodec {
+        fn encode<'a>(&self, key: &Pubkey, values: &'a [u64]) -> Cow<'a, [u64]> {
+            let mut stored: Vec<u64> = vec![];
+            for value in values {
+                match stored.len() {
+                    len if len > 0 && stored[len - 1] == *value ^ Self::mask(key) => {
+                        stored[len - 2] += 1
+                    }
+                    _ => stored.extend_from_slice(&[1, *value ^ Self::mask(key)]),
+                }
+            }
+            Cow::Owned(stored)
+        }
+
+        fn decode<'a>(&self, key: &Pubkey, stored: &'a [u64]) -> Cow<'a, [u64]> {
+            Cow::Owned(
+                stored
+                    .chunks(2)
+                    .flat_map(|run| (0..run[0]).map(move |_| run[1] ^ Self::mask(key)))
+                    .collect(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_value_codec() {
+        let map = BucketMap::new_with_codec(BucketMapConfig::new(1), Arc::new(RleCodec));
+        let key = Pubkey::new_unique();
+        let value = vec![7; 100];
+        map.update(&key, |current| {
+            assert_eq!(current, None);
+            Some((value.clone(), 1))
+        });
+        assert_eq!(map.read_value(&key), Some((value.clone(), 1)));
+        // only the encoded value is stored, which fits in a data cell of 2 elements
+        assert_eq!(
+            map.buckets[0].read().unwrap().as_ref().unwrap().data.len(),
+            2
+        );
+        map.update(&key, |current| {
+            assert_eq!(current, Some((&value[..], 1)));
+            Some((vec![1, 2, 2], 2))
+        });
+        assert_eq!(map.read_value(&key), Some((vec![1, 2, 2], 2)));
+        let items = map.items_in_range(0, &None::<&RangeInclusive<Pubkey>>);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].slot_list, vec![1, 2, 2]);
+
+        // export_debug dumps the encoded value, a run of 1 followed by a run of 2
+        let mut csv = vec![];
+        map.export_debug(&mut csv, ExportFormat::Csv).unwrap();
+        let stored = [1, 1 ^ RleCodec::mask(&key), 2, 2 ^ RleCodec::mask(&key)]
+            .iter()
+            .map(|value| {
+                value
+                    .to_le_bytes()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            })
+            .collect::<String>();
+        assert_eq!(
+            String::from_utf8(csv).unwrap().lines().nth(1).unwrap(),
+            format!("{},2,{}", key, stored)
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Distribution of value lengths and the entries with the longest values, to find the accounts
+//! whose slot lists inflate the index.
+//! Lengths are the number of elements stored, which is the length of the value unless the map
+//! has a ValueCodec or encryption. They are read from the index alone, without paging in values.
+
+use crate::RefCount;
+use solana_sdk::pubkey::Pubkey;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Number of values by length, in power of two classes
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValueLenHistogram {
+    /// `counts[0]` is the number of empty values, `counts[k]` the number of values with a
+    /// length in [2^(k-1), 2^k). Trailing empty classes are left out.
+    pub counts: Vec<u64>,
+    pub max_len: u64,
+    pub total_len: u64,
+}
+
+impl ValueLenHistogram {
+    pub fn add(&mut self, len: u64) {
+        let class = (u64::BITS - len.leading_zeros()) as usize;
+        if self.counts.len() <= class {
+            self.counts.resize(class + 1, 0);
+        }
+        self.counts[class] += 1;
+        self.max_len = self.max_len.max(len);
+        self.total_len += len;
+    }
+
+    /// Number of values counted
+    pub fn entries(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LargeEntry {
+    pub len: u64,
+    pub pubkey: Pubkey,
+    pub ref_count: RefCount,
+}
+
+/// Keeps the `n` longest entries it is given
+pub(crate) struct LargestEntries {
+    n: usize,
+    // shortest on top, so it is the one pushed out
+    heap: BinaryHeap<Reverse<LargeEntry>>,
+}
+
+impl LargestEntries {
+    pub(crate) fn new(n: usize) -> Self {
+        Self {
+            n,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, entry: LargeEntry) {
+        if self.heap.len() < self.n {
+            self.heap.push(Reverse(entry));
+        } else if matches!(self.heap.peek(), Some(Reverse(shortest)) if *shortest < entry) {
+            self.heap.pop();
+            self.heap.push(Reverse(entry));
+        }
+    }
+
+    /// Longest first, equal lengths by descending Pubkey
+    pub(crate) fn into_sorted_vec(self) -> Vec<LargeEntry> {
+        // ascending order of Reverse is descending order of the entries
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(entry)| entry)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_map::{BucketMap, BucketMapConfig};
+
+    #[test]
+    fn test_value_len_histogram() {
+        let mut histogram = ValueLenHistogram::default();
+        for len in [0, 0, 1, 2, 3, 4, 7, 8, 1000].iter() {
+            histogram.add(*len);
+        }
+        assert_eq!(histogram.counts, vec![2, 1, 2, 2, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.max_len, 1000);
+        assert_eq!(histogram.total_len, 1025);
+        assert_eq!(histogram.entries(), 9);
+    }
+
+    #[test]
+    fn test_largest_entries() {
+        let map = BucketMap::<u64>::new(BucketMapConfig::new(4));
+        assert_eq!(map.value_len_histogram(), ValueLenHistogram::default());
+        assert!(map.largest_entries(3).is_empty());
+
+        let keys = (0..100).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            map.update(key, |_| Some((vec![0; i % 10], i as RefCount)));
+        }
+        let histogram = map.value_len_histogram();
+        assert_eq!(histogram.counts, vec![10, 10, 20, 40, 20]);
+        assert_eq!(histogram.max_len, 9);
+        assert_eq!(histogram.total_len, 450);
+
+        let largest = map.largest_entries(15);
+        assert_eq!(largest.len(), 15);
+        assert!(largest[..10].iter().all(|entry| entry.len == 9));
+        assert!(largest[10..].iter().all(|entry| entry.len == 8));
+        for entry in largest.iter() {
+            let i = keys.iter().position(|key| *key == entry.pubkey).unwrap();
+            assert_eq!(entry.ref_count, i as RefCount);
+        }
+        assert!(largest.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(map.largest_entries(1000).len(), 100);
+        assert!(map.largest_entries(0).is_empty());
+    }
+}